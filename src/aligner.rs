@@ -0,0 +1,332 @@
+/*
+Alignment backends
+------------------
+
+The filter aligns every target and query a PAF line is between, against the
+reverse complement of the query for lines on the reverse strand, and feeds the
+runs of matches to the backtrace lambda. Any aligner that can produce those runs can
+drive the filter, the `Aligner` trait is that contract.
+
+ - `Wfa` the native gap-affine wavefront aligner in `wflambda::wfa`
+ - `EditDistance` the native edit distance aligner in `wflambda::wflambda`
+ - `LibWfa` the WFA C library through the libwfa bindings
+
+With --packed the sequences are 2-bit packed. `Wfa` extends over the packed
+words, the others align the unpacked bytes of each pair.
+
+libwfa only returns a CIGAR so the match runs are recovered by walking it.
+*/
+use libwfa::{
+    affine_wavefront::AffineWavefronts, bindings::BUFFER_SIZE_8M, mm_allocator::MMAllocator,
+    penalties::AffinePenalties,
+};
+
+use super::packed::PackedSlice;
+use super::types::{AlignerBackend, CliArgs, Penalties};
+use super::verify;
+use super::wflambda::wfa::types::Alignment;
+use super::wflambda::wfa::utils::run_length_encode;
+use super::wflambda::{wfa, wflambda};
+
+pub type BacktraceLambda<'a> = dyn FnMut((i32, i32), (i32, i32)) + 'a;
+
+pub trait Aligner {
+    fn name(&self) -> &'static str;
+
+    // The penalties the reported score is under
+    fn penalties(&self) -> Penalties;
+
+    /// Globally align `query` against `text` calling `backtrace_lambda` once
+    /// for every run of matches with the (query, target) intervals it spans.
+    fn align(&self, text: &[u8], query: &[u8], backtrace_lambda: &mut BacktraceLambda)
+        -> Alignment;
+
+    /// As align on 2-bit packed sequences. Aligners that cannot compare
+    /// packed words align the unpacked bytes.
+    fn align_packed(
+        &self,
+        text: &PackedSlice,
+        query: &PackedSlice,
+        backtrace_lambda: &mut BacktraceLambda,
+    ) -> Alignment {
+        self.align(&text.to_vec(), &query.to_vec(), backtrace_lambda)
+    }
+}
+
+pub struct Wfa<'a> {
+    cli_args: &'a CliArgs,
+}
+
+impl<'a> Aligner for Wfa<'a> {
+    fn name(&self) -> &'static str {
+        "wfa"
+    }
+
+    fn penalties(&self) -> Penalties {
+        self.cli_args.penalties
+    }
+
+    fn align(
+        &self,
+        text: &[u8],
+        query: &[u8],
+        backtrace_lambda: &mut BacktraceLambda,
+    ) -> Alignment {
+        wfa::wf_align(text, query, self.cli_args, &mut |q, t| {
+            backtrace_lambda(q, t)
+        })
+    }
+
+    fn align_packed(
+        &self,
+        text: &PackedSlice,
+        query: &PackedSlice,
+        backtrace_lambda: &mut BacktraceLambda,
+    ) -> Alignment {
+        wfa::wf_align_sequences(text, query, self.cli_args, &mut |q, t| {
+            backtrace_lambda(q, t)
+        })
+    }
+}
+
+pub struct EditDistance<'a> {
+    cli_args: &'a CliArgs,
+}
+
+impl<'a> Aligner for EditDistance<'a> {
+    fn name(&self) -> &'static str {
+        "wflambda"
+    }
+
+    fn penalties(&self) -> Penalties {
+        verify::edit_distance_penalties()
+    }
+
+    fn align(
+        &self,
+        text: &[u8],
+        query: &[u8],
+        backtrace_lambda: &mut BacktraceLambda,
+    ) -> Alignment {
+        let aln = wflambda::wf_align(text, query, self.cli_args, &mut |q, t| {
+            backtrace_lambda(q, t)
+        });
+
+        Alignment {
+            score: aln.distance,
+            cigar: aln.cigar,
+        }
+    }
+}
+
+pub struct LibWfa {
+    penalties: Penalties,
+    adapt: bool,
+}
+
+impl LibWfa {
+    pub fn new(penalties: Penalties, adapt: bool) -> Self {
+        LibWfa { penalties, adapt }
+    }
+
+    // libwfa asserts that both sequences are non-empty
+    fn align_empty(&self, text: &[u8], query: &[u8]) -> Alignment {
+        let (len, op) = if query.is_empty() {
+            (text.len(), 'D')
+        } else {
+            (query.len(), 'I')
+        };
+
+        if len == 0 {
+            return Alignment {
+                score: 0,
+                cigar: String::new(),
+            };
+        }
+
+        Alignment {
+            score: (self.penalties.gap_open + self.penalties.gap_extend * len as u64) as usize,
+            cigar: format!("{}{}", len, op),
+        }
+    }
+}
+
+impl Aligner for LibWfa {
+    fn name(&self) -> &'static str {
+        "libwfa"
+    }
+
+    fn penalties(&self) -> Penalties {
+        self.penalties
+    }
+
+    fn align(
+        &self,
+        text: &[u8],
+        query: &[u8],
+        backtrace_lambda: &mut BacktraceLambda,
+    ) -> Alignment {
+        if text.is_empty() || query.is_empty() {
+            return self.align_empty(text, query);
+        }
+
+        let alloc = MMAllocator::new(BUFFER_SIZE_8M as u64);
+        let mut penalties = AffinePenalties {
+            match_: -(self.penalties.matches as i32),
+            mismatch: self.penalties.mismatch as i32,
+            gap_opening: self.penalties.gap_open as i32,
+            gap_extension: self.penalties.gap_extend as i32,
+        };
+
+        // In libwfa I consumes the text and D the pattern. Passing the target
+        // as the pattern makes its ops mean the same as ours, I consumes the
+        // query and D the target.
+        let mut wavefronts = if self.adapt {
+            AffineWavefronts::new_reduced(
+                text.len(),
+                query.len(),
+                &mut penalties,
+                wfa::MIN_WAVEFRONT_LENGTH as i32,
+                wfa::MAX_DISTANCE_THRESHOLD as i32,
+                &alloc,
+            )
+        } else {
+            AffineWavefronts::new_complete(text.len(), query.len(), &mut penalties, &alloc)
+        };
+
+        wavefronts
+            .align(text, query)
+            .expect("[wfilter::aligner::libwfa] wavefronts allocated too small");
+
+        let score = wavefronts.edit_cigar_score(&mut penalties).unsigned_abs();
+        let ops = wavefronts.cigar_bytes_raw();
+
+        report_match_runs(&ops, backtrace_lambda);
+
+        let cigar = if ops.is_empty() {
+            String::new()
+        } else {
+            run_length_encode(std::str::from_utf8(&ops).unwrap(), false)
+        };
+
+        Alignment { score, cigar }
+    }
+}
+
+// Walk an expanded CIGAR e.g. MMMXMMI and pass each run of M to the lambda
+fn report_match_runs(ops: &[u8], backtrace_lambda: &mut BacktraceLambda) {
+    let mut v: i32 = 0; // query
+    let mut h: i32 = 0; // text
+    let mut run_start: Option<(i32, i32)> = None;
+
+    for op in ops.iter() {
+        if *op == b'M' {
+            if run_start.is_none() {
+                run_start = Some((v, h));
+            }
+        } else if let Some((qs, ts)) = run_start.take() {
+            backtrace_lambda((qs, v), (ts, h));
+        }
+
+        match op {
+            b'M' | b'X' => {
+                v += 1;
+                h += 1;
+            }
+            b'I' => v += 1,
+            b'D' => h += 1,
+            _ => panic!(
+                "[wfilter::aligner::libwfa] unexpected CIGAR op {}",
+                *op as char
+            ),
+        }
+    }
+
+    if let Some((qs, ts)) = run_start {
+        backtrace_lambda((qs, v), (ts, h));
+    }
+}
+
+/// The aligner selected on the command line.
+pub fn from_cli_args(cli_args: &CliArgs) -> Box<dyn Aligner + '_> {
+    match (cli_args.aligner, cli_args.edit_distance) {
+        (AlignerBackend::Native, false) => Box::new(Wfa { cli_args }),
+        (AlignerBackend::Native, true) => Box::new(EditDistance { cli_args }),
+        (AlignerBackend::Libwfa, false) => {
+            Box::new(LibWfa::new(cli_args.penalties, cli_args.adapt))
+        }
+        (AlignerBackend::Libwfa, true) => Box::new(LibWfa::new(
+            verify::edit_distance_penalties(),
+            cli_args.adapt,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::{verify_alignment, MatchInterval};
+
+    static TEXT: &str = "TCTATACTGCGCGTTTATCTAGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT";
+    static QUERY: &str = "TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT";
+
+    fn check(aligner: &dyn Aligner, text: &[u8], query: &[u8]) -> Alignment {
+        let mut intervals: Vec<MatchInterval> = Vec::new();
+        let aln = aligner.align(text, query, &mut |q, t| intervals.push((q, t)));
+        let result = verify_alignment(
+            text,
+            query,
+            aln.score,
+            &aln.cigar,
+            &aligner.penalties(),
+            &intervals,
+        );
+        assert_eq!(result, Ok(()), "{} {}", aligner.name(), aln.cigar);
+        aln
+    }
+
+    #[test]
+    fn test_report_match_runs() {
+        let mut intervals: Vec<MatchInterval> = Vec::new();
+        report_match_runs(b"MMMXMMIMMDDM", &mut |q, t| intervals.push((q, t)));
+        assert_eq!(
+            intervals,
+            vec![
+                ((0, 3), (0, 3)),
+                ((4, 6), (4, 6)),
+                ((7, 9), (6, 8)),
+                ((9, 10), (10, 11))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        for edit_distance in [false, true] {
+            let mut scores: Vec<usize> = Vec::new();
+
+            for backend in [AlignerBackend::Native, AlignerBackend::Libwfa] {
+                let cli_args = CliArgs {
+                    aligner: backend,
+                    edit_distance,
+                    ..CliArgs::for_tests()
+                };
+                let aligner = from_cli_args(&cli_args);
+
+                scores.push(check(aligner.as_ref(), TEXT.as_bytes(), QUERY.as_bytes()).score);
+                check(aligner.as_ref(), b"GAGATA", b"GAGATA");
+                check(aligner.as_ref(), b"GATACA", b"GAGATA");
+            }
+
+            assert_eq!(scores[0], scores[1]);
+        }
+    }
+
+    #[test]
+    fn test_libwfa_empty_sequences() {
+        let aligner = LibWfa::new(CliArgs::for_tests().penalties, false);
+        assert_eq!(check(&aligner, b"", b"GATACA").cigar, "6I");
+        assert_eq!(check(&aligner, b"GATACA", b"").cigar, "6D");
+        assert_eq!(check(&aligner, b"", b"").cigar, "");
+    }
+}
@@ -0,0 +1,181 @@
+/*
+Best hits
+---------
+
+Drop redundant alignments keeping, for every query region, only the best
+scoring line. In one-to-one mode a line must also be the best on its target
+region. Two lines compete when they are on the same sequence and their
+intervals overlap by more than `--best-overlap` of the shorter one. Lines are
+taken greedily from the best score down, ties going to the earlier line, and a
+line is kept unless it competes with a line already kept. The kept lines never
+overlap each other, and a dropped line never knocks out another.
+
+Lines are scored either by block length × identity of their CIGAR, which
+needs no alignment, or by the bases the WFA path supports.
+*/
+use std::collections::HashMap;
+
+use super::paf;
+use super::rescore;
+use super::types;
+
+// An interval tree of the lines on each sequence
+type Trees<'a> = HashMap<&'a str, types::Index>;
+
+fn trees<'a, F>(alignments: &'a [paf::PafAlignment], candidates: &[bool], interval: F) -> Trees<'a>
+where
+    F: Fn(&'a paf::PafAlignment) -> (&'a str, u32, u32),
+{
+    let mut nodes: HashMap<&str, Vec<coitrees::IntervalNode<types::AlignmentMetadata, u32>>> =
+        HashMap::new();
+
+    for (line_num, a) in alignments.iter().enumerate() {
+        let (name, start, end) = interval(a);
+        if candidates[line_num] && end > start {
+            // coitrees intervals are end-inclusive
+            nodes
+                .entry(name)
+                .or_default()
+                .push(coitrees::IntervalNode::new(
+                    start as i32,
+                    end as i32 - 1,
+                    line_num,
+                ));
+        }
+    }
+
+    nodes
+        .into_iter()
+        .map(|(name, n)| (name, coitrees::COITree::new(n)))
+        .collect()
+}
+
+fn query(a: &paf::PafAlignment) -> (&str, u32, u32) {
+    (a.query.as_str(), a.query_start, a.query_end)
+}
+
+fn target(a: &paf::PafAlignment) -> (&str, u32, u32) {
+    (a.target.as_str(), a.target_start, a.target_end)
+}
+
+// Whether a kept line overlaps the interval by more than max_overlap
+fn overlaps_kept(
+    trees: &Trees,
+    (name, start, end): (&str, u32, u32),
+    kept: &[bool],
+    max_overlap: f64,
+) -> bool {
+    let tree = match trees.get(name) {
+        Some(t) if end > start => t,
+        _ => return false,
+    };
+
+    let mut overlaps = false;
+    tree.query(start as i32, end as i32 - 1, |node| {
+        if !kept[node.metadata] || overlaps {
+            return;
+        }
+
+        let overlap = (end as i32).min(node.last + 1) - (start as i32).max(node.first);
+        let shorter = (end - start).min((node.last + 1 - node.first) as u32);
+        overlaps = overlap as f64 > max_overlap * shorter as f64;
+    });
+
+    overlaps
+}
+
+/// The candidates taken best first, each kept unless it overlaps a kept line
+pub fn best_hits(
+    alignments: &[paf::PafAlignment],
+    scores: &[f64],
+    candidates: &[bool],
+    cli_args: &types::CliArgs,
+) -> Vec<bool> {
+    let query_trees = trees(alignments, candidates, query);
+    let target_trees = match cli_args.best_hits {
+        Some(types::BestHits::OneToOne) => trees(alignments, candidates, target),
+        _ => HashMap::new(),
+    };
+    let max_overlap = cli_args.best_overlap;
+
+    // best first, the sort is stable so ties stay in line order
+    let mut order: Vec<usize> = (0..alignments.len())
+        .filter(|line_num| candidates[*line_num])
+        .collect();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+
+    let mut kept = vec![false; alignments.len()];
+    for line_num in order {
+        let a = &alignments[line_num];
+        kept[line_num] = !overlaps_kept(&query_trees, query(a), &kept, max_overlap)
+            && !overlaps_kept(&target_trees, target(a), &kept, max_overlap);
+    }
+
+    kept
+}
+
+// Block length × identity of the CIGAR, from the PAF columns if there is none
+pub fn identity_score(a: &paf::PafAlignment, penalties: &types::Penalties) -> f64 {
+    match rescore::rescore(a, penalties) {
+        Ok(score) if !a.cigar.is_empty() => a.block_len as f64 * score.identity,
+        _ => a.residue_matches as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the first two overlap on the query, the last two on the target
+    static PAF_STRING: &str = "\
+    q1\t1000\t0\t500\t+\tt\t5000\t0\t500\t490\t500\t60\tcg:Z:500M\n\
+    q1\t1000\t100\t600\t+\tt\t5000\t1000\t1500\t500\t500\t60\tcg:Z:500M\n\
+    q2\t1000\t0\t500\t+\tt\t5000\t1200\t1700\t400\t500\t60\tcg:Z:500M\n\
+    q3\t1000\t0\t400\t+\tt\t5000\t480\t880\t400\t400\t60\tcg:Z:400M";
+
+    fn run(mode: types::BestHits, best_overlap: f64) -> Vec<bool> {
+        let paf = paf::PAF::from_str(PAF_STRING);
+        let alignments = paf.get_alignments();
+        let scores: Vec<f64> = alignments
+            .iter()
+            .map(|a| a.residue_matches as f64)
+            .collect();
+
+        let cli_args = types::CliArgs {
+            best_hits: Some(mode),
+            best_overlap,
+            ..types::CliArgs::for_tests()
+        };
+        best_hits(alignments, &scores, &[true; 4], &cli_args)
+    }
+
+    #[test]
+    fn test_best_hits() {
+        assert_eq!(
+            run(types::BestHits::Query, 0.5),
+            vec![false, true, true, true]
+        );
+        assert_eq!(
+            run(types::BestHits::OneToOne, 0.5),
+            vec![false, true, false, true]
+        );
+        // q3 overlaps the first line by 20 bases on the target but the first
+        // line is dropped for the second, so it does not knock q3 out
+        assert_eq!(
+            run(types::BestHits::OneToOne, 0.0),
+            vec![false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn test_identity_score() {
+        let penalties = types::CliArgs::for_tests().penalties;
+        let a = paf::PafAlignment::from_str(
+            "q\t100\t0\t100\t+\tt\t100\t0\t100\t90\t100\t60\tNM:i:10\tcg:Z:100M",
+        );
+        assert_eq!(identity_score(&a, &penalties), 90.0);
+
+        let a = paf::PafAlignment::from_str("q\t100\t0\t100\t+\tt\t100\t0\t100\t90\t100\t60");
+        assert_eq!(identity_score(&a, &penalties), 90.0);
+    }
+}
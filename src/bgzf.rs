@@ -0,0 +1,290 @@
+/*
+Compressed input
+----------------
+
+PAF and FASTA inputs may be plain, gzip or bgzip compressed, told apart by
+their first bytes. bgzip output is gzip made of independent blocks of up to
+64 KiB, each with its compressed size in a BC extra field, so it also reads as
+ordinary multi-member gzip.
+
+A `.gzi` index maps the offset of every block in the compressed file to the
+offset of its first byte in the uncompressed data. Reading from an offset
+starts at the block it falls in. The index is read from `<file>.gzi` or built
+by walking the block headers, which needs no decompression.
+
+|Bytes  |Description                                                 |
+|:------|:-----------------------------------------------------------|
+|8      |Number of blocks after the first, little endian              |
+|8 + 8  |Compressed then uncompressed offset of each of those blocks |
+*/
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+use flate2::read::MultiGzDecoder;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Bgzf,
+}
+
+// From the first 16 bytes of a file
+pub fn detect(header: &[u8]) -> Compression {
+    if header.len() < 4 || header[0] != 0x1f || header[1] != 0x8b {
+        Compression::Plain
+    } else if header.len() >= 14 && header[3] & 4 != 0 && &header[12..14] == b"BC" {
+        Compression::Bgzf
+    } else {
+        Compression::Gzip
+    }
+}
+
+// Decompress the reader if it is gzip or bgzip
+pub fn reader<'a, R: BufRead + 'a>(mut r: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    let compression = detect(r.fill_buf()?);
+    Ok(match compression {
+        Compression::Plain => Box::new(r),
+        _ => Box::new(BufReader::new(MultiGzDecoder::new(r))),
+    })
+}
+
+// Compressed and uncompressed offsets of the start of every block
+pub type Gzi = Vec<(u64, u64)>;
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// The first block, at 0 in both, is implied
+pub fn parse_gzi<R: Read>(mut r: R) -> io::Result<Gzi> {
+    let n = read_u64(&mut r)?;
+    let mut gzi: Gzi = vec![(0, 0)];
+    for _ in 0..n {
+        gzi.push((read_u64(&mut r)?, read_u64(&mut r)?));
+    }
+    Ok(gzi)
+}
+
+pub fn write_gzi<W: Write>(gzi: &[(u64, u64)], out: &mut W) -> io::Result<()> {
+    let blocks = &gzi[1.min(gzi.len())..];
+    out.write_all(&(blocks.len() as u64).to_le_bytes())?;
+    for (compressed, uncompressed) in blocks {
+        out.write_all(&compressed.to_le_bytes())?;
+        out.write_all(&uncompressed.to_le_bytes())?;
+    }
+    out.flush()
+}
+
+// Walk the block headers reading each block size and, from its last 4 bytes,
+// the size of its uncompressed data
+pub fn build_gzi<R: Read>(mut r: R) -> Result<Gzi, String> {
+    let mut gzi: Gzi = Vec::new();
+    let (mut compressed, mut uncompressed) = (0u64, 0u64);
+    let mut header = [0u8; 12];
+
+    loop {
+        match r.read(&mut header[..1]) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+        r.read_exact(&mut header[1..])
+            .map_err(|e| format!("truncated block at {}: {}", compressed, e))?;
+        if detect(&header) == Compression::Plain || header[3] & 4 == 0 {
+            return Err(format!("no bgzip block at {}", compressed));
+        }
+
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let mut extra = vec![0u8; xlen];
+        r.read_exact(&mut extra).map_err(|e| e.to_string())?;
+        let bsize = (0..xlen.saturating_sub(5))
+            .find(|i| &extra[*i..i + 2] == b"BC")
+            .map(|i| u16::from_le_bytes([extra[i + 4], extra[i + 5]]) as usize)
+            .ok_or_else(|| format!("no BC field in the block at {}", compressed))?;
+
+        // the rest of the block, the data then CRC32 and ISIZE
+        let mut rest = vec![0u8; (bsize + 1).saturating_sub(12 + xlen)];
+        r.read_exact(&mut rest)
+            .map_err(|e| format!("truncated block at {}: {}", compressed, e))?;
+        let isize = rest
+            .get(rest.len().saturating_sub(4)..)
+            .filter(|b| b.len() == 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| format!("truncated block at {}", compressed))?;
+
+        gzi.push((compressed, uncompressed));
+        compressed += (bsize + 1) as u64;
+        uncompressed += isize as u64;
+    }
+
+    if gzi.is_empty() {
+        gzi.push((0, 0));
+    }
+    Ok(gzi)
+}
+
+// Read <fp>.gzi or build it and try to write it next to the file
+pub fn open_gzi(fp: &str, verbosity: u8) -> Result<Gzi, String> {
+    let path = format!("{}.gzi", fp);
+    if let Ok(file) = File::open(&path) {
+        return parse_gzi(BufReader::new(file)).map_err(|e| format!("bad {}: {}", path, e));
+    }
+
+    let file = File::open(fp).map_err(|e| format!("could not open {}: {}", fp, e))?;
+    let gzi = build_gzi(BufReader::new(file))?;
+
+    match File::create(&path).and_then(|f| write_gzi(&gzi, &mut io::BufWriter::new(f))) {
+        Ok(_) if verbosity > 0 => eprintln!("[wfilter::bgzf] wrote {}", path),
+        Ok(_) => {}
+        Err(e) => eprintln!("[wfilter::bgzf] could not write {}: {}", path, e),
+    }
+
+    Ok(gzi)
+}
+
+// Reads bgzip data seeking by offsets in the uncompressed data
+pub struct BgzfReader<R: Read + Seek> {
+    decoder: Option<MultiGzDecoder<BufReader<R>>>,
+    gzi: Gzi,
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    pub fn new(inner: R, gzi: Gzi) -> Self {
+        BgzfReader {
+            decoder: Some(MultiGzDecoder::new(BufReader::new(inner))),
+            gzi,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.as_mut().unwrap().read(buf)
+    }
+}
+
+impl<R: Read + Seek> Seek for BgzfReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => offset,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "bgzip data can only be read from an offset from the start",
+                ))
+            }
+        };
+
+        let block = self.gzi.partition_point(|(_, u)| *u <= offset).max(1) - 1;
+        let (compressed, uncompressed) = self.gzi[block];
+
+        let mut inner = self.decoder.take().unwrap().into_inner().into_inner();
+        inner.seek(SeekFrom::Start(compressed))?;
+        let mut decoder = MultiGzDecoder::new(BufReader::new(inner));
+
+        let skip = offset - uncompressed;
+        let skipped = io::copy(&mut (&mut decoder).take(skip), &mut io::sink());
+        self.decoder = Some(decoder);
+        if skipped? < skip {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "offset past the end of the bgzip data",
+            ));
+        }
+
+        Ok(offset)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use std::io::Cursor;
+
+    // bgzip data with blocks of block_len bytes and the empty end of file block
+    pub fn bgzip(data: &[u8], block_len: usize) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        let chunks: Vec<&[u8]> = data.chunks(block_len).chain([&b""[..]]).collect();
+
+        for chunk in chunks {
+            let mut deflate = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            deflate.write_all(chunk).unwrap();
+            let cdata = deflate.finish().unwrap();
+            let mut crc = flate2::Crc::new();
+            crc.update(chunk);
+
+            let bsize = (18 + cdata.len() + 8 - 1) as u16;
+            out.extend_from_slice(&[
+                0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+            ]);
+            out.extend_from_slice(&bsize.to_le_bytes());
+            out.extend_from_slice(&cdata);
+            out.extend_from_slice(&crc.sum().to_le_bytes());
+            out.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        }
+
+        out
+    }
+
+    pub fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(data).unwrap();
+        gz.finish().unwrap()
+    }
+
+    static DATA: &[u8] = b"the quick brown fox jumps over the lazy dog\n";
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(DATA), Compression::Plain);
+        assert_eq!(detect(&gzip(DATA)), Compression::Gzip);
+        assert_eq!(detect(&bgzip(DATA, 10)), Compression::Bgzf);
+        assert_eq!(detect(b""), Compression::Plain);
+    }
+
+    #[test]
+    fn test_reader() {
+        for data in [DATA.to_vec(), gzip(DATA), bgzip(DATA, 10)] {
+            let mut out = String::new();
+            reader(&data[..]).unwrap().read_to_string(&mut out).unwrap();
+            assert_eq!(out.as_bytes(), DATA);
+        }
+    }
+
+    #[test]
+    fn test_gzi() {
+        let data = bgzip(DATA, 10);
+        let gzi = build_gzi(&data[..]).unwrap();
+        // five blocks of data and the end of file block
+        assert_eq!(gzi.len(), 6);
+        assert_eq!(gzi[0], (0, 0));
+        assert_eq!(gzi[5].1, DATA.len() as u64);
+
+        let mut out: Vec<u8> = Vec::new();
+        write_gzi(&gzi, &mut out).unwrap();
+        assert_eq!(out.len(), 8 + 5 * 16);
+        assert_eq!(parse_gzi(&out[..]).unwrap(), gzi);
+
+        assert!(build_gzi(&gzip(DATA)[..]).is_err());
+    }
+
+    #[test]
+    fn test_seek() {
+        let data = bgzip(DATA, 10);
+        let gzi = build_gzi(&data[..]).unwrap();
+        let mut reader = BgzfReader::new(Cursor::new(data), gzi);
+
+        for (offset, len) in [(0, 5), (4, 9), (20, 15), (40, 4), (9, 1)] {
+            let mut buf = vec![0u8; len];
+            reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, DATA[offset..offset + len].to_vec());
+        }
+        assert!(reader.seek(SeekFrom::Start(100)).is_err());
+        assert!(reader.seek(SeekFrom::Current(1)).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+// A single run of a CIGAR operation e.g. 10M
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct CigarOp {
+    pub len: u32,
+    pub op: char,
+}
+
+impl CigarOp {
+    pub fn new(len: u32, op: char) -> Self {
+        CigarOp { len, op }
+    }
+
+    // M, = and X consume both sequences, I the query and D the target
+    pub fn consumes_query(&self) -> bool {
+        matches!(self.op, 'M' | '=' | 'X' | 'I')
+    }
+
+    pub fn consumes_target(&self) -> bool {
+        matches!(self.op, 'M' | '=' | 'X' | 'D')
+    }
+}
+
+pub type Cigar = Vec<CigarOp>;
+
+// Parse a run length encoded CIGAR such as 3M1X4M1I7M
+pub fn parse(cigar: &str) -> Result<Cigar, String> {
+    let mut ops: Cigar = Vec::new();
+    let mut buffer = String::new();
+
+    for c in cigar.chars() {
+        match c {
+            'M' | '=' | 'X' | 'I' | 'D' => {
+                let len: u32 = buffer
+                    .parse()
+                    .map_err(|_| format!("missing length before {} in CIGAR {}", c, cigar))?;
+                ops.push(CigarOp::new(len, c));
+                buffer.clear();
+            }
+            b if b.is_ascii_digit() => buffer.push(b),
+            _ => return Err(format!("unexpected char {} in CIGAR {}", c, cigar)),
+        }
+    }
+
+    if !buffer.is_empty() {
+        return Err(format!(
+            "CIGAR {} ends with a length and no operation",
+            cigar
+        ));
+    }
+
+    Ok(ops)
+}
+
+// Bases in M and = ops, the residue matches of a PAF line
+pub fn residue_matches(ops: &[CigarOp]) -> u32 {
+    ops.iter()
+        .filter(|o| matches!(o.op, 'M' | '='))
+        .map(|o| o.len)
+        .sum()
+}
+
+// Bases in all ops, the alignment block length of a PAF line
+pub fn block_len(ops: &[CigarOp]) -> u32 {
+    ops.iter().map(|o| o.len).sum()
+}
+
+// Mismatches and gap bases, the NM of a CIGAR where M only holds matches
+pub fn edit_distance(ops: &[CigarOp]) -> u32 {
+    ops.iter()
+        .filter(|o| matches!(o.op, 'X' | 'I' | 'D'))
+        .map(|o| o.len)
+        .sum()
+}
+
+pub fn to_string(ops: &[CigarOp]) -> String {
+    ops.iter().map(|o| format!("{}{}", o.len, o.op)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let ops = parse("3M1X4M1I7M2D").unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                CigarOp::new(3, 'M'),
+                CigarOp::new(1, 'X'),
+                CigarOp::new(4, 'M'),
+                CigarOp::new(1, 'I'),
+                CigarOp::new(7, 'M'),
+                CigarOp::new(2, 'D'),
+            ]
+        );
+
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("M").is_err());
+        assert!(parse("3M2").is_err());
+        assert!(parse("3S").is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let ops = parse("3M1X4M1I7M2D").unwrap();
+        assert_eq!(residue_matches(&ops), 14);
+        assert_eq!(block_len(&ops), 18);
+        assert_eq!(edit_distance(&ops), 4);
+        assert_eq!(to_string(&ops), "3M1X4M1I7M2D");
+    }
+}
@@ -1,6 +1,9 @@
-use clap::{App, Arg};
+use clap::{value_t, App, Arg, ErrorKind, SubCommand};
 use std::env;
 
+use crate::expr;
+use crate::fasta;
+use crate::io;
 use crate::types;
 
 // Env vars
@@ -10,41 +13,103 @@ const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 
 pub fn start() -> types::CliArgs {
+    let expr_arg = Arg::with_name("expr")
+        .short("x")
+        .long("expr")
+        .value_name("EXPR")
+        .help("Only consider PAF lines the expression selects, e.g. 'mapq >= 20 && tp == \"P\" && de < 0.02'. See expr.rs for the fields");
+
+    let sample_pairs_arg = Arg::with_name("sample_pairs")
+        .long("sample-pairs")
+        .value_name("PAIRS")
+        .possible_values(&["same", "different"])
+        .help("Only consider PAF lines between sequences of the same PanSN sample, or of different ones. Names without a sample# prefix are of no sample");
+
+    let best_hits_arg = Arg::with_name("best_hits")
+        .long("best-hits")
+        .value_name("MODE")
+        .possible_values(&["query", "one-to-one"])
+        .help("Drop lines that overlap a better line on the query, or on the query or the target");
+    let best_overlap_arg = Arg::with_name("best_overlap")
+        .long("best-overlap")
+        .value_name("FLOAT")
+        .default_value("0.5")
+        .help("Lines compete for --best-hits when they overlap by more than this fraction of the shorter one");
+
     let matches = App::new(NAME)
         .version(VERSION)
         .author(AUTHORS)
         .about(DESCRIPTION)
         .arg(
             Arg::with_name("input_paf")
+                .global(true)
                 .short("p")
                 .long("paf")
                 .value_name("FILE")
-                .help("Path to input PAF file")
+                .help("Path to input PAF file. - reads stdin with rescore and select")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("target_fasta")
+                .global(true)
                 .short("t")
                 .long("target")
                 .value_name("FILE")
-                .help("Path to input target fasta file")
-                .takes_value(true),
+                .help("Target FASTA or FASTQ file, repeat it to read several as one. - reads stdin")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
         )
         .arg(
             Arg::with_name("query_fasta")
+                .global(true)
                 .short("q")
                 .long("query")
                 .value_name("FILE")
-                .help("Path to input target fasta file")
-                .takes_value(true),
+                .help("Query FASTA or FASTQ file, repeat it to read several as one. - reads stdin")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
         )
         .arg(
             Arg::with_name("adapt")
+                .global(true)
                 .short("a")
                 .long("adapt")
                 .multiple(false)
                 .help("To apply adaptive wavefront alignment [Default: false]"),
         )
+        .arg(
+            Arg::with_name("faidx")
+                .global(true)
+                .long("faidx")
+                .multiple(false)
+                .help("Read only the sequences and regions the PAF needs through a .fai index, built next to the FASTA if missing [Default: false]"),
+        )
+        .arg(
+            Arg::with_name("packed")
+                .global(true)
+                .long("packed")
+                .multiple(false)
+                .help("Hold the FASTA sequences 2-bit packed, about 4x less memory [Default: false]"),
+        )
+        .arg(
+            Arg::with_name("aliases")
+                .global(true)
+                .long("aliases")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Rename PAF sequences to FASTA record IDs, one 'paf_name record_id' pair per line"),
+        )
+        .arg(
+            Arg::with_name("validate")
+                .global(true)
+                .long("validate")
+                .value_name("MODE")
+                .possible_values(&["warn", "skip", "abort"])
+                .default_value("warn")
+                .help("What to do with PAF lines whose lengths, coordinates or CIGAR disagree with the FASTA or each other, report them, report them and leave them unaligned, or stop"),
+        )
         .arg(
             Arg::with_name("alignment_matrix")
                 .short("m")
@@ -52,29 +117,363 @@ pub fn start() -> types::CliArgs {
                 .multiple(false)
                 .help("Generate alignment matrix TSV file `wfilter-<no_adapt/adapt>-<now>.tsv` [Default: false]"),
         )
+        .arg(
+            Arg::with_name("edit_distance")
+                .global(true)
+                .short("e")
+                .long("edit-distance")
+                .multiple(false)
+                .help("Align with edit distance (wflambda) instead of gap-affine penalties. Fast on low divergence sequences [Default: false]"),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .global(true)
+                .long("verify")
+                .multiple(false)
+                .help("Replay every alignment's CIGAR against the sequences and check its score and the reported match intervals [Default: false]"),
+        )
+        .arg(
+            Arg::with_name("aligner")
+                .global(true)
+                .long("aligner")
+                .value_name("ALIGNER")
+                .possible_values(&["native", "libwfa"])
+                .default_value("native")
+                .help("Alignment backend, the native Rust wavefront aligner or the WFA C library"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("T")
+                .long("threads")
+                .value_name("INT")
+                .default_value("1")
+                .help(
+                    "Number of threads to align with, split between the sequence pairs \
+                     and the diagonals of wide wavefronts",
+                ),
+        )
+        .arg(
+            Arg::with_name("per_alignment")
+                .short("b")
+                .long("per-alignment")
+                .multiple(false)
+                .help("Align only the region of each PAF line instead of whole sequences. Work is proportional to the alignments rather than the genomes [Default: false]"),
+        )
+        .arg(
+            Arg::with_name("min_support_fraction")
+                .long("min-support-fraction")
+                .value_name("FLOAT")
+                .default_value("0")
+                .help("Keep a PAF line only if WFA supports at least this fraction of its matched bases on both query and target"),
+        )
+        .arg(
+            Arg::with_name("min_supported_bases")
+                .long("min-supported-bases")
+                .value_name("INT")
+                .default_value("1")
+                .help("Keep a PAF line only if WFA supports at least this many of its matched bases on both query and target"),
+        )
+        .arg(
+            Arg::with_name("report")
+                .short("r")
+                .long("report")
+                .value_name("FILE")
+                .help("Write a report with the support of every PAF line and whether it was kept")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("report_format")
+                .long("report-format")
+                .value_name("FORMAT")
+                .possible_values(&["tsv", "json"])
+                .default_value("tsv")
+                .help("Format of the report"),
+        )
+        .arg(
+            Arg::with_name("genome_report")
+                .long("genome-report")
+                .value_name("FILE")
+                .help("Write the lines kept and the support for every pair of PanSN genomes, in the --report-format")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rejected")
+                .long("rejected")
+                .value_name("FILE")
+                .help("Write the PAF lines that are dropped to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("invert")
+                .long("invert")
+                .multiple(false)
+                .help("Print the dropped lines instead of the kept ones, --rejected then gets the kept lines [Default: false]"),
+        )
+        .arg(
+            Arg::with_name("annotate")
+                .long("annotate")
+                .multiple(false)
+                .conflicts_with_all(&["rejected", "invert"])
+                .help("Print every line with a wf:Z:kept or wf:Z:dropped tag instead of removing lines [Default: false]"),
+        )
+        .arg(
+            Arg::with_name("tags")
+                .long("tags")
+                .multiple(false)
+                .help("Append the WFA score (ws:i), supported bases (sb:i), support fraction (sf:f) and PAF/WFA concordance (wc:f) to every output line [Default: false]"),
+        )
+        .arg(
+            Arg::with_name("trim")
+                .long("trim")
+                .multiple(false)
+                .help("Clip kept lines to the span the WFA path supports, rewriting their coordinates, residue matches, block length and cg:Z CIGAR [Default: false]"),
+        )
+        .arg(expr_arg.clone())
+        .arg(sample_pairs_arg.clone())
+        .arg(best_hits_arg.clone())
+        .arg(
+            Arg::with_name("best_by")
+                .long("best-by")
+                .value_name("SCORE")
+                .possible_values(&["identity", "support"])
+                .default_value("identity")
+                .help("Rank --best-hits by block length × identity of the CIGAR before aligning or by WFA supported bases after"),
+        )
+        .arg(best_overlap_arg.clone())
         .arg(
             Arg::with_name("v")
+                .global(true)
                 .short("v")
                 .multiple(true)
                 .help("Sets the level of verbosity [Default: 0]"),
         )
+        .subcommand(
+            SubCommand::with_name("realign")
+                .about("Replace the CIGAR of every PAF line with a WFA alignment of its region. Writes the PAF with a new cg:Z, NM:i, residue matches, block length and the WFA score as ws:i"),
+        )
+        .subcommand(
+            SubCommand::with_name("rescore")
+                .about("Score the existing CIGAR of every PAF line without the FASTA files. Writes the lines that pass the thresholds with the gap-affine score (rs:i), identity (ri:f), gap-compressed identity (rg:f) and indel events (re:i)")
+                .arg(
+                    Arg::with_name("min_identity")
+                        .long("min-identity")
+                        .value_name("FLOAT")
+                        .default_value("0")
+                        .help("Keep a PAF line only if its identity is at least this"),
+                )
+                .arg(
+                    Arg::with_name("min_gap_compressed_identity")
+                        .long("min-gap-compressed-identity")
+                        .value_name("FLOAT")
+                        .default_value("0")
+                        .help("Keep a PAF line only if its gap-compressed identity is at least this"),
+                )
+                .arg(
+                    Arg::with_name("max_indel_events")
+                        .long("max-indel-events")
+                        .value_name("INT")
+                        .help("Keep a PAF line only if its CIGAR has at most this many I and D ops"),
+                )
+                .arg(
+                    Arg::with_name("max_score")
+                        .long("max-score")
+                        .value_name("INT")
+                        .help("Keep a PAF line only if its gap-affine score is at most this"),
+                )
+                .arg(expr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("select")
+                .about("Write the PAF lines an expression selects, or the best hits by identity, without the FASTA files")
+                .arg(expr_arg.required_unless_one(&["best_hits", "sample_pairs"]))
+                .arg(sample_pairs_arg)
+                .arg(best_hits_arg)
+                .arg(best_overlap_arg),
+        )
         .get_matches();
 
     // Gets a value for config if supplied by user, or defaults to "default.conf"
     let paf_file_path: &str = matches.value_of("input_paf").unwrap();
-    let target_file_path: &str = matches.value_of("target_fasta").unwrap();
-    let query_file_path: &str = matches.value_of("query_fasta").unwrap();
+    // rescore and select only read the PAF
+    let fasta = |name: &str, flag: &str| -> Vec<String> {
+        match matches.values_of(name) {
+            Some(fps) => fps.map(String::from).collect(),
+            None if matches!(matches.subcommand_name(), Some("rescore" | "select")) => Vec::new(),
+            None => clap::Error::with_description(
+                &format!("The argument '{}' is required to align", flag),
+                ErrorKind::MissingRequiredArgument,
+            )
+            .exit(),
+        }
+    };
+    let target_fasta: Vec<String> = fasta("target_fasta", "--target <FILE>");
+    let query_fasta: Vec<String> = fasta("query_fasta", "--query <FILE>");
     let adapt: bool = matches.is_present("adapt");
+    let packed: bool = matches.is_present("packed");
+    let faidx: bool = matches.is_present("faidx");
+
+    // stdin can be read once and not seeked into
+    let from_stdin = target_fasta
+        .iter()
+        .chain(query_fasta.iter())
+        .filter(|fp| *fp == "-")
+        .count();
+    if from_stdin > 1 || (from_stdin > 0 && faidx) {
+        clap::Error::with_description(
+            "Only one --target or --query file can be - (stdin), and none with --faidx",
+            ErrorKind::ArgumentConflict,
+        )
+        .exit()
+    }
+    // aligning reads the PAF more than once, rescore and select read it once
+    let reads_paf_once = matches!(matches.subcommand_name(), Some("rescore" | "select"));
+    if paf_file_path == "-" && !reads_paf_once {
+        clap::Error::with_description(
+            "--paf can only be - (stdin) with rescore and select, give a file to align",
+            ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    let aliases = match matches.value_of("aliases") {
+        Some(fp) => fasta::parse_aliases(io::open(fp)).unwrap_or_else(|msg| {
+            clap::Error::with_description(
+                &format!("Invalid value for '--aliases <FILE>': {}", msg),
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        }),
+        None => fasta::Aliases::new(),
+    };
+    let validation = match matches.value_of("validate") {
+        Some("skip") => types::Validation::Skip,
+        Some("abort") => types::Validation::Abort,
+        _ => types::Validation::Warn,
+    };
     let generate_alignment_tsv: bool = matches.is_present("alignment_matrix");
+    let edit_distance: bool = matches.is_present("edit_distance");
+    let verify: bool = matches.is_present("verify");
+    let per_alignment: bool = matches.is_present("per_alignment");
+    let threads: usize = value_t!(matches, "threads", usize).unwrap_or_else(|e| e.exit());
+    let min_support_fraction: f64 =
+        value_t!(matches, "min_support_fraction", f64).unwrap_or_else(|e| e.exit());
+    let min_supported_bases: u64 =
+        value_t!(matches, "min_supported_bases", u64).unwrap_or_else(|e| e.exit());
+    let report: Option<String> = matches.value_of("report").map(String::from);
+    let report_format = match matches.value_of("report_format") {
+        Some("json") => types::ReportFormat::Json,
+        _ => types::ReportFormat::Tsv,
+    };
+    let genome_report: Option<String> = matches.value_of("genome_report").map(String::from);
+    let rejected: Option<String> = matches.value_of("rejected").map(String::from);
+    let invert: bool = matches.is_present("invert");
+    let annotate: bool = matches.is_present("annotate");
+    let add_tags: bool = matches.is_present("tags");
+    let trim: bool = matches.is_present("trim");
+    let aligner = match matches.value_of("aligner") {
+        Some("libwfa") => types::AlignerBackend::Libwfa,
+        _ => types::AlignerBackend::Native,
+    };
     let verbosity_level: u8 = matches.occurrences_of("v") as u8;
+    let mode = match matches.subcommand_name() {
+        Some("realign") => types::Mode::Realign,
+        Some("rescore") => types::Mode::Rescore,
+        Some("select") => types::Mode::Select,
+        _ => types::Mode::Filter,
+    };
 
-    types::CliArgs::new(
+    // thresholds of the rescore subcommand
+    let (mut min_identity, mut min_gap_compressed_identity) = (0.0, 0.0);
+    let (mut max_indel_events, mut max_score) = (None, None);
+    if let Some(rescore) = matches.subcommand_matches("rescore") {
+        let optional = |name: &str| -> Option<u64> {
+            if rescore.is_present(name) {
+                Some(value_t!(rescore, name, u64).unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            }
+        };
+
+        min_identity = value_t!(rescore, "min_identity", f64).unwrap_or_else(|e| e.exit());
+        min_gap_compressed_identity =
+            value_t!(rescore, "min_gap_compressed_identity", f64).unwrap_or_else(|e| e.exit());
+        max_indel_events = optional("max_indel_events");
+        max_score = optional("max_score");
+    }
+
+    // select takes these after the subcommand
+    let mode_matches = matches.subcommand_matches("select").unwrap_or(&matches);
+    let best_hits = match mode_matches.value_of("best_hits") {
+        Some("query") => Some(types::BestHits::Query),
+        Some("one-to-one") => Some(types::BestHits::OneToOne),
+        _ => None,
+    };
+    let sample_pairs = match mode_matches.value_of("sample_pairs") {
+        Some("same") => types::SamplePairs::Same,
+        Some("different") => types::SamplePairs::Different,
+        _ => types::SamplePairs::All,
+    };
+    let best_by = match matches.value_of("best_by") {
+        Some("support") => types::BestBy::Support,
+        _ => types::BestBy::Identity,
+    };
+    let best_overlap: f64 =
+        value_t!(mode_matches, "best_overlap", f64).unwrap_or_else(|e| e.exit());
+    // and rescore takes --expr after it too
+    let expr_matches = matches
+        .subcommand_matches("rescore")
+        .unwrap_or(mode_matches);
+    let expr = expr_matches.value_of("expr").map(|e| {
+        expr::Expr::parse(e).unwrap_or_else(|msg| {
+            clap::Error::with_description(
+                &format!("Invalid value for '--expr <EXPR>': {}", msg),
+                ErrorKind::InvalidValue,
+            )
+            .exit()
+        })
+    });
+
+    let args = types::CliArgs::new(
         verbosity_level,
         paf_file_path,
-        target_file_path,
-        query_file_path,
+        "",
+        "",
         None, // TODO: implement penalties
         adapt,
-        generate_alignment_tsv
-    )
+        generate_alignment_tsv,
+    );
+
+    types::CliArgs {
+        mode,
+        target_fasta,
+        query_fasta,
+        packed,
+        faidx,
+        aliases,
+        validation,
+        edit_distance,
+        verify,
+        aligner,
+        per_alignment,
+        min_support_fraction,
+        min_supported_bases,
+        report,
+        report_format,
+        genome_report,
+        rejected,
+        invert,
+        annotate,
+        add_tags,
+        trim,
+        min_identity,
+        min_gap_compressed_identity,
+        max_indel_events,
+        max_score,
+        expr,
+        sample_pairs,
+        best_hits,
+        best_by,
+        best_overlap,
+        threads,
+        ..args
+    }
 }
@@ -0,0 +1,577 @@
+/*
+Filter expressions
+------------------
+
+A small language to select PAF lines on their columns and tags, e.g.
+
+    mapq >= 20 && tp == "P" && block_len > 5000 && de < 0.02
+
+Comparisons (==, !=, <, <=, >, >=) between fields, numbers and quoted strings
+are combined with &&, || and ! and grouped with parentheses. && binds tighter
+than ||.
+
+|Field                                |Value                                   |
+|:------------------------------------|:---------------------------------------|
+|query, target                        |Sequence names                          |
+|query_len, target_len                |Sequence lengths                        |
+|query_start, query_end               |Query coordinates                       |
+|target_start, target_end             |Target coordinates                      |
+|strand                               |"+" or "-"                              |
+|residue_matches, block_len, mapq     |Columns 10, 11 and 12                   |
+|cigar_score, identity,               |The CIGAR rescored as by the rescore    |
+|gap_compressed_identity, indel_events|subcommand (see rescore.rs)             |
+|query_sample, query_haplotype,       |PanSN parts of the query name          |
+|query_genome                         |e.g. HG002, 1 and HG002#1 (see pansn.rs)|
+|target_sample, target_haplotype,     |PanSN parts of the target name         |
+|target_genome                        |                                        |
+|any other two character name         |The tag of that name e.g. NM, tp or de  |
+
+A comparison involving a tag the line does not have, a PanSN part a name does
+not have, or a number and a string, is false.
+*/
+use std::io::{BufRead, Write};
+
+use super::besthits;
+use super::paf::{self, TagValue};
+use super::pansn::{self, PanSn};
+use super::rescore;
+use super::types;
+
+const COLUMNS: [&str; 12] = [
+    "query",
+    "query_len",
+    "query_start",
+    "query_end",
+    "strand",
+    "target",
+    "target_len",
+    "target_start",
+    "target_end",
+    "residue_matches",
+    "block_len",
+    "mapq",
+];
+
+const PANSN: [&str; 6] = [
+    "query_sample",
+    "query_haplotype",
+    "query_genome",
+    "target_sample",
+    "target_haplotype",
+    "target_genome",
+];
+
+const METRICS: [&str; 4] = [
+    "cigar_score",
+    "identity",
+    "gap_compressed_identity",
+    "indel_events",
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+    Literal(Value),
+    Field(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Compare(Operand, Op, Operand),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Text(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut i = 0;
+
+    // the next char is c
+    let next_is = |i: usize, c: char| chars.get(i + 1) == Some(&c);
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::Open);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                i += 1;
+            }
+            '&' | '|' if next_is(i, c) => {
+                tokens.push(if c == '&' { Token::And } else { Token::Or });
+                i += 2;
+            }
+            '=' if next_is(i, '=') => {
+                tokens.push(Token::Op(Op::Eq));
+                i += 2;
+            }
+            '!' | '<' | '>' => {
+                let eq = next_is(i, '=');
+                tokens.push(match (c, eq) {
+                    ('!', true) => Token::Op(Op::Ne),
+                    ('!', false) => Token::Not,
+                    ('<', true) => Token::Op(Op::Le),
+                    ('<', false) => Token::Op(Op::Lt),
+                    ('>', true) => Token::Op(Op::Ge),
+                    _ => Token::Op(Op::Gt),
+                });
+                i += if eq { 2 } else { 1 };
+            }
+            '"' | '\'' => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(format!("unterminated string in {}", s)),
+                        Some(q) if *q == c => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            text.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(x) => {
+                            text.push(*x);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::Text(text));
+                i += 1;
+            }
+            _ if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() {
+                    let x = chars[i];
+                    let exponent_sign = (x == '-' || x == '+') && matches!(chars[i - 1], 'e' | 'E');
+                    if x.is_ascii_digit() || matches!(x, '.' | 'e' | 'E') || exponent_sign {
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let number: String = chars[start..i].iter().collect();
+                let number = number
+                    .parse::<f64>()
+                    .map_err(|_| format!("malformed number {}", number))?;
+                tokens.push(Token::Number(number));
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            _ => return Err(format!("unexpected {} at {} in {}", c, i, s)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+// A column, a metric, a PanSN part or a SAM-like tag name
+fn is_field(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    let is_tag =
+        bytes.len() == 2 && bytes[0].is_ascii_alphabetic() && bytes[1].is_ascii_alphanumeric();
+    is_tag || COLUMNS.contains(&name) || METRICS.contains(&name) || PANSN.contains(&name)
+}
+
+// Recursive descent over the tokens, lowest precedence first
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Expr::Not(Box::new(self.not()?)))
+            }
+            Some(Token::Open) => {
+                self.next();
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(String::from("missing )")),
+                }
+            }
+            _ => self.compare(),
+        }
+    }
+
+    fn compare(&mut self) -> Result<Expr, String> {
+        let left = self.operand()?;
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            Some(t) => return Err(format!("expected a comparison, found {:?}", t)),
+            None => return Err(String::from("expected a comparison at the end")),
+        };
+        let right = self.operand()?;
+        Ok(Expr::Compare(left, op, right))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Number(x)) => Ok(Operand::Literal(Value::Number(x))),
+            Some(Token::Text(s)) => Ok(Operand::Literal(Value::Text(s))),
+            Some(Token::Ident(name)) if is_field(&name) => Ok(Operand::Field(name)),
+            Some(Token::Ident(name)) => Err(format!("unknown field {}", name)),
+            Some(t) => Err(format!("expected a field or a value, found {:?}", t)),
+            None => Err(String::from("expected a field or a value at the end")),
+        }
+    }
+}
+
+fn field(name: &str, a: &paf::PafAlignment, penalties: &types::Penalties) -> Option<Value> {
+    let number = |x: u32| Some(Value::Number(x as f64));
+
+    match name {
+        "query" => Some(Value::Text(a.query.clone())),
+        "query_len" => number(a.query_length),
+        "query_start" => number(a.query_start),
+        "query_end" => number(a.query_end),
+        "strand" => Some(Value::Text(String::from(match a.strand {
+            types::Strand::Forward => "+",
+            types::Strand::Reverse => "-",
+        }))),
+        "target" => Some(Value::Text(a.target.clone())),
+        "target_len" => number(a.target_length),
+        "target_start" => number(a.target_start),
+        "target_end" => number(a.target_end),
+        "residue_matches" => number(a.residue_matches),
+        "block_len" => number(a.block_len),
+        "mapq" => number(a.mapq),
+        _ if PANSN.contains(&name) => {
+            let (sequence, part) = name.split_once('_')?;
+            let pansn: PanSn = match sequence {
+                "query" => a.query_pansn(),
+                _ => a.target_pansn(),
+            }?;
+            let value = match part {
+                "sample" => Some(pansn.sample),
+                "haplotype" => pansn.haplotype,
+                _ => Some(pansn.genome),
+            };
+            value.map(|v| Value::Text(String::from(v)))
+        }
+        _ if METRICS.contains(&name) => {
+            let score = rescore::rescore(a, penalties).ok()?;
+            Some(Value::Number(match name {
+                "cigar_score" => score.score as f64,
+                "identity" => score.identity,
+                "gap_compressed_identity" => score.gap_compressed_identity,
+                _ => score.indel_events as f64,
+            }))
+        }
+        _ => match &a.tag(name)?.value {
+            TagValue::Int(i) => Some(Value::Number(*i as f64)),
+            TagValue::Float(x) => Some(Value::Number(*x)),
+            TagValue::Char(c) => Some(Value::Text(c.to_string())),
+            TagValue::String(s) | TagValue::Hex(s) | TagValue::Array(s) => {
+                Some(Value::Text(s.clone()))
+            }
+            // a tag that does not parse has no value to compare
+            TagValue::Opaque(_) => None,
+        },
+    }
+}
+
+fn compare(left: Option<Value>, op: Op, right: Option<Value>) -> bool {
+    let ordering = match (left, right) {
+        (Some(Value::Number(l)), Some(Value::Number(r))) => l.partial_cmp(&r),
+        (Some(Value::Text(l)), Some(Value::Text(r))) => Some(l.cmp(&r)),
+        _ => None,
+    };
+
+    match ordering {
+        Some(o) => match op {
+            Op::Eq => o.is_eq(),
+            Op::Ne => o.is_ne(),
+            Op::Lt => o.is_lt(),
+            Op::Le => o.is_le(),
+            Op::Gt => o.is_gt(),
+            Op::Ge => o.is_ge(),
+        },
+        None => false,
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(t) => Err(format!("unexpected {:?} after the expression", t)),
+        }
+    }
+
+    pub fn eval(&self, a: &paf::PafAlignment, penalties: &types::Penalties) -> bool {
+        let value = |operand: &Operand| match operand {
+            Operand::Literal(v) => Some(v.clone()),
+            Operand::Field(name) => field(name, a, penalties),
+        };
+
+        match self {
+            Expr::Compare(left, op, right) => compare(value(left), *op, value(right)),
+            Expr::And(l, r) => l.eval(a, penalties) && r.eval(a, penalties),
+            Expr::Or(l, r) => l.eval(a, penalties) || r.eval(a, penalties),
+            Expr::Not(e) => !e.eval(a, penalties),
+        }
+    }
+}
+
+// Whether the line passes --expr and --sample-pairs, every line does without them
+pub fn is_selected(a: &paf::PafAlignment, cli_args: &types::CliArgs) -> bool {
+    let pair = match cli_args.sample_pairs {
+        types::SamplePairs::All => true,
+        types::SamplePairs::Same => pansn::same_sample(&a.query, &a.target),
+        types::SamplePairs::Different => !pansn::same_sample(&a.query, &a.target),
+    };
+
+    pair && cli_args
+        .expr
+        .as_ref()
+        .is_none_or(|e| e.eval(a, &cli_args.penalties))
+}
+
+// Write the lines the expression selects unchanged. With --best-hits the
+// lines are held until the best of them by identity are known.
+pub fn select_paf<R: BufRead, W: Write>(
+    reader: R,
+    cli_args: &types::CliArgs,
+    out: &mut W,
+) -> std::io::Result<()> {
+    if cli_args.best_hits.is_none() {
+        for line in reader.lines() {
+            let line = line?;
+            if is_selected(&paf::PafAlignment::from_str(&line), cli_args) {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        return out.flush();
+    }
+
+    let lines: Vec<String> = reader.lines().collect::<std::io::Result<_>>()?;
+    let alignments: Vec<paf::PafAlignment> = lines
+        .iter()
+        .map(|l| paf::PafAlignment::from_str(l))
+        .collect();
+    let selected: Vec<bool> = alignments
+        .iter()
+        .map(|a| is_selected(a, cli_args))
+        .collect();
+    let scores: Vec<f64> = alignments
+        .iter()
+        .map(|a| besthits::identity_score(a, &cli_args.penalties))
+        .collect();
+
+    let best = besthits::best_hits(&alignments, &scores, &selected, cli_args);
+    for (line, _) in lines.iter().zip(best).filter(|(_, b)| *b) {
+        writeln!(out, "{}", line)?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PAF_STRING: &str = "\
+    qry\t1000\t0\t1000\t+\ttgt\t2000\t0\t1000\t990\t1000\t60\ttp:A:P\tde:f:0.01\tcg:Z:990M10D\n\
+    qry\t1000\t0\t100\t-\ttgt\t2000\t0\t100\t100\t100\t10\ttp:A:S\tcg:Z:100M";
+
+    fn selected(expr: &str) -> Vec<bool> {
+        let expr = Expr::parse(expr).unwrap();
+        let penalties = types::CliArgs::for_tests().penalties;
+        paf::PAF::from_str(PAF_STRING)
+            .get_alignments()
+            .iter()
+            .map(|a| expr.eval(a, &penalties))
+            .collect()
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(
+            selected("mapq >= 20 && tp == \"P\" && block_len > 500 && de < 0.02"),
+            vec![true, false]
+        );
+        assert_eq!(
+            selected("strand == '-' || query_start > 0"),
+            vec![false, true]
+        );
+        assert_eq!(
+            selected("!(tp == 'P') && target == \"tgt\""),
+            vec![false, true]
+        );
+        // precedence: a || (b && c)
+        assert_eq!(
+            selected("mapq < 20 || mapq > 20 && block_len < 500"),
+            vec![false, true]
+        );
+        assert_eq!(
+            selected("indel_events == 1 && identity < 1"),
+            vec![true, false]
+        );
+        assert_eq!(selected("cigar_score == 26"), vec![true, false]);
+    }
+
+    #[test]
+    fn test_missing_and_mismatched() {
+        // the second line has no de tag
+        assert_eq!(selected("de < 1"), vec![true, false]);
+        assert_eq!(selected("de != 1"), vec![true, false]);
+        assert_eq!(selected("tp == 1"), vec![false, false]);
+        assert_eq!(selected("1e-3 < de"), vec![true, false]);
+    }
+
+    #[test]
+    fn test_select_paf() {
+        let cli_args = types::CliArgs {
+            expr: Some(Expr::parse("mapq > 5").unwrap()),
+            ..types::CliArgs::for_tests()
+        };
+        let mut out: Vec<u8> = Vec::new();
+        select_paf(PAF_STRING.as_bytes(), &cli_args, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 2);
+
+        // the lines overlap on the query, the first has more matches
+        let cli_args = types::CliArgs {
+            best_hits: Some(types::BestHits::Query),
+            ..cli_args
+        };
+        let mut out: Vec<u8> = Vec::new();
+        select_paf(PAF_STRING.as_bytes(), &cli_args, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out.lines().collect::<Vec<&str>>(),
+            vec![PAF_STRING.lines().next().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_pansn() {
+        let paf_string = "\
+        HG002#1#chr1\t100\t0\t100\t+\tHG002#2#chr1\t100\t0\t100\t100\t100\t60\n\
+        HG002#1#chr1\t100\t0\t100\t+\tHG005#1#chr1\t100\t0\t100\t100\t100\t60\n\
+        chr1\t100\t0\t100\t+\tchr1\t100\t0\t100\t100\t100\t60\n";
+        let alignments = paf::PAF::from_str(paf_string);
+
+        let selected = |cli_args: &types::CliArgs| -> Vec<bool> {
+            alignments
+                .get_alignments()
+                .iter()
+                .map(|a| is_selected(a, cli_args))
+                .collect()
+        };
+        let args = |expr: Option<&str>, sample_pairs| types::CliArgs {
+            expr: expr.map(|e| Expr::parse(e).unwrap()),
+            sample_pairs,
+            ..types::CliArgs::for_tests()
+        };
+
+        assert_eq!(
+            selected(&args(None, types::SamplePairs::Same)),
+            vec![true, false, false]
+        );
+        assert_eq!(
+            selected(&args(None, types::SamplePairs::Different)),
+            vec![false, true, true]
+        );
+        assert_eq!(
+            selected(&args(
+                Some("target_genome == \"HG002#2\" || target_sample == \"HG005\""),
+                types::SamplePairs::All
+            )),
+            vec![true, true, false]
+        );
+        assert_eq!(
+            selected(&args(
+                Some("query_haplotype != target_haplotype"),
+                types::SamplePairs::All
+            )),
+            vec![true, false, false]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expr::parse("mapq >= ").is_err());
+        assert!(Expr::parse("mapq = 20").is_err());
+        assert!(Expr::parse("quality > 20").is_err());
+        assert!(Expr::parse("(mapq > 20").is_err());
+        assert!(Expr::parse("mapq > 20 20").is_err());
+        assert!(Expr::parse("tp == \"P").is_err());
+        assert!(Expr::parse("mapq").is_err());
+    }
+}
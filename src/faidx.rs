@@ -0,0 +1,474 @@
+/*
+Indexed FASTA
+-------------
+
+Random access to a FASTA file through a samtools compatible `.fai` index so
+that only the sequences, or the regions of them, that the PAF names are read.
+The index is read from `<fasta>.fai` or built and written there if missing.
+A bgzipped FASTA is read through its `.gzi` index as well and the offsets in
+the `.fai` are then in the uncompressed data, as samtools writes them.
+
+|Column     |Description                                        |
+|:----------|:--------------------------------------------------|
+|name       |Sequence name, the header up to the first whitespace|
+|length     |Bases in the sequence                              |
+|offset     |Byte offset of the first base                      |
+|line_bases |Bases on each line                                 |
+|line_width |Bytes on each line including the newline           |
+
+Every line of a record but the last must have the same length.
+*/
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use super::bgzf;
+use super::fasta;
+use super::io;
+use super::packed::PackedSeq;
+use super::paf;
+use super::types;
+use super::validate;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FaiEntry {
+    pub name: String,
+    pub length: usize,
+    pub offset: u64,
+    pub line_bases: usize,
+    pub line_width: usize,
+}
+
+impl FaiEntry {
+    // Byte offset of base i
+    fn position(&self, i: usize) -> u64 {
+        if self.line_bases == 0 {
+            return self.offset;
+        }
+        self.offset + ((i / self.line_bases) * self.line_width + i % self.line_bases) as u64
+    }
+}
+
+pub fn parse_fai<R: BufRead>(reader: R) -> Result<Vec<FaiEntry>, String> {
+    reader
+        .lines()
+        .enumerate()
+        .map(|(line_num, line)| {
+            let line = line.map_err(|e| e.to_string())?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            let number = |i: usize| -> Result<u64, String> {
+                fields
+                    .get(i)
+                    .and_then(|f| f.parse::<u64>().ok())
+                    .ok_or_else(|| format!("bad column {} on .fai line {}", i + 1, line_num + 1))
+            };
+
+            Ok(FaiEntry {
+                name: fields[0].to_string(),
+                length: number(1)? as usize,
+                offset: number(2)?,
+                line_bases: number(3)? as usize,
+                line_width: number(4)? as usize,
+            })
+        })
+        .collect()
+}
+
+pub fn write_fai<W: Write>(entries: &[FaiEntry], out: &mut W) -> std::io::Result<()> {
+    for e in entries {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            e.name, e.length, e.offset, e.line_bases, e.line_width
+        )?;
+    }
+    out.flush()
+}
+
+// Index a FASTA in one pass over its lines
+pub fn build<R: BufRead>(mut reader: R) -> Result<Vec<FaiEntry>, String> {
+    let mut entries: Vec<FaiEntry> = Vec::new();
+    let mut offset: u64 = 0;
+    let mut line = String::new();
+    // a line shorter than line_bases ends the record
+    let mut ended = false;
+
+    loop {
+        line.clear();
+        let width = reader.read_line(&mut line).map_err(|e| e.to_string())?;
+        if width == 0 {
+            break;
+        }
+        offset += width as u64;
+
+        if let Some(header) = line.strip_prefix('>') {
+            let name = header.split_whitespace().next().unwrap_or("");
+            entries.push(FaiEntry {
+                name: name.to_string(),
+                length: 0,
+                offset,
+                line_bases: 0,
+                line_width: 0,
+            });
+            ended = false;
+            continue;
+        }
+
+        if entries.is_empty() && line.starts_with('@') {
+            return Err(String::from(
+                "FASTQ can not be indexed, read it without --faidx",
+            ));
+        }
+        let entry = entries
+            .last_mut()
+            .ok_or_else(|| String::from("sequence before the first header"))?;
+        let bases = line.trim_end_matches(['\n', '\r']).len();
+        if bases == 0 {
+            ended = true;
+            continue;
+        }
+        if entry.line_bases == 0 {
+            entry.line_bases = bases;
+            entry.line_width = width;
+        } else if ended
+            || bases > entry.line_bases
+            // only the last line may be missing its newline
+            || (width > bases && width - bases != entry.line_width - entry.line_bases)
+        {
+            return Err(format!("different line lengths in {}", entry.name));
+        }
+        if bases < entry.line_bases {
+            ended = true;
+        }
+        entry.length += bases;
+    }
+
+    Ok(entries)
+}
+
+// The bases [start, end) of a sequence
+pub fn fetch<R: Read + Seek>(
+    reader: &mut R,
+    entry: &FaiEntry,
+    range: Range<usize>,
+) -> Result<Vec<u8>, String> {
+    if range.start > range.end || range.end > entry.length {
+        return Err(format!(
+            "region {}-{} is out of bounds for {} of length {}",
+            range.start, range.end, entry.name, entry.length
+        ));
+    }
+    if range.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let (from, to) = (
+        entry.position(range.start),
+        entry.position(range.end - 1) + 1,
+    );
+    let mut bytes = vec![0u8; (to - from) as usize];
+    reader
+        .seek(SeekFrom::Start(from))
+        .and_then(|_| reader.read_exact(&mut bytes))
+        .map_err(|e| format!("could not read {}: {}", entry.name, e))?;
+
+    bytes.retain(|b| *b != b'\n' && *b != b'\r');
+    Ok(bytes)
+}
+
+// Read <fp>.fai or build it and try to write it next to the FASTA
+pub fn open_index(fp: &str, verbosity: u8) -> Result<Vec<FaiEntry>, String> {
+    let fai = format!("{}.fai", fp);
+    if let Ok(file) = File::open(&fai) {
+        return parse_fai(BufReader::new(file));
+    }
+
+    let entries = build(io::open(fp))?;
+
+    match File::create(&fai).and_then(|f| write_fai(&entries, &mut std::io::BufWriter::new(f))) {
+        Ok(_) if verbosity > 0 => eprintln!("[wfilter::faidx] wrote {}", fai),
+        Ok(_) => {}
+        Err(e) => eprintln!("[wfilter::faidx] could not write {}: {}", fai, e),
+    }
+
+    Ok(entries)
+}
+
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+// A plain FASTA or a bgzipped one, gzip can not be read from an offset
+pub fn open_fasta(fp: &str, verbosity: u8) -> Result<Box<dyn ReadSeek>, String> {
+    let mut file = File::open(fp).map_err(|e| format!("could not open {}: {}", fp, e))?;
+    let mut header = [0u8; 16];
+    let n = file.read(&mut header).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+
+    match bgzf::detect(&header[..n]) {
+        bgzf::Compression::Plain => Ok(Box::new(BufReader::new(file))),
+        bgzf::Compression::Bgzf => {
+            let gzi = bgzf::open_gzi(fp, verbosity)?;
+            Ok(Box::new(bgzf::BgzfReader::new(file, gzi)))
+        }
+        bgzf::Compression::Gzip => Err(format!(
+            "{} is gzip compressed, recompress it with bgzip to index it",
+            fp
+        )),
+    }
+}
+
+// The spans of each query and target the PAF lines cover, by record ID, sorted
+// and merged where they overlap or touch. With whole set the spans are the
+// whole sequences. The lines are checked against records that hold no bases,
+// from the index, before anything is read: a line --validate skip leaves out
+// only adds its records, so that realign can still write it, and a span past
+// the end of a record is cut there for --validate to report later.
+pub type Regions = HashMap<String, Vec<Range<usize>>>;
+
+fn merge(spans: &mut Vec<Range<usize>>) {
+    spans.sort_by_key(|s| s.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(spans.len());
+    for span in spans.drain(..) {
+        match merged.last_mut() {
+            Some(last) if span.start <= last.end => last.end = last.end.max(span.end),
+            _ => merged.push(span),
+        }
+    }
+    *spans = merged;
+}
+
+pub fn paf_regions<R: BufRead>(
+    reader: R,
+    whole: bool,
+    target: &[fasta::Fasta],
+    query: &[fasta::Fasta],
+    cli_args: &types::CliArgs,
+) -> (Regions, Regions) {
+    let targets = fasta::index_by_name(target, &cli_args.aliases);
+    let queries = fasta::index_by_name(query, &cli_args.aliases);
+    let mut target_regions = Regions::new();
+    let mut query_regions = Regions::new();
+
+    // a name that is not in the FASTA is reported once it is read
+    let cover =
+        |regions: &mut Regions, record: Option<&fasta::Fasta>, span: Range<usize>, skip: bool| {
+            if let Some(f) = record {
+                let spans = regions
+                    .entry(String::from_utf8_lossy(f.name()).into_owned())
+                    .or_default();
+                if skip {
+                    return;
+                }
+                spans.push(if whole {
+                    0..f.length
+                } else {
+                    let end = span.end.min(f.length);
+                    span.start.min(end)..end
+                });
+            }
+        };
+
+    for line in reader.lines() {
+        let line = line.unwrap_or_else(|e| panic!("[wfilter::faidx] could not read PAF: {}", e));
+        let a = paf::PafAlignment::from_str(&line);
+        let t = targets.get(a.target.as_bytes()).copied();
+        let q = queries.get(a.query.as_bytes()).copied();
+        let skip =
+            cli_args.validation == types::Validation::Skip && !validate::check(&a, t, q).is_empty();
+
+        cover(
+            &mut target_regions,
+            t,
+            a.target_start as usize..a.target_end as usize,
+            skip,
+        );
+        cover(
+            &mut query_regions,
+            q,
+            a.query_start as usize..a.query_end as usize,
+            skip,
+        );
+    }
+
+    target_regions
+        .values_mut()
+        .chain(query_regions.values_mut())
+        .for_each(merge);
+    (target_regions, query_regions)
+}
+
+// The records of an index holding none of their bases, to check lines against
+pub fn unloaded(entries: &[FaiEntry]) -> fasta::FastaFile {
+    entries
+        .iter()
+        .map(|e| fasta::Fasta {
+            header: e.name.as_bytes().to_vec(),
+            seq: Vec::new(),
+            packed: None,
+            pieces: Vec::new(),
+            length: e.length,
+        })
+        .collect()
+}
+
+// Read only the given regions of the sequences, in the order of the index, each
+// region a piece of its record
+pub fn load<R: Read + Seek>(
+    reader: &mut R,
+    entries: &[FaiEntry],
+    regions: &Regions,
+    packed: bool,
+) -> Result<fasta::FastaFile, String> {
+    entries
+        .iter()
+        .filter_map(|e| regions.get(&e.name).map(|spans| (e, spans)))
+        .map(|(e, spans)| {
+            let mut seq = Vec::new();
+            for span in spans {
+                seq.extend(fetch(reader, e, span.clone())?);
+            }
+            Ok(fasta::Fasta {
+                header: e.name.as_bytes().to_vec(),
+                pieces: spans.clone(),
+                length: e.length,
+                packed: if packed {
+                    Some(PackedSeq::from_bytes(&seq))
+                } else {
+                    None
+                },
+                seq: if packed { Vec::new() } else { seq },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    static FASTA: &str = ">chr1 first\nACGTA\nCGTAC\nGT\n>chr2\r\nTTGG\r\nCC\r\n>chr3\nAAAA\n";
+
+    #[test]
+    fn test_build() {
+        let entries = build(FASTA.as_bytes()).unwrap();
+        let mut fai: Vec<u8> = Vec::new();
+        write_fai(&entries, &mut fai).unwrap();
+        assert_eq!(
+            String::from_utf8(fai.clone()).unwrap(),
+            "chr1\t12\t12\t5\t6\nchr2\t6\t34\t4\t6\nchr3\t4\t50\t4\t5\n"
+        );
+        assert_eq!(parse_fai(&fai[..]).unwrap(), entries);
+
+        assert!(build(">a\nACG\nACGT\n".as_bytes()).is_err());
+        assert!(build(">a\nACGT\nAC\nACGT\n".as_bytes()).is_err());
+        assert!(build("@a\nACGT\n+\nIIII\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_fetch() {
+        let entries = build(FASTA.as_bytes()).unwrap();
+        let mut reader = Cursor::new(FASTA.as_bytes());
+
+        assert_eq!(
+            fetch(&mut reader, &entries[0], 0..12).unwrap(),
+            b"ACGTACGTACGT"
+        );
+        assert_eq!(fetch(&mut reader, &entries[0], 4..11).unwrap(), b"ACGTACG");
+        assert_eq!(fetch(&mut reader, &entries[1], 3..6).unwrap(), b"GCC");
+        assert_eq!(fetch(&mut reader, &entries[2], 2..2).unwrap(), b"");
+        assert!(fetch(&mut reader, &entries[2], 2..5).is_err());
+    }
+
+    #[test]
+    fn test_fetch_bgzf() {
+        // blocks that end mid line
+        let data = bgzf::tests::bgzip(FASTA.as_bytes(), 7);
+        let entries = build(bgzf::reader(&data[..]).unwrap()).unwrap();
+        assert_eq!(entries, build(FASTA.as_bytes()).unwrap());
+
+        let gzi = bgzf::build_gzi(&data[..]).unwrap();
+        let mut reader = bgzf::BgzfReader::new(Cursor::new(data), gzi);
+        assert_eq!(fetch(&mut reader, &entries[0], 4..11).unwrap(), b"ACGTACG");
+        assert_eq!(fetch(&mut reader, &entries[1], 3..6).unwrap(), b"GCC");
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut spans = vec![8..10, 0..4, 3..5, 5..6, 20..30, 12..15];
+        merge(&mut spans);
+        assert_eq!(spans, vec![0..6, 8..10, 12..15, 20..30]);
+    }
+
+    #[test]
+    fn test_load() {
+        let paf = "\
+        q\t4\t0\t4\t+\tchr1\t12\t2\t6\t4\t4\t60\n\
+        q\t4\t0\t4\t+\tchr1\t12\t8\t10\t2\t2\t60\n";
+        let entries = build(FASTA.as_bytes()).unwrap();
+        let records = unloaded(&entries);
+        let mut reader = Cursor::new(FASTA.as_bytes());
+        let cli_args = types::CliArgs::for_tests();
+
+        let (targets, queries) = paf_regions(paf.as_bytes(), false, &records, &records, &cli_args);
+        assert_eq!(targets[&String::from("chr1")], vec![2..6, 8..10]);
+        // q is not a record ID
+        assert!(queries.is_empty());
+
+        let target = load(&mut reader, &entries, &targets, false).unwrap();
+        assert_eq!(target.len(), 1);
+        assert_eq!(target[0].name(), b"chr1");
+        assert_eq!(target[0].region(2..6).unwrap().to_bytes(), b"GTAC".to_vec());
+        assert_eq!(target[0].region(8..10).unwrap().to_bytes(), b"AC".to_vec());
+        // the gap between the lines is not read
+        assert!(target[0].region(4..8).is_none());
+        assert!(target[0].region(0..4).is_none());
+        assert_eq!(target[0].len(), 6);
+        assert_eq!(target[0].length, 12);
+
+        let (targets, _) = paf_regions(paf.as_bytes(), true, &records, &records, &cli_args);
+        let target = load(&mut reader, &entries, &targets, true).unwrap();
+        assert_eq!(target[0].bases().to_bytes(), b"ACGTACGTACGT".to_vec());
+
+        // regions of a renamed sequence are read from the record it stands for
+        let cli_args = types::CliArgs {
+            aliases: fasta::parse_aliases("q chr3\n".as_bytes()).unwrap(),
+            ..types::CliArgs::for_tests()
+        };
+        let (_, queries) = paf_regions(paf.as_bytes(), false, &records, &records, &cli_args);
+        let query = load(&mut reader, &entries, &queries, false).unwrap();
+        assert_eq!(query[0].name(), b"chr3");
+        assert_eq!(query[0].bases().to_bytes(), b"AAAA".to_vec());
+    }
+
+    #[test]
+    fn test_stale_regions() {
+        // made against a chr1 of 14 bases, the index has 12
+        let paf = "\
+        chr3\t4\t0\t4\t+\tchr1\t14\t8\t14\t4\t6\t60\n\
+        chr3\t4\t0\t4\t+\tchr1\t12\t0\t2\t2\t2\t60\n";
+        let records = unloaded(&build(FASTA.as_bytes()).unwrap());
+        // the spans read of chr1
+        let regions = |validation: types::Validation, whole: bool| -> Vec<(usize, usize)> {
+            let cli_args = types::CliArgs {
+                validation,
+                ..types::CliArgs::for_tests()
+            };
+            let (targets, _) = paf_regions(paf.as_bytes(), whole, &records, &records, &cli_args);
+            targets[&String::from("chr1")]
+                .iter()
+                .map(|r| (r.start, r.end))
+                .collect()
+        };
+
+        // a skipped line adds its records but no spans
+        assert_eq!(regions(types::Validation::Skip, false), vec![(0, 2)]);
+        // otherwise it is cut at the end of the record
+        assert_eq!(
+            regions(types::Validation::Warn, false),
+            vec![(0, 2), (8, 12)]
+        );
+        assert_eq!(regions(types::Validation::Warn, true), vec![(0, 12)]);
+    }
+}
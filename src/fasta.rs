@@ -1,16 +1,166 @@
 use seq_io::fasta::Reader;
-use std::io::Read;
+use seq_io::fastq;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Read};
+use std::ops::Range;
+
+use super::io;
+use super::packed::{PackedSeq, PackedSlice};
+use super::pansn::PanSn;
+use super::utils;
 
 pub struct Fasta {
     pub header: Vec<u8>,
     pub seq: Vec<u8>,
+    // the sequence when read with --packed, seq is then empty
+    pub packed: Option<PackedSeq>,
+    // the regions held, in order and with their bases one after another in
+    // seq, the whole sequence unless only regions are read with --faidx
+    pub pieces: Vec<Range<usize>>,
+    // bases in the whole sequence, more than len() when only regions are held
+    pub length: usize,
 }
 
 pub type FastaFile = Vec<Fasta>;
 
+// The bases of a record or a region of one
+pub enum Bases<'a> {
+    Plain(Cow<'a, [u8]>),
+    Packed(Cow<'a, PackedSeq>, Range<usize>),
+}
+
+impl<'a> Bases<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            Bases::Plain(seq) => seq.len(),
+            Bases::Packed(_, range) => range.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn packed(&self) -> Option<PackedSlice<'_>> {
+        match self {
+            Bases::Plain(_) => None,
+            Bases::Packed(seq, range) => Some(seq.slice(range.clone())),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Bases::Plain(seq) => Cow::Borrowed(seq),
+            Bases::Packed(seq, range) => Cow::Owned(seq.unpack(range.clone())),
+        }
+    }
+
+    // A packed region stays packed
+    pub fn reverse_complement(&self) -> Bases<'static> {
+        let seq = utils::reverse_complement(&self.to_bytes());
+        match self {
+            Bases::Plain(_) => Bases::Plain(Cow::Owned(seq)),
+            Bases::Packed(..) => {
+                Bases::Packed(Cow::Owned(PackedSeq::from_bytes(&seq)), 0..seq.len())
+            }
+        }
+    }
+}
+
 // TODO: have the from methods return Fasta and not FastaFile
 impl Fasta {
-    fn parse_fasta<R>(reader: Reader<R>) -> FastaFile
+    // The record ID, the header up to the first whitespace, as PAF names it
+    pub fn name(&self) -> &[u8] {
+        self.header
+            .split(|b| b.is_ascii_whitespace())
+            .next()
+            .unwrap_or(&[])
+    }
+
+    // The sample, haplotype and contig of a PanSN record ID
+    pub fn pansn(&self) -> Option<PanSn<'_>> {
+        std::str::from_utf8(self.name()).ok().and_then(PanSn::parse)
+    }
+
+    // The rest of the header after the ID
+    #[allow(dead_code)]
+    pub fn description(&self) -> &[u8] {
+        let rest = &self.header[self.name().len()..];
+        let start = rest
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        &rest[start..]
+    }
+
+    // Bases held, over all pieces
+    pub fn len(&self) -> usize {
+        match &self.packed {
+            Some(packed) => packed.len(),
+            None => self.seq.len(),
+        }
+    }
+
+    // Move the sequence into 2-bit packed storage
+    pub fn pack(self) -> Self {
+        let packed = PackedSeq::from_bytes(&self.seq);
+        Fasta {
+            header: self.header,
+            seq: Vec::new(),
+            packed: Some(packed),
+            pieces: self.pieces,
+            length: self.length,
+        }
+    }
+
+    // The bases [start, end), None if they are not all held
+    pub fn region(&self, range: Range<usize>) -> Option<Bases<'_>> {
+        if range.start > range.end {
+            return None;
+        }
+
+        // where the piece holding the region starts in seq
+        let mut held = 0;
+        for piece in &self.pieces {
+            if piece.start <= range.start && range.end <= piece.end {
+                let start = held + range.start - piece.start;
+                return Some(self.held(start..start + range.len()));
+            }
+            held += piece.len();
+        }
+        None
+    }
+
+    // All the bases held, the whole sequence unless regions were read with --faidx
+    pub fn bases(&self) -> Bases<'_> {
+        self.held(0..self.len())
+    }
+
+    fn held(&self, range: Range<usize>) -> Bases<'_> {
+        match &self.packed {
+            Some(packed) => Bases::Packed(Cow::Borrowed(packed), range),
+            None => Bases::Plain(Cow::Borrowed(&self.seq[range])),
+        }
+    }
+
+    // Each record is packed as it is read so only one is ever held unpacked
+    fn from_record(header: Vec<u8>, seq: Vec<u8>, packed: bool) -> Self {
+        let fasta = Fasta {
+            header,
+            length: seq.len(),
+            pieces: std::iter::once(0..seq.len()).collect(),
+            seq,
+            packed: None,
+        };
+        if packed {
+            fasta.pack()
+        } else {
+            fasta
+        }
+    }
+
+    fn parse_fasta<R>(reader: Reader<R>, packed: bool) -> FastaFile
     where
         R: Read,
     {
@@ -18,26 +168,121 @@ impl Fasta {
             .into_records()
             .map(|r| {
                 let r = r.unwrap();
-                Fasta {
-                    header: r.head.clone(),
-                    seq: r.seq.clone(),
-                }
+                Self::from_record(r.head, r.seq, packed)
             })
             .collect()
     }
 
+    // The qualities are dropped
+    fn parse_fastq<R>(reader: fastq::Reader<R>, packed: bool) -> FastaFile
+    where
+        R: Read,
+    {
+        reader
+            .into_records()
+            .map(|r| {
+                let r = r.unwrap();
+                Self::from_record(r.head, r.seq, packed)
+            })
+            .collect()
+    }
+
+    // FASTA or FASTQ, told apart by the first byte
+    pub fn from_reader<R: BufRead>(mut reader: R, packed: bool) -> FastaFile {
+        let first = reader.fill_buf().ok().and_then(|b| b.first().copied());
+        match first {
+            Some(b'@') => Self::parse_fastq(fastq::Reader::new(reader), packed),
+            _ => Self::parse_fasta(Reader::new(reader), packed),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn from_str(data: &str) -> FastaFile {
         let bytestring = &data.as_bytes()[..];
-        let reader = Reader::new(bytestring);
 
-        Self::parse_fasta(reader)
+        Self::from_reader(bytestring, false)
+    }
+
+    pub fn from_path(fp: &str, packed: bool) -> FastaFile {
+        Self::from_reader(io::open(fp), packed)
+    }
+}
+
+// Bytes the sequences take, packed or not
+pub fn heap_size(fasta_file: &[Fasta]) -> usize {
+    fasta_file
+        .iter()
+        .map(|f| match &f.packed {
+            Some(packed) => packed.heap_size(),
+            None => f.seq.len(),
+        })
+        .sum()
+}
+
+// The records of each PanSN genome, records without one are left out
+pub fn genomes(fasta_file: &[Fasta]) -> BTreeMap<&str, Vec<&Fasta>> {
+    let mut genomes: BTreeMap<&str, Vec<&Fasta>> = BTreeMap::new();
+    for f in fasta_file {
+        if let Some(pansn) = f.pansn() {
+            genomes.entry(pansn.genome).or_default().push(f);
+        }
     }
+    genomes
+}
+
+// PAF sequence names mapped to the FASTA record IDs they stand for
+pub type Aliases = HashMap<String, String>;
+
+// Two whitespace separated columns, the name in the PAF then the record ID.
+// Blank lines and lines starting with # are skipped.
+pub fn parse_aliases<R: BufRead>(reader: R) -> Result<Aliases, String> {
+    let mut aliases = Aliases::new();
 
-    pub fn from_path(fp: &str) -> FastaFile {
-        let reader = Reader::from_path(fp).unwrap();
-        Self::parse_fasta(reader)
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (alias, id) = match fields[..] {
+            [alias, id] => (alias, id),
+            _ => return Err(format!("expected 2 columns on line {}", line_num + 1)),
+        };
+        match aliases.insert(alias.to_string(), id.to_string()) {
+            Some(other) if other != id => {
+                return Err(format!(
+                    "{} is an alias of both {} and {} on line {}",
+                    alias,
+                    other,
+                    id,
+                    line_num + 1
+                ))
+            }
+            _ => {}
+        }
     }
+
+    Ok(aliases)
+}
+
+// The record ID a PAF name stands for
+pub fn resolve<'a>(aliases: &'a Aliases, name: &'a str) -> &'a str {
+    aliases.get(name).map(String::as_str).unwrap_or(name)
+}
+
+// Look up records by the names used in the PAF, their IDs and any aliases of them
+pub fn index_by_name<'a>(
+    fasta_file: &'a [Fasta],
+    aliases: &'a Aliases,
+) -> HashMap<&'a [u8], &'a Fasta> {
+    let mut index: HashMap<&[u8], &Fasta> = fasta_file.iter().map(|f| (f.name(), f)).collect();
+    let aliased: Vec<(&[u8], &Fasta)> = aliases
+        .iter()
+        .filter_map(|(alias, id)| index.get(id.as_bytes()).map(|f| (alias.as_bytes(), *f)))
+        .collect();
+    index.extend(aliased);
+    index
 }
 
 #[cfg(test)]
@@ -124,4 +369,91 @@ mod tests {
         assert_eq!(vec!["species_x"], foo(species_x));
         assert_eq!(vec!["species_x"], foo(with_newlines));
     }
+
+    #[test]
+    fn test_name() {
+        let fasta_file = Fasta::from_str(">chr1 assembled molecule\nACGT\n>chr2\nGG\n");
+        assert_eq!(fasta_file[0].name(), b"chr1");
+        assert_eq!(fasta_file[1].name(), b"chr2");
+
+        assert_eq!(fasta_file[0].description(), b"assembled molecule");
+        assert_eq!(fasta_file[1].description(), b"");
+
+        let no_aliases = Aliases::new();
+        let names = index_by_name(&fasta_file, &no_aliases);
+        assert_eq!(names[&b"chr2"[..]].seq, b"GG".to_vec());
+        assert!(!names.contains_key(&b"chr1 assembled molecule"[..]));
+    }
+
+    #[test]
+    fn test_genomes() {
+        let fasta_file =
+            Fasta::from_str(">HG002#1#chr1\nA\n>HG002#2#chr1\nC\n>HG002#1#chr2\nG\n>chrM\nT\n");
+        assert_eq!(fasta_file[2].pansn().unwrap().contig, "chr2");
+        assert!(fasta_file[3].pansn().is_none());
+
+        let genomes = genomes(&fasta_file);
+        let counts: Vec<(&str, usize)> = genomes.iter().map(|(g, f)| (*g, f.len())).collect();
+        assert_eq!(counts, vec![("HG002#1", 2), ("HG002#2", 1)]);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let fasta_file = Fasta::from_str(">NC_000001.11 chr1\nACGT\n>chr2\nGG\n");
+        let aliases =
+            parse_aliases("# renamed\nchr1\tNC_000001.11\n\nchrX NC_000023.11\n".as_bytes())
+                .unwrap();
+        assert_eq!(resolve(&aliases, "chr1"), "NC_000001.11");
+        assert_eq!(resolve(&aliases, "chr2"), "chr2");
+
+        let names = index_by_name(&fasta_file, &aliases);
+        assert_eq!(names[&b"chr1"[..]].seq, b"ACGT".to_vec());
+        assert_eq!(names[&b"NC_000001.11"[..]].seq, b"ACGT".to_vec());
+        // the record chrX stands for is not in the file
+        assert!(!names.contains_key(&b"chrX"[..]));
+
+        assert!(parse_aliases("chr1\n".as_bytes()).is_err());
+        assert!(parse_aliases("chr1 a\nchr1 b\n".as_bytes()).is_err());
+        assert!(parse_aliases("chr1 a\nchr1 a\n".as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_fastq() {
+        let fastq = "@read1 run=7\nACGTN\n+\nIIII#\n@read2\nGGA\n+read2\nIII\n";
+        let fasta_file = Fasta::from_str(fastq);
+        assert_eq!(fasta_file.len(), 2);
+        assert_eq!(fasta_file[0].name(), b"read1");
+        assert_eq!(fasta_file[0].seq, b"ACGTN".to_vec());
+        assert_eq!(fasta_file[1].seq, b"GGA".to_vec());
+
+        let packed = Fasta::from_reader(fastq.as_bytes(), true);
+        assert_eq!(packed[0].bases().to_bytes(), b"ACGTN".to_vec());
+        assert_eq!(packed[1].length, 3);
+    }
+
+    #[test]
+    fn test_packed_regions() {
+        let plain = Fasta::from_str(">chr1\nACGTNacgtTTGA\n").pop().unwrap();
+        let packed = Fasta::from_str(">chr1\nACGTNacgtTTGA\n")
+            .pop()
+            .unwrap()
+            .pack();
+        assert!(packed.seq.is_empty());
+        assert_eq!(packed.len(), 13);
+
+        for (start, end) in [(0, 13), (2, 9), (5, 5)] {
+            let (p, q) = (
+                plain.region(start..end).unwrap(),
+                packed.region(start..end).unwrap(),
+            );
+            assert_eq!(p.to_bytes(), q.to_bytes());
+            assert!(q.packed().is_some());
+            assert_eq!(
+                p.reverse_complement().to_bytes(),
+                q.reverse_complement().to_bytes()
+            );
+        }
+        assert!(packed.region(10..14).is_none());
+        assert_eq!(packed.bases().to_bytes(), b"ACGTNacgtTTGA".to_vec());
+    }
 }
@@ -2,51 +2,69 @@ use coitrees;
 use std::convert::TryFrom;
 use std::str::FromStr;
 
+use super::cigar;
 use super::paf;
 use super::types;
 
-// TODO: account for strand & stop
+// The match intervals of a PAF line in one of the sequences, half-open.
+// On the reverse strand the CIGAR walks the query from its end backwards.
 fn compute_match_intervals(
     seq_type: types::SequenceType,
-    _strand: types::Strand,
+    strand: types::Strand,
     start: u32,
-    _stop: u32,
+    stop: u32,
     cigar: &str,
     line_num: usize,
 ) -> Vec<types::Interval> {
     let mut intervals: Vec<types::Interval> = Vec::new();
     let mut buffer = String::new();
-    let mut cursor = start;
+
+    let reverse = seq_type == types::SequenceType::Query && strand == types::Strand::Reverse;
+    let mut cursor = if reverse { stop } else { start };
+
+    // move along the sequence by n bases
+    let advance = |cursor: u32, n: u32| -> u32 {
+        if reverse {
+            cursor - n
+        } else {
+            cursor + n
+        }
+    };
 
     cigar.chars().for_each(|c: char| {
         match c {
             'M' | '=' => {
                 // TODO: consider the ambiguity of M being match/mismatch
                 let m: u32 = u32::from_str(&buffer[..]).unwrap();
-                intervals.push(types::Interval(cursor, cursor + m, line_num));
-                cursor += m;
+                let next = advance(cursor, m);
+                if m > 0 {
+                    intervals.push(types::Interval(
+                        cursor.min(next),
+                        cursor.max(next),
+                        line_num,
+                    ));
+                }
+                cursor = next;
                 buffer.clear();
             }
             'X' => {
                 let x: u32 = u32::from_str(&buffer[..]).unwrap();
-                cursor += x;
+                cursor = advance(cursor, x);
                 buffer.clear();
             }
             'I' => {
+                // insertions only consume the query
                 let i: u32 = u32::from_str(&buffer[..]).unwrap();
-                if seq_type == types::SequenceType::Target {
-                    cursor -= i
-                } else {
-                    cursor += i
+                if seq_type == types::SequenceType::Query {
+                    cursor = advance(cursor, i);
                 };
                 buffer.clear();
             }
             'D' => {
+                // deletions only consume the target
                 let d: u32 = u32::from_str(&buffer[..]).unwrap();
                 if seq_type == types::SequenceType::Target {
-                    cursor += d
-                } else {
-                    cursor -= d
+                    cursor = advance(cursor, d);
                 };
                 buffer.clear();
             }
@@ -70,16 +88,120 @@ fn compute_match_intervals(
     intervals
 }
 
-pub fn index_paf_matches(p: &paf::PAF) -> (types::Index, types::Index) {
+// Number of matched bases in a PAF CIGAR, the same on the query and target.
+// 0 for a CIGAR that does not parse, --validate reports it.
+pub fn matched_bases(cigar: &str) -> u64 {
+    cigar::parse(cigar).map_or(0, |ops| cigar::residue_matches(&ops) as u64)
+}
+
+// A block of matches of a PAF line, a segment of a diagonal in the dot plot.
+// On the reverse strand the query runs backwards from query_start + length
+// as the target runs forwards, so the segment lies on an anti-diagonal.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Segment {
+    pub query_start: i32,
+    pub target_start: i32,
+    pub length: i32,
+    pub strand: types::Strand,
+    pub line_num: usize,
+}
+
+impl Segment {
+    // Constant along the segment, t - q forwards and t + q in reverse
+    fn diagonal(&self) -> i32 {
+        match self.strand {
+            types::Strand::Forward => self.target_start - self.query_start,
+            types::Strand::Reverse => self.target_start + self.query_start + self.length - 1,
+        }
+    }
+
+    /// The part of a WFA match run that lies on this segment as half-open
+    /// (query, target) intervals. None if the run is on another diagonal.
+    pub fn overlap(
+        &self,
+        query: (i32, i32),
+        target: (i32, i32),
+        strand: types::Strand,
+    ) -> Option<((i32, i32), (i32, i32))> {
+        let run = Segment {
+            query_start: query.0,
+            target_start: target.0,
+            length: target.1 - target.0,
+            strand,
+            line_num: self.line_num,
+        };
+
+        if run.strand != self.strand || run.diagonal() != self.diagonal() {
+            return None;
+        }
+
+        let start = target.0.max(self.target_start);
+        let end = target.1.min(self.target_start + self.length);
+        if start >= end {
+            return None;
+        }
+
+        let query_overlap = match strand {
+            types::Strand::Forward => {
+                let d = self.diagonal();
+                (start - d, end - d)
+            }
+            types::Strand::Reverse => {
+                let d = self.diagonal();
+                (d - end + 1, d - start + 1)
+            }
+        };
+
+        Some((query_overlap, (start, end)))
+    }
+}
+
+// Match segments of all PAF lines keyed on their target coordinates
+pub struct SegmentIndex {
+    segments: Vec<Segment>,
+    tree: types::Index,
+}
+
+impl SegmentIndex {
+    // Call visit with every segment whose target interval overlaps [start, end)
+    pub fn query<F>(&self, start: i32, end: i32, mut visit: F)
+    where
+        F: FnMut(&Segment),
+    {
+        if start >= end {
+            return;
+        }
+
+        // coitrees intervals are end-inclusive
+        self.tree
+            .query(start, end - 1, |node| visit(&self.segments[node.metadata]));
+    }
+}
+
+// The match blocks of the selected lines, lines that are left out are never
+// read so a CIGAR --validate skip rejects is fine. A CIGAR that does not parse
+// has no match blocks either, --validate warn has reported it.
+pub fn index_paf_segments(p: &paf::PAF, selected: &[bool]) -> SegmentIndex {
     let alignments: &Vec<paf::PafAlignment> = p.get_alignments();
-    let mut query_intervals: Vec<types::Interval> = Vec::new();
-    let mut target_intervals: Vec<types::Interval> = Vec::new();
+    let mut segments: Vec<Segment> = Vec::new();
 
     alignments
         .iter()
         .enumerate()
+        .filter(|(line_num, _)| selected[*line_num])
         .for_each(|(line_num, a): (usize, &paf::PafAlignment)| {
-            let mut t = compute_match_intervals(
+            if a.cigar.is_empty() {
+                panic!(
+                    "[index::index_paf] no cg:Z: CIGAR on PAF line {} for {}, run realign first",
+                    line_num + 1,
+                    a.query
+                );
+            }
+            if cigar::parse(&a.cigar).is_err() {
+                return;
+            }
+
+            let t = compute_match_intervals(
                 types::SequenceType::Target,
                 a.strand,
                 a.target_start,
@@ -87,7 +209,7 @@ pub fn index_paf_matches(p: &paf::PAF) -> (types::Index, types::Index) {
                 &a.cigar[..],
                 line_num,
             );
-            let mut q = compute_match_intervals(
+            let q = compute_match_intervals(
                 types::SequenceType::Query,
                 a.strand,
                 a.query_start,
@@ -96,35 +218,42 @@ pub fn index_paf_matches(p: &paf::PAF) -> (types::Index, types::Index) {
                 line_num,
             );
 
-            query_intervals.append(&mut q);
-            target_intervals.append(&mut t);
-        });
+            // both come from the same M ops in the same order
+            t.iter().zip(q.iter()).for_each(
+                |(types::Interval(t_start, t_stop, _), types::Interval(q_start, _, _))| {
+                    let to_i32 = |x: u32| {
+                        i32::try_from(x).expect("[index::index_paf] Could not convert u32 to i32")
+                    };
 
-    let gen_coitree =
-        |intervals: Vec<types::Interval>| -> coitrees::COITree<types::AlignmentMetadata, u32> {
-            // Generate coitrees::IntervalNodes
-            let interval_nodes: Vec<coitrees::IntervalNode<types::AlignmentMetadata, u32>> =
-                intervals
-                    .iter()
-                    .map(|types::Interval(start, stop, line_num): &types::Interval| {
-                        let start = i32::try_from(*start)
-                            .expect("[index::index_paf] Could not convert start u32 to i32");
-                        let end = i32::try_from(*stop)
-                            .expect("[index::index_paf] Could not convert end u32 to i32");
-
-                        coitrees::IntervalNode::<types::AlignmentMetadata, u32>::new(
-                            start, end, *line_num,
-                        )
-                    })
-                    .collect();
-
-            coitrees::COITree::new(interval_nodes)
-        };
+                    segments.push(Segment {
+                        query_start: to_i32(*q_start),
+                        target_start: to_i32(*t_start),
+                        length: to_i32(t_stop - t_start),
+                        strand: a.strand,
+                        line_num,
+                    });
+                },
+            );
+        });
 
-    let query_coitree = gen_coitree(query_intervals);
-    let target_coitree = gen_coitree(target_intervals);
+    // Generate coitrees::IntervalNodes pointing back into the segments
+    let interval_nodes: Vec<coitrees::IntervalNode<types::AlignmentMetadata, u32>> = segments
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            // coitrees intervals are end-inclusive
+            coitrees::IntervalNode::<types::AlignmentMetadata, u32>::new(
+                s.target_start,
+                s.target_start + s.length - 1,
+                i,
+            )
+        })
+        .collect();
 
-    (query_coitree, target_coitree)
+    SegmentIndex {
+        segments,
+        tree: coitrees::COITree::new(interval_nodes),
+    }
 }
 
 #[cfg(test)]
@@ -172,6 +301,68 @@ mod tests {
         // Reverse strand
     }
 
+    #[test]
+    fn test_indels_and_strand() {
+        // an insertion moves only the query and a deletion only the target
+        let cg = "3M2I4M1X2M3D5M";
+        let target = compute_match_intervals(
+            types::SequenceType::Target,
+            types::Strand::Forward,
+            100,
+            120,
+            cg,
+            7,
+        );
+        assert_eq!(
+            target,
+            vec![
+                types::Interval(100, 103, 7),
+                types::Interval(103, 107, 7),
+                types::Interval(108, 110, 7),
+                types::Interval(113, 118, 7),
+            ]
+        );
+
+        let query = compute_match_intervals(
+            types::SequenceType::Query,
+            types::Strand::Forward,
+            0,
+            17,
+            cg,
+            7,
+        );
+        assert_eq!(
+            query,
+            vec![
+                types::Interval(0, 3, 7),
+                types::Interval(5, 9, 7),
+                types::Interval(10, 12, 7),
+                types::Interval(12, 17, 7),
+            ]
+        );
+
+        // on the reverse strand the query is walked from its end
+        let query = compute_match_intervals(
+            types::SequenceType::Query,
+            types::Strand::Reverse,
+            0,
+            17,
+            cg,
+            7,
+        );
+        assert_eq!(
+            query,
+            vec![
+                types::Interval(14, 17, 7),
+                types::Interval(8, 12, 7),
+                types::Interval(5, 7, 7),
+                types::Interval(0, 5, 7),
+            ]
+        );
+
+        assert_eq!(matched_bases(cg), 14);
+    }
+
     #[test]
     fn test_index_paf() {
         static TEST_PAF_STRING: &str = "\
@@ -180,15 +371,73 @@ mod tests {
         qry\t329347\t41052\t324759\t+\ttgt\t283680\t0\t283680\t283613\t283736\t0\tNM:i:123\tms:i:566760\tAS:i:566760\tnn:i:0\ttp:A:S\tcm:i:53397\ts1:i:282348\tde:f:0.0003\trl:i:2765\tcg:Z:15M1I158M1I24M1I169M1I1147M1I24M1I851M1I13M1I3900M1D25M1I874M4I10847M3D4400M1I1494M1D4041M1I8577M14I1340M2D21138M2I7776M6D3563M2I83120M10D5541M2D27729M1I2M13I49698M1I5030M2I17541M1D22531M1I187M1D458M1D80M1I75M1I266M1I48M1I269M1I460M1D240M
 ";
         let alignments: paf::PAF = paf::PAF::from_str(TEST_PAF_STRING);
-        let (query_index, target_index): (
-            coitrees::COITree<types::AlignmentMetadata, u32>,
-            coitrees::COITree<types::AlignmentMetadata, u32>,
-        ) = index_paf_matches(&alignments);
+        let index: SegmentIndex = index_paf_segments(&alignments, &[true; 2]);
 
-        // should apply to all of them
-        assert_eq!(38, query_index.query_count(0, 330_243));
+        let count = |start: i32, end: i32| -> usize {
+            let mut count = 0;
+            index.query(start, end, |_| count += 1);
+            count
+        };
+
+        // one segment per match op, should apply to all of them
+        assert_eq!(38, count(0, 330_243));
         // the first match in the second alignment plus the first alignment which covers everything
-        assert_eq!(2, query_index.query_count(41_052, 41_067));
-        assert_eq!(query_index.len(), target_index.len());
+        assert_eq!(2, count(0, 15));
+
+        // only the first line is on the main diagonal
+        let mut lines: Vec<usize> = Vec::new();
+        index.query(0, 15, |s| {
+            if s.overlap((0, 15), (0, 15), types::Strand::Forward)
+                .is_some()
+            {
+                lines.push(s.line_num)
+            }
+        });
+        assert_eq!(lines, vec![0]);
+    }
+
+    #[test]
+    fn test_segment_overlap() {
+        let forward = Segment {
+            query_start: 100,
+            target_start: 10,
+            length: 20,
+            strand: types::Strand::Forward,
+            line_num: 0,
+        };
+
+        // same diagonal, clipped to the segment
+        assert_eq!(
+            forward.overlap((95, 105), (5, 15), types::Strand::Forward),
+            Some(((100, 105), (10, 15)))
+        );
+        // overlaps on the target but on another diagonal
+        assert_eq!(
+            forward.overlap((0, 10), (10, 20), types::Strand::Forward),
+            None
+        );
+        assert_eq!(
+            forward.overlap((100, 110), (10, 20), types::Strand::Reverse),
+            None
+        );
+
+        // query 100..120 reverse complemented against target 10..30
+        // pairs query 119 with target 10 and query 100 with target 29
+        let reverse = Segment {
+            strand: types::Strand::Reverse,
+            ..forward
+        };
+        assert_eq!(
+            reverse.overlap((110, 120), (10, 20), types::Strand::Reverse),
+            Some(((110, 120), (10, 20)))
+        );
+        assert_eq!(
+            reverse.overlap((90, 110), (20, 40), types::Strand::Reverse),
+            Some(((100, 110), (20, 30)))
+        );
+        assert_eq!(
+            reverse.overlap((100, 110), (10, 20), types::Strand::Reverse),
+            None
+        );
     }
 }
@@ -1,7 +1,18 @@
-use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use super::bgzf;
+use super::paf::{self, Tag, TagValue};
+use super::report;
+use super::support;
+use super::trim;
+use super::types;
 
 pub fn read_file(fp: &str) -> Vec<String> {
-    let paf_byte_vector: Vec<u8> = fs::read(fp).expect("Error reading file");
+    let mut paf_byte_vector: Vec<u8> = Vec::new();
+    open(fp)
+        .read_to_end(&mut paf_byte_vector)
+        .expect("Error reading file");
     let x: String = String::from_utf8_lossy(&paf_byte_vector)
         .parse()
         .expect("Error decoding file. Does it contain non ASCII characters?");
@@ -9,10 +20,232 @@ pub fn read_file(fp: &str) -> Vec<String> {
     x.lines().map(|x| x.to_string()).collect::<Vec<String>>()
 }
 
-pub fn copy_filtered(fp: &str, filtered: &Vec<usize>) {
-    let data = read_file(fp);
+// A plain, gzip or bgzip compressed file, - for stdin
+pub fn open(fp: &str) -> Box<dyn BufRead> {
+    let reader: Box<dyn BufRead> = if fp == "-" {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        let file = File::open(fp).unwrap_or_else(|e| panic!("Error reading file {}: {}", fp, e));
+        Box::new(BufReader::new(file))
+    };
+    bgzf::reader(reader).unwrap_or_else(|e| panic!("Error reading file {}: {}", fp, e))
+}
+
+// Stream the PAF once sending every line to the kept or the rejected output.
+// With --invert the outputs swap and with --annotate every line goes to the
+// kept output tagged wf:Z:kept or wf:Z:dropped. With --tags the support of
+// the line is appended as tags and with --trim kept lines are clipped to the
+// span the WFA path supports.
+pub fn write_filtered<R: BufRead, K: Write>(
+    reader: R,
+    support: &[support::LineSupport],
+    cli_args: &types::CliArgs,
+    kept: &mut K,
+    mut rejected: Option<&mut dyn Write>,
+) -> std::io::Result<()> {
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_support = support.get(line_num);
+        let is_kept = line_support.is_some_and(|s| s.is_kept(cli_args));
+
+        let line = match line_support.and_then(|s| s.target_span) {
+            Some(span) if cli_args.trim && is_kept => {
+                let a = paf::PafAlignment::from_str(&line);
+                let trimmed = trim::trim(&a, span).unwrap_or_else(|e| {
+                    panic!("[wfilter::io] could not trim line {}: {}", line_num + 1, e)
+                });
+                trim::rewrite(&line, &trimmed)
+            }
+            _ => line,
+        };
+
+        let mut tags: Vec<Tag> = Vec::new();
+        if cli_args.add_tags {
+            tags.extend(line_support.map(|s| s.tags()).unwrap_or_default());
+        }
+        if cli_args.annotate {
+            let decision = String::from(report::decision(is_kept));
+            tags.push(Tag::new("wf", TagValue::String(decision)));
+        }
+        let line = if tags.is_empty() {
+            line
+        } else {
+            paf::append_tags(&line, &tags)
+        };
+
+        if cli_args.annotate || is_kept != cli_args.invert {
+            writeln!(kept, "{}", line)?;
+        } else if let Some(r) = rejected.as_mut() {
+            writeln!(r, "{}", line)?;
+        }
+    }
+
+    kept.flush()?;
+    if let Some(r) = rejected.as_mut() {
+        r.flush()?;
+    }
+
+    Ok(())
+}
+
+pub fn copy_filtered(fp: &str, support: &[support::LineSupport], cli_args: &types::CliArgs) {
+    let stdout = std::io::stdout();
+    let mut kept = BufWriter::new(stdout.lock());
 
-    filtered.iter().for_each(|line| {
-        println!("{}", data.get(*line).unwrap());
+    let mut rejected = cli_args.rejected.as_ref().map(|rejected_fp| {
+        let f = File::create(rejected_fp)
+            .unwrap_or_else(|e| panic!("Error creating file {}: {}", rejected_fp, e));
+        BufWriter::new(f)
     });
+
+    write_filtered(
+        open(fp),
+        support,
+        cli_args,
+        &mut kept,
+        rejected.as_mut().map(|r| r as &mut dyn Write),
+    )
+    .expect("Error writing filtered PAF");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PAF: &str = "\
+    q\t10\t0\t10\t+\tt\t10\t0\t10\t10\t10\t60\tcg:Z:10M\n\
+    q\t10\t0\t5\t+\tt\t10\t0\t5\t5\t5\t60\tcg:Z:5M\n\
+    q\t10\t5\t10\t+\tt\t10\t5\t10\t5\t5\t60\tcg:Z:5M\n";
+
+    fn run(cli_args: &types::CliArgs) -> (Vec<String>, Vec<String>) {
+        let line = |supported: u64| support::LineSupport {
+            matched_bases: 10,
+            query_supported: supported,
+            target_supported: supported,
+            wfa_bases: 10,
+            score: Some(8),
+            target_span: None,
+            selected: true,
+        };
+        let support = vec![line(10), line(0), line(5)];
+
+        let mut kept: Vec<u8> = Vec::new();
+        let mut rejected: Vec<u8> = Vec::new();
+        write_filtered(
+            PAF.as_bytes(),
+            &support,
+            cli_args,
+            &mut kept,
+            Some(&mut rejected),
+        )
+        .unwrap();
+
+        // the last column of every line
+        let last = |out: Vec<u8>| -> Vec<String> {
+            String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|l| String::from(l.rsplit('\t').next().unwrap()))
+                .collect()
+        };
+
+        (last(kept), last(rejected))
+    }
+
+    #[test]
+    fn test_write_filtered() {
+        let cli_args = types::CliArgs::for_tests();
+        assert_eq!(
+            run(&cli_args),
+            (
+                vec![String::from("cg:Z:10M"), String::from("cg:Z:5M")],
+                vec![String::from("cg:Z:5M")]
+            )
+        );
+
+        let cli_args = types::CliArgs {
+            invert: true,
+            ..cli_args
+        };
+        let (kept, rejected) = run(&cli_args);
+        assert_eq!((kept.len(), rejected.len()), (1, 2));
+
+        let cli_args = types::CliArgs {
+            invert: false,
+            annotate: true,
+            ..cli_args
+        };
+        assert_eq!(
+            run(&cli_args),
+            (
+                vec![
+                    String::from("wf:Z:kept"),
+                    String::from("wf:Z:dropped"),
+                    String::from("wf:Z:kept")
+                ],
+                Vec::new()
+            )
+        );
+    }
+
+    #[test]
+    fn test_write_tags() {
+        let cli_args = types::CliArgs {
+            add_tags: true,
+            ..types::CliArgs::for_tests()
+        };
+
+        let mut kept: Vec<u8> = Vec::new();
+        let support = vec![support::LineSupport {
+            matched_bases: 10,
+            query_supported: 10,
+            target_supported: 10,
+            wfa_bases: 10,
+            score: Some(8),
+            target_span: None,
+            selected: true,
+        }];
+        write_filtered(
+            PAF.lines().next().unwrap().as_bytes(),
+            &support,
+            &cli_args,
+            &mut kept,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(kept).unwrap(),
+            "q\t10\t0\t10\t+\tt\t10\t0\t10\t10\t10\t60\tcg:Z:10M\tws:i:8\tsb:i:10\tsf:f:1\twc:f:1\n"
+        );
+    }
+
+    #[test]
+    fn test_write_trimmed() {
+        let cli_args = types::CliArgs {
+            trim: true,
+            ..types::CliArgs::for_tests()
+        };
+
+        let line = |target_span| support::LineSupport {
+            matched_bases: 10,
+            query_supported: 6,
+            target_supported: 6,
+            wfa_bases: 6,
+            score: Some(8),
+            target_span,
+            selected: true,
+        };
+        let support = vec![line(Some((2, 8))), line(None)];
+
+        let mut kept: Vec<u8> = Vec::new();
+        write_filtered(PAF.as_bytes(), &support, &cli_args, &mut kept, None).unwrap();
+
+        // lines without a span are not touched
+        assert_eq!(
+            String::from_utf8(kept).unwrap(),
+            "q\t10\t2\t8\t+\tt\t10\t2\t8\t6\t6\t60\tcg:Z:6M\n\
+             q\t10\t0\t5\t+\tt\t10\t0\t5\t5\t5\t60\tcg:Z:5M\n"
+        );
+    }
 }
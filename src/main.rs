@@ -1,67 +1,490 @@
-use coitrees;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use std::time::Instant;
 
 // local
 mod wflambda;
 
+mod aligner;
+mod besthits;
+mod bgzf;
+mod cigar;
 mod cli;
+mod expr;
+mod faidx;
 mod fasta;
 mod index;
 mod io;
+mod packed;
 mod paf;
+mod pansn;
+mod report;
+mod rescore;
+mod support;
+mod trim;
 mod types;
 mod utils;
+mod validate;
+mod verify;
 
+use paf::{Tag, TagValue};
+use wflambda::wfa::types::Alignment;
+
+// Align one pair of sequences passing the runs of matches to the lambda.
+// Returns the alignment and false if --verify is set and it failed verification.
+fn align_pair(
+    aligner: &dyn aligner::Aligner,
+    target_name: &[u8],
+    text: &fasta::Bases,
+    query_name: &[u8],
+    query: &fasta::Bases,
+    cli_args: &types::CliArgs,
+    backtrace_lambda: &mut aligner::BacktraceLambda,
+) -> (Alignment, bool) {
+    let verbosity = cli_args.verbosity_level;
+
+    if verbosity > 1 {
+        eprintln!(
+            "[wfilter::main::filter] Aligning name: \"{}\" length (bases): {} and name: \"{}\" length (bases): {}",
+            std::str::from_utf8(target_name).unwrap(),
+            utils::pretty_print_int(text.len() as isize),
+            std::str::from_utf8(query_name).unwrap(),
+            utils::pretty_print_int(query.len() as isize)
+        );
+    };
+
+    // keep the intervals passed to the backtrace lambda when verifying
+    let mut intervals: Vec<verify::MatchInterval> = Vec::new();
+    let mut lambda = |q: (i32, i32), t: (i32, i32)| {
+        if cli_args.verify {
+            intervals.push((q, t));
+        }
+        backtrace_lambda(q, t);
+    };
+
+    let aln = match (text.packed(), query.packed()) {
+        (Some(text), Some(query)) => aligner.align_packed(&text, &query, &mut lambda),
+        _ => aligner.align(&text.to_bytes(), &query.to_bytes(), &mut lambda),
+    };
+
+    if verbosity > 3 {
+        eprintln!("score {}", aln.score);
+        eprintln!("{}", aln.cigar);
+    }
+
+    if !cli_args.verify {
+        return (aln, true);
+    }
+
+    let penalties = aligner.penalties();
+    let result = verify::verify_alignment(
+        &text.to_bytes(),
+        &query.to_bytes(),
+        aln.score,
+        &aln.cigar,
+        &penalties,
+        &intervals,
+    );
+    if let Err(diagnostics) = result {
+        eprintln!(
+            "[wfilter::main::filter] {} verification failed for target: \"{}\" and query: \"{}\" score: {} CIGAR: {}",
+            aligner.name(),
+            std::str::from_utf8(target_name).unwrap(),
+            std::str::from_utf8(query_name).unwrap(),
+            aln.score,
+            aln.cigar
+        );
+        diagnostics.iter().for_each(|d| eprintln!("\t{}", d));
+        return (aln, false);
+    }
+
+    (aln, true)
+}
+
+// Position of the region of a PAF line in the original sequences.
+// Maps intervals in the aligned subsequences back to the originals.
+struct Region {
+    query_start: i32,
+    query_end: i32,
+    target_start: i32,
+    strand: types::Strand,
+}
+
+impl Region {
+    fn from_alignment(a: &paf::PafAlignment) -> Self {
+        Region {
+            query_start: a.query_start as i32,
+            query_end: a.query_end as i32,
+            target_start: a.target_start as i32,
+            strand: a.strand,
+        }
+    }
+
+    // On the reverse strand the query was reverse complemented
+    fn query(&self, (start, end): (i32, i32)) -> (i32, i32) {
+        match self.strand {
+            types::Strand::Forward => (self.query_start + start, self.query_start + end),
+            types::Strand::Reverse => (self.query_end - end, self.query_end - start),
+        }
+    }
+
+    fn target(&self, (start, end): (i32, i32)) -> (i32, i32) {
+        (self.target_start + start, self.target_start + end)
+    }
+}
+
+// Slice a region out of a sequence failing loudly if the PAF and FASTA disagree
+fn subsequence(f: &fasta::Fasta, start: u32, end: u32, line_num: usize) -> fasta::Bases<'_> {
+    let name = std::str::from_utf8(f.name()).unwrap();
+    f.region(start as usize..end as usize).unwrap_or_else(|| {
+        panic!(
+            "[wfilter::main::filter] PAF line {} region {}-{} is out of bounds for \"{}\" held in {}",
+            line_num + 1,
+            start,
+            end,
+            name,
+            f.pieces
+                .iter()
+                .map(|p| format!("{}-{}", p.start, p.end))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })
+}
+
+// Read a whole FASTA or, given the regions, only those through its index
+fn read_fasta(
+    fp: &str,
+    regions: Option<&faidx::Regions>,
+    cli_args: &types::CliArgs,
+) -> fasta::FastaFile {
+    let regions = match regions {
+        Some(r) => r,
+        None => return fasta::Fasta::from_path(fp, cli_args.packed),
+    };
+
+    let mut reader = faidx::open_fasta(fp, cli_args.verbosity_level)
+        .unwrap_or_else(|e| panic!("[wfilter::main] could not read {}: {}", fp, e));
+    let entries = faidx::open_index(fp, cli_args.verbosity_level)
+        .unwrap_or_else(|e| panic!("[wfilter::main] could not index {}: {}", fp, e));
+    faidx::load(&mut reader, &entries, regions, cli_args.packed)
+        .unwrap_or_else(|e| panic!("[wfilter::main] could not read {}: {}", fp, e))
+}
+
+// Read every file of the target or of the query as one collection, failing on
+// a record ID that is in more than one
+fn read_files(
+    fps: &[String],
+    regions: Option<&faidx::Regions>,
+    cli_args: &types::CliArgs,
+) -> fasta::FastaFile {
+    let mut sequences: fasta::FastaFile = Vec::new();
+    let mut seen: HashMap<Vec<u8>, &str> = HashMap::new();
+
+    for fp in fps {
+        for f in read_fasta(fp, regions, cli_args) {
+            if let Some(other) = seen.insert(f.name().to_vec(), fp) {
+                panic!(
+                    "[wfilter::main] record \"{}\" is in {} and again in {}",
+                    String::from_utf8_lossy(f.name()),
+                    other,
+                    fp
+                );
+            }
+            sequences.push(f);
+        }
+    }
+
+    sequences
+}
+
+// The sequence a PAF line names failing loudly if it is not in the FASTA
+fn lookup<'a>(
+    sequences: &HashMap<&[u8], &'a fasta::Fasta>,
+    name: &str,
+    kind: &str,
+    line_num: usize,
+) -> &'a fasta::Fasta {
+    sequences.get(name.as_bytes()).copied().unwrap_or_else(|| {
+        panic!(
+            "[wfilter::main] {} \"{}\" on PAF line {} is not in the {} FASTA",
+            kind,
+            name,
+            line_num + 1,
+            kind
+        )
+    })
+}
+
+// The PAF sequence names that are neither a record ID in their FASTA nor an
+// alias of one, as (target or query, name)
+fn missing_names<R: BufRead>(
+    reader: R,
+    target: &fasta::FastaFile,
+    query: &fasta::FastaFile,
+    cli_args: &types::CliArgs,
+) -> BTreeSet<(&'static str, String)> {
+    let targets = fasta::index_by_name(target, &cli_args.aliases);
+    let queries = fasta::index_by_name(query, &cli_args.aliases);
+    let mut missing: BTreeSet<(&str, String)> = BTreeSet::new();
+
+    for line in reader.lines() {
+        let line = line.unwrap_or_else(|e| panic!("[wfilter::main] could not read PAF: {}", e));
+        let a = paf::PafAlignment::from_str(&line);
+        if !targets.contains_key(a.target.as_bytes()) {
+            missing.insert(("target", a.target));
+        }
+        if !queries.contains_key(a.query.as_bytes()) {
+            missing.insert(("query", a.query));
+        }
+    }
+
+    missing
+}
+
+// The target and query regions of a PAF line, on the reverse strand the query
+// is reverse complemented so that both read along the target
+fn regions<'a>(
+    a: &paf::PafAlignment,
+    t: &'a fasta::Fasta,
+    q: &'a fasta::Fasta,
+    line_num: usize,
+) -> (fasta::Bases<'a>, fasta::Bases<'a>) {
+    let text = subsequence(t, a.target_start, a.target_end, line_num);
+    let query = subsequence(q, a.query_start, a.query_end, line_num);
+
+    let query = match a.strand {
+        types::Strand::Forward => query,
+        types::Strand::Reverse => query.reverse_complement(),
+    };
+
+    (text, query)
+}
+
+// The target and query record IDs of a pair and the strand of its lines
+type PairKey<'a> = (&'a [u8], &'a [u8], types::Strand);
+
+// A unit of work for the alignment threads
+enum Job<'a> {
+    Line(usize), // the region of a PAF line in per-alignment mode
+    // a whole target and query with a line between them on the strand, the
+    // query reverse complemented for the lines on the reverse strand
+    Pair(&'a fasta::Fasta, &'a fasta::Fasta, types::Strand),
+}
+
+// The support of every PAF line, an error if any alignment failed --verify
 fn filter(
+    paf: &paf::PAF,
     target: &fasta::FastaFile,
-    target_index: &types::Index,
     query: &fasta::FastaFile,
-    query_index: &types::Index,
     cli_args: &types::CliArgs,
-) -> HashSet<usize> {
+) -> Result<Vec<support::LineSupport>, String> {
     let verbosity = cli_args.verbosity_level;
 
-    let mut query_lines: HashSet<usize> = HashSet::new();
-    let mut target_lines: HashSet<usize> = HashSet::new();
+    let targets = fasta::index_by_name(target, &cli_args.aliases);
+    let queries = fasta::index_by_name(query, &cli_args.aliases);
 
-    let mut backtrace_lambda = |query: (i32, i32), target: (i32, i32)| {
-        let y = |i: &coitrees::IntervalNode<types::AlignmentMetadata, u32>| {
-            target_lines.insert(i.metadata);
-        };
-        let z = |i: &coitrees::IntervalNode<types::AlignmentMetadata, u32>| {
-            query_lines.insert(i.metadata);
-        };
+    // lines --expr, --validate skip or --best-hits by identity leave out are
+    // not aligned and get no support
+    let mut selected: Vec<bool> = paf
+        .get_alignments()
+        .iter()
+        .enumerate()
+        .map(|(line_num, a)| {
+            validate::validate_line(a, &targets, &queries, line_num, cli_args)
+                && expr::is_selected(a, cli_args)
+        })
+        .collect();
+    if cli_args.best_hits.is_some() && cli_args.best_by == types::BestBy::Identity {
+        let alignments = paf.get_alignments();
+        let scores: Vec<f64> = alignments
+            .iter()
+            .map(|a| besthits::identity_score(a, &cli_args.penalties))
+            .collect();
+        selected = besthits::best_hits(alignments, &scores, &selected, cli_args);
+    }
+
+    // only the lines left in are indexed, one --validate skips may not parse
+    let paf_index = &index::index_paf_segments(paf, &selected);
 
-        target_index.query(target.0, target.1, y);
-        query_index.query(query.0, query.1, z);
+    let new_counter = || {
+        let mut counter = support::SupportCounter::new(paf);
+        selected
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| !**s)
+            .for_each(|(line_num, _)| counter.deselect(line_num));
+        counter
     };
 
-    let now = Instant::now();
-    for t in target.iter() {
-        for q in query.iter() {
-            if verbosity > 1 {
-                eprintln!(
-                    "[wfilter::main::filter] Aligning name: \"{}\" length (bases): {} and name: \"{}\" length (bases): {}",
-                    std::str::from_utf8(&t.header[..]).unwrap(),
-                    utils::pretty_print_int(t.seq.len() as isize),
-                    std::str::from_utf8(&q.header[..]).unwrap(),
-                    utils::pretty_print_int(q.seq.len() as isize)
-                );
-            };
+    // the selected lines each pair of records can support on each strand, in
+    // line order
+    let mut pair_lines: BTreeMap<PairKey, Vec<usize>> = BTreeMap::new();
+    for (line_num, a) in paf.get_alignments().iter().enumerate() {
+        if !selected[line_num] {
+            continue;
+        }
+        let aliases = &cli_args.aliases;
+        pair_lines
+            .entry((
+                fasta::resolve(aliases, &a.target).as_bytes(),
+                fasta::resolve(aliases, &a.query).as_bytes(),
+                a.strand,
+            ))
+            .or_default()
+            .push(line_num);
+    }
+
+    let jobs: Vec<Job> = if cli_args.per_alignment {
+        (0..selected.len())
+            .filter(|line_num| selected[*line_num])
+            .map(Job::Line)
+            .collect()
+    } else {
+        // only the pairs a selected line is between, not every target and query
+        pair_lines
+            .keys()
+            .map(|(t, q, strand)| Job::Pair(targets[t], queries[q], *strand))
+            .collect()
+    };
+
+    // Align a job recording its matches and scores in the counter.
+    // Returns false if it failed verification.
+    let align_job = |job: &Job,
+                     aligner: &dyn aligner::Aligner,
+                     counter: &mut support::SupportCounter|
+     -> bool {
+        match *job {
+            Job::Line(line_num) => {
+                let a = &paf.get_alignments()[line_num];
+                let t = lookup(&targets, &a.target, "target", line_num);
+                let q = lookup(&queries, &a.query, "query", line_num);
 
-            let aln =
-                wflambda::wfa::wf_align(&t.seq[..], &q.seq[..], cli_args, &mut backtrace_lambda);
+                let (text, query_seq) = regions(a, t, q, line_num);
+                if text.is_empty() || query_seq.is_empty() {
+                    return true;
+                }
 
-            if verbosity > 3 {
-                eprintln!("score {}", aln.score);
-                eprintln!("{}", aln.cigar);
+                let region = Region::from_alignment(a);
+                let (aln, verified) = align_pair(
+                    aligner,
+                    t.name(),
+                    &text,
+                    q.name(),
+                    &query_seq,
+                    cli_args,
+                    &mut |q, t| {
+                        counter.add_match(
+                            paf_index,
+                            &[line_num],
+                            region.query(q),
+                            region.target(t),
+                            region.strand,
+                        )
+                    },
+                );
+                counter.set_score(line_num, aln.score);
+                verified
+            }
+            Job::Pair(t, q, strand) => {
+                let lines: &[usize] = pair_lines
+                    .get(&(t.name(), q.name(), strand))
+                    .map_or(&[], |l| &l[..]);
+                let query_seq = match strand {
+                    types::Strand::Forward => q.bases(),
+                    types::Strand::Reverse => q.bases().reverse_complement(),
+                };
+                // the whole query, to map runs back from its reverse complement
+                let region = Region {
+                    query_start: 0,
+                    query_end: query_seq.len() as i32,
+                    target_start: 0,
+                    strand,
+                };
+                let (aln, verified) = align_pair(
+                    aligner,
+                    &t.header,
+                    &t.bases(),
+                    &q.header,
+                    &query_seq,
+                    cli_args,
+                    &mut |q, t| {
+                        counter.add_match(
+                            paf_index,
+                            lines,
+                            region.query(q),
+                            region.target(t),
+                            strand,
+                        )
+                    },
+                );
+                lines
+                    .iter()
+                    .for_each(|line_num| counter.set_score(*line_num, aln.score));
+                verified
             }
         }
+    };
+
+    if verbosity > 0 {
+        eprintln!(
+            "[wfilter::main::filter] aligning with {} on {} thread(s)",
+            aligner::from_cli_args(cli_args).name(),
+            cli_args.threads
+        );
+    }
+
+    // Every thread takes the next job and records into its own counter.
+    // The counters are merged after so the result does not depend on which
+    // thread aligned what.
+    let now = Instant::now();
+    let next_job = AtomicUsize::new(0);
+    let num_workers = cli_args.threads.clamp(1, jobs.len().max(1));
+    // the threads left over when there are fewer jobs split the wide wavefronts
+    let wavefront_args = types::CliArgs {
+        threads: cli_args.threads / num_workers,
+        ..cli_args.clone()
+    };
+    let workers: Vec<(support::SupportCounter, usize)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..num_workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let aligner = aligner::from_cli_args(&wavefront_args);
+                    let mut counter = new_counter();
+                    let mut verification_failures: usize = 0;
+
+                    while let Some(job) = jobs.get(next_job.fetch_add(1, Ordering::Relaxed)) {
+                        if !align_job(job, aligner.as_ref(), &mut counter) {
+                            verification_failures += 1;
+                        }
+                    }
+
+                    (counter, verification_failures)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    });
+
+    let mut counter = new_counter();
+    let mut verification_failures: usize = 0;
+    for (worker_counter, failures) in workers {
+        counter.merge(worker_counter);
+        verification_failures += failures;
     }
 
-    let mut filtered = target_lines;
-    filtered.extend(&query_lines);
+    if verification_failures > 0 {
+        return Err(format!(
+            "{} alignment(s) failed verification",
+            verification_failures
+        ));
+    }
 
     if verbosity > 0 {
         eprintln!(
@@ -70,7 +493,97 @@ fn filter(
         )
     };
 
-    filtered
+    let mut lines = counter.into_lines();
+
+    // the best of the lines that are otherwise kept
+    if cli_args.best_hits.is_some() && cli_args.best_by == types::BestBy::Support {
+        let kept: Vec<bool> = lines.iter().map(|s| s.is_kept(cli_args)).collect();
+        let scores: Vec<f64> = lines.iter().map(|s| s.supported_bases() as f64).collect();
+        let best = besthits::best_hits(paf.get_alignments(), &scores, &kept, cli_args);
+        lines
+            .iter_mut()
+            .zip(best)
+            .for_each(|(s, best)| s.selected = s.selected && best);
+    }
+
+    Ok(lines)
+}
+
+// Replace the CIGAR of every PAF line with a WFA alignment of its region and
+// update NM, the residue matches and the block length to match. The WFA score
+// is added as ws:i. Lines with an empty region or that --validate skips are
+// written unchanged. Returns how many alignments failed --verify.
+fn realign<R: BufRead, W: Write>(
+    reader: R,
+    target: &fasta::FastaFile,
+    query: &fasta::FastaFile,
+    cli_args: &types::CliArgs,
+    out: &mut W,
+) -> std::io::Result<usize> {
+    let aligner = aligner::from_cli_args(cli_args);
+    if cli_args.verbosity_level > 0 {
+        eprintln!("[wfilter::main::realign] aligning with {}", aligner.name());
+    }
+
+    let targets = fasta::index_by_name(target, &cli_args.aliases);
+    let queries = fasta::index_by_name(query, &cli_args.aliases);
+    let mut verification_failures: usize = 0;
+
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let a = paf::PafAlignment::from_str(&line);
+        let t = lookup(&targets, &a.target, "target", line_num);
+        let q = lookup(&queries, &a.query, "query", line_num);
+        if !validate::validate_line(&a, &targets, &queries, line_num, cli_args) {
+            writeln!(out, "{}", line)?;
+            continue;
+        }
+
+        let (text, query_seq) = regions(&a, t, q, line_num);
+        if text.is_empty() || query_seq.is_empty() {
+            writeln!(out, "{}", line)?;
+            continue;
+        }
+
+        let (aln, verified) = align_pair(
+            aligner.as_ref(),
+            t.name(),
+            &text,
+            q.name(),
+            &query_seq,
+            cli_args,
+            &mut |_, _| {},
+        );
+        if !verified {
+            verification_failures += 1;
+        }
+
+        let ops = cigar::parse(&aln.cigar).unwrap_or_else(|e| {
+            panic!(
+                "[wfilter::main::realign] {} returned a bad CIGAR for PAF line {}: {}",
+                aligner.name(),
+                line_num + 1,
+                e
+            )
+        });
+        let line = paf::rewrite_line(
+            &line,
+            &[
+                (9, cigar::residue_matches(&ops).to_string()),
+                (10, cigar::block_len(&ops).to_string()),
+            ],
+            &[
+                Tag::new("NM", TagValue::Int(cigar::edit_distance(&ops) as i64)),
+                Tag::new("ws", TagValue::Int(aln.score as i64)),
+                Tag::new("cg", TagValue::String(aln.cigar)),
+            ],
+        );
+        writeln!(out, "{}", line)?;
+    }
+
+    out.flush()?;
+
+    Ok(verification_failures)
 }
 
 fn main() {
@@ -84,36 +597,77 @@ fn main() {
     let verbosity = args.verbosity_level;
 
     // ------------
-    //     PAF
+    //     Select
     // ------------
 
-    // Parse the PAF input file
-    let now = Instant::now();
-    if verbosity > 0 {
-        eprintln!("Parsing PAF: {}", paf_file_path)
-    }
-    let paf = paf::PAF::from_file(paf_file_path);
-    if verbosity > 0 {
-        eprintln!(
-            "[wfilter::main] done parsing PAF. Time taken {} seconds",
-            now.elapsed().as_millis() as f64 / 1000.0
+    if args.mode == types::Mode::Select {
+        let stdout = std::io::stdout();
+        expr::select_paf(
+            io::open(paf_file_path),
+            &args,
+            &mut BufWriter::new(stdout.lock()),
         )
+        .expect("[wfilter::main] Error writing selected PAF");
+        return;
     }
 
-    // index
-    let (query_index, target_index): (types::Index, types::Index) = index::index_paf_matches(&paf);
+    // ------------
+    //     Rescore
+    // ------------
+
+    // only needs the PAF
+    if args.mode == types::Mode::Rescore {
+        let stdout = std::io::stdout();
+        rescore::rescore_paf(
+            io::open(paf_file_path),
+            &args,
+            &mut BufWriter::new(stdout.lock()),
+        )
+        .expect("[wfilter::main] Error writing rescored PAF");
+        return;
+    }
 
     // ------------
     //     FASTA
     // ------------
 
+    // with --faidx only what the PAF lines cover is read, whole sequences when
+    // whole pairs of sequences are aligned
+    let (target_regions, query_regions) = if args.faidx {
+        let whole = args.mode == types::Mode::Filter && !args.per_alignment;
+        // the lines are checked against the lengths in the indexes first
+        let records = |fps: &[String]| -> fasta::FastaFile {
+            fps.iter()
+                .flat_map(|fp| {
+                    let entries = faidx::open_index(fp, verbosity).unwrap_or_else(|e| {
+                        panic!("[wfilter::main] could not index {}: {}", fp, e)
+                    });
+                    faidx::unloaded(&entries)
+                })
+                .collect()
+        };
+        let (t, q) = faidx::paf_regions(
+            io::open(paf_file_path),
+            whole,
+            &records(&args.target_fasta),
+            &records(&args.query_fasta),
+            &args,
+        );
+        (Some(t), Some(q))
+    } else {
+        (None, None)
+    };
+
     // Parse target fasta
     let now = Instant::now();
     if verbosity > 0 {
-        eprintln!("[wfilter::main] parsing target: {}", args.target_fasta);
+        eprintln!(
+            "[wfilter::main] parsing target: {}",
+            args.target_fasta.join(", ")
+        );
     }
 
-    let target: fasta::FastaFile = fasta::Fasta::from_path(&args.target_fasta[..]);
+    let target: fasta::FastaFile = read_files(&args.target_fasta, target_regions.as_ref(), &args);
     if verbosity > 0 {
         eprintln!(
             "[wfilter::main] done parsing target. Time taken {} seconds",
@@ -123,14 +677,25 @@ fn main() {
             "[wfilter::main] Number of sequences in the target {}",
             target.len()
         );
+        eprintln!(
+            "[wfilter::main] Bytes of sequence held for the target {}",
+            utils::pretty_print_int(fasta::heap_size(&target) as isize)
+        );
+        eprintln!(
+            "[wfilter::main] PanSN genomes in the target {}",
+            fasta::genomes(&target).len()
+        );
     }
 
     // Parse query fasta
     let now = Instant::now();
     if verbosity > 0 {
-        eprintln!("[wfilter::main] parsing query: {}", args.query_fasta);
+        eprintln!(
+            "[wfilter::main] parsing query: {}",
+            args.query_fasta.join(", ")
+        );
     }
-    let query: fasta::FastaFile = fasta::Fasta::from_path(&args.query_fasta[..]);
+    let query: fasta::FastaFile = read_files(&args.query_fasta, query_regions.as_ref(), &args);
     if verbosity > 0 {
         eprintln!(
             "[wfilter::main] done parsing query. Time taken {} seconds",
@@ -140,6 +705,84 @@ fn main() {
             "[wfilter::main] Number of sequences in the query {}",
             query.len()
         );
+        eprintln!(
+            "[wfilter::main] Bytes of sequence held for the query {}",
+            utils::pretty_print_int(fasta::heap_size(&query) as isize)
+        );
+        eprintln!(
+            "[wfilter::main] PanSN genomes in the query {}",
+            fasta::genomes(&query).len()
+        );
+    }
+
+    // fail on every PAF name the FASTA lacks at once rather than on the first
+    let missing = missing_names(io::open(paf_file_path), &target, &query, &args);
+    if !missing.is_empty() {
+        const SHOWN: usize = 10;
+        let names: Vec<String> = missing
+            .iter()
+            .take(SHOWN)
+            .map(|(kind, name)| format!("{} \"{}\"", kind, name))
+            .collect();
+        panic!(
+            "[wfilter::main] {} PAF sequence name(s) are not record IDs in the FASTA: {}{}. Rename them with --aliases",
+            missing.len(),
+            names.join(", "),
+            if missing.len() > SHOWN { ", ..." } else { "" }
+        );
+    }
+
+    // ------------
+    //     Realign
+    // ------------
+
+    if args.mode == types::Mode::Realign {
+        let now = Instant::now();
+        if verbosity > 0 {
+            eprintln!("[wfilter::main] Realigning: {}", paf_file_path);
+        }
+
+        let stdout = std::io::stdout();
+        let verification_failures = realign(
+            io::open(paf_file_path),
+            &target,
+            &query,
+            &args,
+            &mut BufWriter::new(stdout.lock()),
+        )
+        .expect("[wfilter::main] Error writing realigned PAF");
+        if verification_failures > 0 {
+            eprintln!(
+                "[wfilter::main::realign] {} alignment(s) failed verification",
+                verification_failures
+            );
+            std::process::exit(1);
+        }
+
+        if verbosity > 0 {
+            eprintln!(
+                "[wfilter::main] done realigning. Time taken {} seconds",
+                now.elapsed().as_millis() as f64 / 1000.0
+            );
+        }
+        return;
+    }
+
+    // ------------
+    //     PAF
+    // ------------
+
+    // Parse the PAF input file
+    let now = Instant::now();
+    if verbosity > 0 {
+        eprintln!("Parsing PAF: {}", paf_file_path)
+    }
+    let paf = paf::PAF::from_file(paf_file_path);
+    if verbosity > 0 {
+        eprintln!(
+            "[wfilter::main] done parsing PAF. Time taken {} seconds",
+            now.elapsed().as_millis() as f64 / 1000.0
+        )
     }
 
     // ------------
@@ -151,11 +794,19 @@ fn main() {
         eprintln!("[wfilter::main] Filtering");
     }
 
-    let lines: HashSet<usize> = filter(&target, &target_index, &query, &query_index, &args);
-    let mut lines = lines.into_iter().collect::<Vec<usize>>();
-    lines.sort();
+    let support: Vec<support::LineSupport> =
+        filter(&paf, &target, &query, &args).unwrap_or_else(|e| {
+            eprintln!("[wfilter::main::filter] {}", e);
+            std::process::exit(1)
+        });
+    io::copy_filtered(paf_file_path, &support, &args);
 
-    io::copy_filtered(paf_file_path, &lines);
+    if let Some(report_path) = &args.report {
+        report::write_report_file(report_path, &paf, &support, &args);
+    }
+    if let Some(report_path) = &args.genome_report {
+        report::write_genome_report_file(report_path, &paf, &support, &args);
+    }
     if verbosity > 0 {
         eprintln!(
             "[wfilter::main] done filtering. Time taken {} seconds",
@@ -167,18 +818,10 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::fasta::Fasta;
-    use super::index;
     use super::paf;
-    use super::types::{CliArgs, Index};
+    use super::types::CliArgs;
     use super::*;
 
-    use chrono::Local;
-
-    static PAF_STRING: &str = "\
-    qry\t330243\t0\t330243\t+\ttgt\t330243\t0\t330243\t330243\t330243\t60\tNM:i:0\tms:i:660486\
-    \tAS:i:660486\tnn:i:0\ttp:A:P\tcm:i:62290\ts1:i:329202\ts2:i:262341\tde:f:0\trl:i:2730\
-    \tcg:Z:330243M";
-
     static TEXT: &str = ">species_y\n\
                          TCTATACTGCGCGTTTATCTAGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT\
                          TCTATACTGCGCGTTTGGAGAAATAACTATCAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT";
@@ -188,30 +831,288 @@ mod tests {
 
     #[test]
     fn test_filter() {
-        let alignments: paf::PAF = paf::PAF::from_str(PAF_STRING);
-        let (query_index, target_index): (Index, Index) = index::index_paf_matches(&alignments);
-
-        let penalties = types::Penalties {
-            mismatch: 4,
-            matches: 0,
-            gap_open: 6,
-            gap_extend: 2,
+        let text = Fasta::from_str(TEXT);
+        // and a reverse complemented copy of the target
+        let query = Fasta::from_str(&format!(
+            "{}\n>rev\n{}\n",
+            QUERY,
+            std::str::from_utf8(&utils::reverse_complement(&text[0].seq)).unwrap()
+        ));
+
+        // the whole pair as the native aligner aligns it, a line off its path
+        // and the reverse complemented copy
+        let paf_string = "\
+        species_x\t128\t0\t128\t+\tspecies_y\t138\t0\t138\t117\t142\t60\t\
+        cg:Z:3M1X4M1I7M6D9M1X9M1X4M1I7M1D9M1X9M1X4M1I7M1D9M5D10M1X4M1I7M1D9M1X6M\n\
+        species_x\t128\t0\t40\t+\tspecies_y\t138\t90\t130\t40\t40\t60\tcg:Z:40M\n\
+        rev\t138\t0\t138\t-\tspecies_y\t138\t0\t138\t138\t138\t60\tcg:Z:138M";
+        let alignments: paf::PAF = paf::PAF::from_str(paf_string);
+
+        // the gap-affine and the edit distance scores of the pair
+        for (edit_distance, score) in [(false, 118), (true, 25)] {
+            for aligner in [types::AlignerBackend::Native, types::AlignerBackend::Libwfa] {
+                let args = CliArgs {
+                    aligner,
+                    edit_distance,
+                    verify: true,
+                    min_support_fraction: 0.5,
+                    ..CliArgs::for_tests()
+                };
+
+                let support = filter(&alignments, &text, &query, &args).unwrap();
+                assert!(support[..2].iter().all(|s| s.score == Some(score)));
+                assert_eq!(support[2].score, Some(0));
+                assert_eq!(
+                    support
+                        .iter()
+                        .map(|s| s.is_kept(&args))
+                        .collect::<Vec<bool>>(),
+                    vec![true, false, true],
+                    "{:?} edit distance {}",
+                    aligner,
+                    edit_distance
+                );
+                assert_eq!(support[1].supported_bases(), 0);
+                assert_eq!(support[2].supported_bases(), 138);
+            }
+        }
+    }
+
+    #[test]
+    fn test_region() {
+        let line = "qry\t200\t100\t150\t-\ttgt\t300\t10\t60\t50\t50\t60\tcg:Z:50M";
+        let region = Region::from_alignment(&paf::PafAlignment::from_str(line));
+        assert_eq!(region.query((0, 10)), (140, 150));
+        assert_eq!(region.query((40, 50)), (100, 110));
+        assert_eq!(region.target((0, 10)), (10, 20));
+
+        let line = "qry\t200\t100\t150\t+\ttgt\t300\t10\t60\t50\t50\t60\tcg:Z:50M";
+        let region = Region::from_alignment(&paf::PafAlignment::from_str(line));
+        assert_eq!(region.query((0, 10)), (100, 110));
+    }
+
+    #[test]
+    fn test_filter_per_alignment() {
+        let text = Fasta::from_str(TEXT);
+        let target_seq = &text[0].seq;
+
+        // a forward and a reverse complemented copy of two regions of the target
+        let query_fasta = format!(
+            ">fwd description\n{}\n>rev\n{}\n",
+            std::str::from_utf8(&target_seq[10..60]).unwrap(),
+            std::str::from_utf8(&utils::reverse_complement(&target_seq[70..120])).unwrap()
+        );
+        let query = Fasta::from_str(&query_fasta);
+
+        let paf_string = "\
+        fwd\t50\t0\t50\t+\tspecies_y\t138\t10\t60\t50\t50\t60\tcg:Z:50M\n\
+        rev\t50\t0\t50\t-\tspecies_y\t138\t70\t120\t50\t50\t60\tcg:Z:50M";
+        let alignments: paf::PAF = paf::PAF::from_str(paf_string);
+
+        let args = CliArgs {
+            per_alignment: true,
+            verify: true,
+            ..CliArgs::for_tests()
         };
 
+        let support = filter(&alignments, &text, &query, &args).unwrap();
+        assert!(support.iter().all(|s| s.matched_bases == 50));
+        assert!(support.iter().all(|s| s.fraction() == 1.0));
+        assert!(support.iter().all(|s| s.score == Some(0)));
+        // only the forward line is aligned
         let args = CliArgs {
-            verbosity_level: 0,
-            input_paf: String::new(),
-            target_fasta: String::new(),
-            query_fasta: String::new(),
-            penalties: penalties,
-            adapt: false,
-            generate_alignment_tsv: false,
-            start_time: Local::now(),
+            expr: Some(expr::Expr::parse("strand == \"+\"").unwrap()),
+            ..args
         };
+        let support = filter(&alignments, &text, &query, &args).unwrap();
+        assert_eq!(
+            support.iter().map(|s| s.selected).collect::<Vec<bool>>(),
+            vec![true, false]
+        );
+        assert_eq!(
+            (support[0].supported_bases(), support[1].supported_bases()),
+            (50, 0)
+        );
+        assert_eq!(support[1].score, None);
+    }
+
+    #[test]
+    fn test_filter_other_query() {
+        let text = Fasta::from_str(TEXT);
+        let good = &text[0].seq[0..50];
+        // mismatches the target at every base on the diagonal of its line
+        let bad: Vec<u8> = good
+            .iter()
+            .map(|b| match b {
+                b'A' => b'T',
+                b'T' => b'A',
+                b'C' => b'G',
+                _ => b'C',
+            })
+            .collect();
+        let query = Fasta::from_str(&format!(
+            ">good\n{}\n>bad\n{}\n",
+            std::str::from_utf8(good).unwrap(),
+            std::str::from_utf8(&bad).unwrap()
+        ));
 
+        // both claim the same target coordinates
+        let paf_string = "\
+        good\t50\t0\t50\t+\tspecies_y\t138\t0\t50\t50\t50\t60\tcg:Z:50M\n\
+        bad\t50\t0\t50\t+\tspecies_y\t138\t0\t50\t50\t50\t60\tcg:Z:50M";
+        let alignments: paf::PAF = paf::PAF::from_str(paf_string);
+
+        for per_alignment in [false, true] {
+            let args = CliArgs {
+                per_alignment,
+                ..CliArgs::for_tests()
+            };
+            let support = filter(&alignments, &text, &query, &args).unwrap();
+            assert_eq!(support[0].supported_bases(), 50);
+            assert_eq!(support[1].supported_bases(), 0);
+            assert!(!support[1].is_kept(&args));
+        }
+    }
+
+    fn pack(fasta_file: fasta::FastaFile) -> fasta::FastaFile {
+        fasta_file.into_iter().map(Fasta::pack).collect()
+    }
+
+    #[test]
+    fn test_realign() {
+        let text = Fasta::from_str(TEXT);
+        let target_seq = &text[0].seq;
+
+        // one mismatch in the forward copy
+        let mut fwd = target_seq[10..60].to_vec();
+        fwd[20] = if fwd[20] == b'A' { b'C' } else { b'A' };
+        let query_fasta = format!(
+            ">fwd\n{}\n>rev\n{}\n",
+            std::str::from_utf8(&fwd).unwrap(),
+            std::str::from_utf8(&utils::reverse_complement(&target_seq[70..120])).unwrap()
+        );
+        let query = Fasta::from_str(&query_fasta);
+
+        // no CIGARs and made up residue matches and block lengths
+        let paf_string = "\
+        fwd\t50\t0\t50\t+\tspecies_y\t138\t10\t60\t0\t0\t60\tNM:i:9\n\
+        rev\t50\t0\t50\t-\tspecies_y\t138\t70\t120\t0\t0\t60\n";
+
+        let args = CliArgs {
+            verify: true,
+            ..CliArgs::for_tests()
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        let verification_failures =
+            realign(paf_string.as_bytes(), &text, &query, &args, &mut out).unwrap();
+        assert_eq!(verification_failures, 0);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "fwd\t50\t0\t50\t+\tspecies_y\t138\t10\t60\t49\t50\t60\tNM:i:1\tws:i:4\tcg:Z:20M1X29M"
+        );
+        assert_eq!(
+            lines[1],
+            "rev\t50\t0\t50\t-\tspecies_y\t138\t70\t120\t50\t50\t60\tNM:i:0\tws:i:0\tcg:Z:50M"
+        );
+
+        // the same from 2-bit packed sequences
+        let (text, query) = (pack(text), pack(query));
+        let mut packed_out: Vec<u8> = Vec::new();
+        realign(paf_string.as_bytes(), &text, &query, &args, &mut packed_out).unwrap();
+        assert_eq!(String::from_utf8(packed_out).unwrap(), out);
+    }
+
+    #[test]
+    fn test_missing_names() {
         let text = Fasta::from_str(TEXT);
         let query = Fasta::from_str(QUERY);
+        let paf_string = "\
+        species_x\t10\t0\t10\t+\tspecies_y\t138\t0\t10\t10\t10\t60\n\
+        x\t10\t0\t10\t+\ty\t138\t0\t10\t10\t10\t60\n\
+        z\t10\t0\t10\t+\ty\t138\t0\t10\t10\t10\t60\n";
+
+        let args = CliArgs::for_tests();
+        let missing = missing_names(paf_string.as_bytes(), &text, &query, &args);
+        let expected: Vec<(&str, String)> = vec![
+            ("query", String::from("x")),
+            ("query", String::from("z")),
+            ("target", String::from("y")),
+        ];
+        assert_eq!(missing.into_iter().collect::<Vec<_>>(), expected);
 
-        filter(&text, &target_index, &query, &query_index, &args);
+        let args = CliArgs {
+            aliases: fasta::parse_aliases("x species_x\ny species_y\n".as_bytes()).unwrap(),
+            ..args
+        };
+        let missing = missing_names(paf_string.as_bytes(), &text, &query, &args);
+        assert_eq!(
+            missing.into_iter().collect::<Vec<_>>(),
+            expected[1..2].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_filter_threads() {
+        let text = Fasta::from_str(TEXT);
+        let target_seq = &text[0].seq;
+
+        // overlapping regions of the target as separate queries
+        let regions: Vec<(usize, usize)> = vec![(0, 40), (20, 70), (50, 120), (90, 138)];
+        let query_fasta: String = regions
+            .iter()
+            .enumerate()
+            .map(|(i, (s, e))| {
+                format!(
+                    ">q{}\n{}\n",
+                    i,
+                    std::str::from_utf8(&target_seq[*s..*e]).unwrap()
+                )
+            })
+            .collect();
+        let query = Fasta::from_str(&query_fasta);
+
+        let paf_string: String = regions
+            .iter()
+            .enumerate()
+            .map(|(i, (s, e))| {
+                format!(
+                    "q{}\t{}\t0\t{}\t+\tspecies_y\t138\t{}\t{}\t{}\t{}\t60\tcg:Z:{}M\n",
+                    i,
+                    e - s,
+                    e - s,
+                    s,
+                    e,
+                    e - s,
+                    e - s,
+                    e - s
+                )
+            })
+            .collect();
+        let alignments: paf::PAF = paf::PAF::from_str(&paf_string);
+
+        for per_alignment in [false, true] {
+            let args = CliArgs {
+                per_alignment,
+                ..CliArgs::for_tests()
+            };
+            let serial = filter(&alignments, &text, &query, &args).unwrap();
+
+            let args = CliArgs { threads: 3, ..args };
+            let parallel = filter(&alignments, &text, &query, &args).unwrap();
+
+            assert_eq!(serial, parallel);
+            assert!(serial.iter().all(|s| s.score.is_some()));
+
+            let (text, query) = (
+                pack(Fasta::from_str(TEXT)),
+                pack(Fasta::from_str(&query_fasta)),
+            );
+            let packed = filter(&alignments, &text, &query, &args).unwrap();
+            assert_eq!(serial, packed);
+        }
     }
 }
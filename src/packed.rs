@@ -0,0 +1,288 @@
+/*
+2-bit packed sequences
+----------------------
+
+A, C, G and T are stored in 2 bits, 32 bases to a u64 with the first base in
+the lowest bits. Everything else is kept in a side mask of runs so that the
+bytes unpack exactly as they were read:
+
+ - soft-masked acgt keep their packed code and a lowercase run
+ - N, IUPAC codes and any other byte are stored verbatim and packed as A
+
+Outside the runs two sequences are compared 32 bases at a time, the first
+mismatch being the lowest set bit pair of the XOR of two words. Within a run
+they are compared a byte at a time, so a region full of N or of soft-masked
+bases is correct but gets no speed-up.
+*/
+use std::ops::Range;
+
+use super::wflambda::wfa::Sequence;
+
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+const WORD_BASES: usize = 32;
+
+fn code(b: u8) -> Option<u64> {
+    match b {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Mask {
+    Lowercase,
+    Verbatim(Vec<u8>),
+}
+
+// Bases [start, end) that are not upper case ACGT
+#[derive(Debug, Clone, PartialEq)]
+struct Run {
+    start: usize,
+    end: usize,
+    mask: Mask,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PackedSeq {
+    len: usize,
+    words: Vec<u64>,
+    runs: Vec<Run>,
+}
+
+impl PackedSeq {
+    pub fn from_bytes(seq: &[u8]) -> Self {
+        let mut words = vec![0u64; seq.len().div_ceil(WORD_BASES)];
+        let mut runs: Vec<Run> = Vec::new();
+
+        for (i, b) in seq.iter().enumerate() {
+            let mask = match code(*b) {
+                Some(c) => {
+                    words[i / WORD_BASES] |= c << (2 * (i % WORD_BASES));
+                    if b.is_ascii_uppercase() {
+                        continue;
+                    }
+                    Mask::Lowercase
+                }
+                None => Mask::Verbatim(vec![*b]),
+            };
+
+            // grow the last run if it is of the same kind and ends here
+            match (runs.last_mut(), mask) {
+                (Some(run), Mask::Lowercase) if run.end == i && run.mask == Mask::Lowercase => {
+                    run.end += 1
+                }
+                (
+                    Some(Run {
+                        end,
+                        mask: Mask::Verbatim(bytes),
+                        ..
+                    }),
+                    Mask::Verbatim(_),
+                ) if *end == i => {
+                    *end += 1;
+                    bytes.push(*b);
+                }
+                (_, mask) => runs.push(Run {
+                    start: i,
+                    end: i + 1,
+                    mask,
+                }),
+            }
+        }
+
+        Self {
+            len: seq.len(),
+            words,
+            runs,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Bytes held, the packed words and the side mask
+    pub fn heap_size(&self) -> usize {
+        let verbatim: usize = self
+            .runs
+            .iter()
+            .map(|r| match &r.mask {
+                Mask::Verbatim(bytes) => bytes.len(),
+                Mask::Lowercase => 0,
+            })
+            .sum();
+        self.words.len() * 8 + self.runs.len() * std::mem::size_of::<Run>() + verbatim
+    }
+
+    // The run i is in or the first one after it
+    fn run_from(&self, i: usize) -> Option<&Run> {
+        self.runs.get(self.runs.partition_point(|r| r.end <= i))
+    }
+
+    fn code(&self, i: usize) -> u64 {
+        (self.words[i / WORD_BASES] >> (2 * (i % WORD_BASES))) & 3
+    }
+
+    pub fn base(&self, i: usize) -> u8 {
+        let b = BASES[self.code(i) as usize];
+        match self.run_from(i) {
+            Some(run) if run.start <= i => match &run.mask {
+                Mask::Lowercase => b.to_ascii_lowercase(),
+                Mask::Verbatim(bytes) => bytes[i - run.start],
+            },
+            _ => b,
+        }
+    }
+
+    // The 32 codes from i in one word, zeros past the end
+    fn word(&self, i: usize) -> u64 {
+        let (w, shift) = (i / WORD_BASES, 2 * (i % WORD_BASES));
+        let lo = self.words[w] >> shift;
+        match self.words.get(w + 1) {
+            Some(hi) if shift > 0 => lo | (hi << (64 - shift)),
+            _ => lo,
+        }
+    }
+
+    // Bases from i up to the next run, 0 if i is in one
+    fn unmasked_from(&self, i: usize) -> usize {
+        match self.run_from(i) {
+            Some(run) => run.start.saturating_sub(i),
+            None => self.len - i,
+        }
+    }
+
+    pub fn unpack(&self, range: Range<usize>) -> Vec<u8> {
+        range.map(|i| self.base(i)).collect()
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> PackedSlice<'_> {
+        assert!(range.start <= range.end && range.end <= self.len);
+        PackedSlice {
+            seq: self,
+            start: range.start,
+            len: range.end - range.start,
+        }
+    }
+}
+
+// A region of a packed sequence
+#[derive(Clone, Copy)]
+pub struct PackedSlice<'a> {
+    seq: &'a PackedSeq,
+    start: usize,
+    len: usize,
+}
+
+impl<'a> PackedSlice<'a> {
+    pub fn to_vec(self) -> Vec<u8> {
+        self.seq.unpack(self.start..self.start + self.len)
+    }
+}
+
+impl<'a> Sequence for PackedSlice<'a> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn base(&self, i: usize) -> u8 {
+        self.seq.base(self.start + i)
+    }
+
+    fn match_length(&self, i: usize, other: &Self, j: usize) -> usize {
+        let max = (self.len - i).min(other.len - j);
+        let (a, b) = (self.start + i, other.start + j);
+
+        let mut n = 0;
+        while n < max {
+            let unmasked = self
+                .seq
+                .unmasked_from(a + n)
+                .min(other.seq.unmasked_from(b + n))
+                .min(max - n);
+
+            if unmasked == 0 {
+                if self.seq.base(a + n) != other.seq.base(b + n) {
+                    return n;
+                }
+                n += 1;
+                continue;
+            }
+
+            let end = n + unmasked;
+            while n < end {
+                let diff = self.seq.word(a + n) ^ other.seq.word(b + n);
+                let bases = (end - n).min(WORD_BASES);
+                let diff = if bases < WORD_BASES {
+                    diff & ((1 << (2 * bases)) - 1)
+                } else {
+                    diff
+                };
+                if diff != 0 {
+                    return n + (diff.trailing_zeros() / 2) as usize;
+                }
+                n += bases;
+            }
+        }
+
+        max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SEQ: &[u8] = b"ACGTTGCAacgtNNNNRYACGTACGTACGTACGTACGTACGTACGTACGTACGTacgTTTGCAN";
+
+    #[test]
+    fn test_round_trip() {
+        let packed = PackedSeq::from_bytes(SEQ);
+        assert_eq!(packed.len(), SEQ.len());
+        assert_eq!(packed.unpack(0..SEQ.len()), SEQ.to_vec());
+        assert_eq!(packed.slice(10..20).to_vec(), SEQ[10..20].to_vec());
+        assert_eq!(PackedSeq::from_bytes(b"").unpack(0..0), Vec::<u8>::new());
+
+        // lowercase acgt, NNNNRY, acg and the trailing N
+        assert_eq!(packed.runs.len(), 4);
+        assert_eq!(packed.runs[1].mask, Mask::Verbatim(b"NNNNRY".to_vec()));
+    }
+
+    #[test]
+    fn test_match_length() {
+        let packed = PackedSeq::from_bytes(SEQ);
+        let naive = |i: usize, j: usize| {
+            SEQ[i..]
+                .iter()
+                .zip(&SEQ[j..])
+                .take_while(|(x, y)| x == y)
+                .count()
+        };
+
+        let all = packed.slice(0..SEQ.len());
+        for i in 0..SEQ.len() {
+            for j in 0..SEQ.len() {
+                assert_eq!(all.match_length(i, &all, j), naive(i, j), "i={} j={}", i, j);
+            }
+        }
+
+        // a mismatch in and after the first word of a long run of ACGT
+        let seq: Vec<u8> = b"ACGT".iter().cycle().take(100).cloned().collect();
+        let a = PackedSeq::from_bytes(&seq);
+        for pos in [3, 40, 99] {
+            let mut other = seq.clone();
+            other[pos] = if other[pos] == b'A' { b'C' } else { b'A' };
+            let b = PackedSeq::from_bytes(&other);
+            assert_eq!(a.slice(0..100).match_length(0, &b.slice(0..100), 0), pos);
+        }
+    }
+
+    #[test]
+    fn test_heap_size() {
+        let seq: Vec<u8> = b"ACGT".iter().cycle().take(4000).cloned().collect();
+        assert_eq!(PackedSeq::from_bytes(&seq).heap_size(), 1000);
+    }
+}
@@ -27,32 +27,176 @@ insertions and deletions in the alignment. If alignment is not available,
 column 10 and 11 are still required but may be highly inaccurate.
 
 A PAF file may optionally contain SAM-like typed key-value pairs at the end of
-each line.
+each line. These are `TAG:TYPE:VALUE` where TYPE is one of
+  A  printable character
+  i  signed integer
+  f  float
+  Z  printable string
+  H  byte array in hex
+  B  integer or numeric array
+
+A tag that does not parse, or has a type not listed above, is kept as written
+and written back unchanged. Only a cg that is not a Z string fails the line.
 */
 
 use std::fmt;
 use std::str;
 use std::str::FromStr;
 
+use super::cigar;
 use super::io;
+use super::pansn::PanSn;
 use super::types;
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum TagValue {
+    Char(char),     // A
+    Int(i64),       // i
+    Float(f64),     // f
+    String(String), // Z
+    Hex(String),    // H
+    Array(String),  // B kept as written e.g. c,1,2
+    Opaque(String), // a whole field that does not parse, kept as written
+}
+
+// A SAM-like typed key-value pair e.g. NM:i:0
+#[derive(PartialEq, Debug, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub value: TagValue,
+}
+
+impl Tag {
+    pub fn new(name: &str, value: TagValue) -> Self {
+        Tag {
+            name: String::from(name),
+            value,
+        }
+    }
+
+    // A field that is not a tag, named by the text before its first colon
+    pub fn opaque(field: &str) -> Self {
+        let name = field.split(':').next().unwrap_or(field);
+        Tag::new(name, TagValue::Opaque(String::from(field)))
+    }
+
+    pub fn from_str(field: &str) -> Result<Self, String> {
+        let mut parts = field.splitn(3, ':');
+        let (name, tag_type, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(n), Some(t), Some(v)) if n.len() == 2 => (n, t, v),
+            _ => return Err(format!("malformed tag {}", field)),
+        };
+
+        let bad_value = |_| format!("malformed {} value in tag {}", tag_type, field);
+        let value = match tag_type {
+            "A" => {
+                let mut chars = value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => TagValue::Char(c),
+                    _ => return Err(format!("malformed A value in tag {}", field)),
+                }
+            }
+            "i" => TagValue::Int(i64::from_str(value).map_err(|e| bad_value(e.to_string()))?),
+            "f" => TagValue::Float(f64::from_str(value).map_err(|e| bad_value(e.to_string()))?),
+            "Z" => TagValue::String(String::from(value)),
+            "H" => TagValue::Hex(String::from(value)),
+            "B" => TagValue::Array(String::from(value)),
+            _ => return Err(format!("unknown type {} in tag {}", tag_type, field)),
+        };
+
+        Ok(Tag::new(name, value))
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            TagValue::Char(c) => write!(f, "{}:A:{}", self.name, c),
+            TagValue::Int(i) => write!(f, "{}:i:{}", self.name, i),
+            TagValue::Float(x) => write!(f, "{}:f:{}", self.name, x),
+            TagValue::String(s) => write!(f, "{}:Z:{}", self.name, s),
+            TagValue::Hex(h) => write!(f, "{}:H:{}", self.name, h),
+            TagValue::Array(a) => write!(f, "{}:B:{}", self.name, a),
+            TagValue::Opaque(field) => write!(f, "{}", field),
+        }
+    }
+}
+
+// Append tags to a PAF line leaving the original columns untouched.
+// Tags already on the line with the same names are replaced so that running
+// the filter twice does not repeat them.
+pub fn append_tags(line: &str, tags: &[Tag]) -> String {
+    let is_replaced = |field: &str| {
+        tags.iter().any(|t| {
+            field.len() > 3 && field.as_bytes()[2] == b':' && field.starts_with(&t.name[..])
+        })
+    };
+
+    let mut fields: Vec<String> = line
+        .split('\t')
+        .enumerate()
+        .filter(|(i, field)| *i < 12 || !is_replaced(field))
+        .map(|(_, field)| String::from(field))
+        .collect();
+    fields.extend(tags.iter().map(|t| t.to_string()));
+
+    fields.join("\t")
+}
+
+// Replace mandatory columns (0-based) and tags of a PAF line in place.
+// Tags that are not on the line yet are appended.
+pub fn rewrite_line(line: &str, columns: &[(usize, String)], tags: &[Tag]) -> String {
+    let mut fields: Vec<String> = line.split('\t').map(String::from).collect();
+
+    for (i, value) in columns.iter() {
+        if let Some(field) = fields.get_mut(*i) {
+            *field = value.clone();
+        }
+    }
+
+    for tag in tags.iter() {
+        let prefix = format!("{}:", tag.name);
+        match fields.iter().skip(12).position(|f| f.starts_with(&prefix)) {
+            Some(i) => fields[i + 12] = tag.to_string(),
+            None => fields.push(tag.to_string()),
+        }
+    }
+
+    fields.join("\t")
+}
+
+// Drop the tags with the given names from a PAF line
+pub fn remove_tags(line: &str, names: &[&str]) -> String {
+    line.split('\t')
+        .enumerate()
+        .filter(|(i, field)| {
+            *i < 12
+                || !names
+                    .iter()
+                    .any(|n| field.starts_with(n) && field[n.len()..].starts_with(':'))
+        })
+        .map(|(_, field)| field)
+        .collect::<Vec<&str>>()
+        .join("\t")
+}
+
 // A struct over a single line of a PAF file (a single alignment)
 #[derive(PartialEq)]
 pub struct PafAlignment {
-    query: String,             // Query sequence name
-    query_length: u32,         // Query sequence length
+    pub query: String,         // Query sequence name
+    pub query_length: u32,     // Query sequence length
     pub query_start: u32,      // Query start (0-based; BED-like; closed)
     pub query_end: u32,        // Query end (0-based; BED-like; open)
     pub strand: types::Strand, // Relative strand: "+" or "-"
-    target: String,            // target sequence name
-    target_length: u32,        // Target sequence length
+    pub target: String,        // target sequence name
+    pub target_length: u32,    // Target sequence length
     pub target_start: u32,     // Target start on original strand (0-based)
     pub target_end: u32,       // Target end on original strand (0-based)
-    // residue_matches: u32,  // Number of residue matches
-    // block_len: u32,        // Alignment block length
-    // quality: String,       // Mapping quality (0-255; 255 for missing)
-    pub cigar: String, // SAM style CIGAR string TODO: specify CIGAR version
+    pub residue_matches: u32,  // Number of residue matches
+    pub block_len: u32,        // Alignment block length
+    pub mapq: u32,             // Mapping quality (0-255; 255 for missing)
+    pub cigar: String,         // SAM style CIGAR string from cg:Z, empty if the line has none
+    pub tags: Vec<Tag>,        // SAM-like typed key-value pairs including cg
 }
 
 #[allow(dead_code)]
@@ -72,6 +216,8 @@ impl PafAlignment {
         target_end: u32,
         cigar: &str,
     ) -> Self {
+        let ops = cigar::parse(cigar).unwrap_or_default();
+
         PafAlignment {
             query: String::from(query),
             query_length,
@@ -82,9 +228,27 @@ impl PafAlignment {
             target_length,
             target_start,
             target_end,
+            residue_matches: cigar::residue_matches(&ops),
+            block_len: cigar::block_len(&ops),
+            mapq: 255,
             cigar: String::from(cigar),
+            tags: vec![Tag::new("cg", TagValue::String(String::from(cigar)))],
         }
     }
+
+    pub fn tag(&self, name: &str) -> Option<&Tag> {
+        self.tags.iter().find(|t| t.name == name)
+    }
+
+    // The PanSN sample, haplotype and contig of the query and the target
+    pub fn query_pansn(&self) -> Option<PanSn<'_>> {
+        PanSn::parse(&self.query)
+    }
+
+    pub fn target_pansn(&self) -> Option<PanSn<'_>> {
+        PanSn::parse(&self.target)
+    }
+
     pub fn from_lines(lines: Vec<String>) -> Vec<PafAlignment> {
         lines
             .iter()
@@ -94,18 +258,28 @@ impl PafAlignment {
 
     pub fn from_str(line: &str) -> Self {
         let it: Vec<&str> = line.split_whitespace().collect();
-        let sam_fields: &[&str] = &it[9..];
-
-        // expensive
-        // should return an Option<String> in case a field doens't exist
-        let extract_field = |pattern: &str| -> String {
-            let f: &str = sam_fields
-                .iter()
-                .find(|s| s.find(pattern) != None) // if the field is found
-                .unwrap();
+        // the SAM-like fields after the 12 mandatory columns
+        let tags: Vec<Tag> = it
+            .iter()
+            .skip(12)
+            .map(|field| {
+                if field.starts_with("cg:") && !field.starts_with("cg:Z:") {
+                    panic!(
+                        "[paf::PafAlignment::from_str] malformed CIGAR tag {}",
+                        field
+                    );
+                }
+                Tag::from_str(field).unwrap_or_else(|_| Tag::opaque(field))
+            })
+            .collect();
 
-            // drop the cg chars
-            String::from(&f[5..])
+        // realign does not need one
+        let cigar = match tags.iter().find(|t| t.name == "cg") {
+            Some(Tag {
+                value: TagValue::String(cg),
+                ..
+            }) => cg.clone(),
+            _ => String::new(),
         };
 
         let extract_strand = || -> types::Strand {
@@ -127,7 +301,11 @@ impl PafAlignment {
             target_length: u32::from_str(it[6]).unwrap(),
             target_start: u32::from_str(it[7]).unwrap(),
             target_end: u32::from_str(it[8]).unwrap(),
-            cigar: extract_field("cg"),
+            residue_matches: u32::from_str(it[9]).unwrap(),
+            block_len: u32::from_str(it[10]).unwrap(),
+            mapq: u32::from_str(it[11]).unwrap(),
+            cigar,
+            tags,
         }
     }
 }
@@ -203,6 +381,95 @@ qry\t330243\t0\t330243\t+\ttgt\t330243\t0\t330243\t330243\t330243\t60\tNM:i:0\tm
             "330243M",
         );
 
-        assert_eq!(aln, aln2);
+        assert_eq!(aln.tags.len(), 11);
+        assert_eq!(aln.tag("cg"), aln2.tag("cg"));
+        assert_eq!(
+            aln,
+            PafAlignment {
+                tags: aln.tags.clone(),
+                mapq: 60,
+                ..aln2
+            }
+        );
+
+        // mappers may leave out the CIGAR
+        let aln = PafAlignment::from_str("qry\t100\t0\t100\t+\ttgt\t100\t0\t100\t90\t100\t60");
+        assert!(aln.cigar.is_empty() && aln.tags.is_empty());
+    }
+
+    #[test]
+    fn test_tags() {
+        let aln = PafAlignment::from_str(TEST_PAF_STRING);
+        assert_eq!(aln.tag("NM").unwrap().value, TagValue::Int(0));
+        assert_eq!(aln.tag("tp").unwrap().value, TagValue::Char('P'));
+        assert_eq!(aln.tag("de").unwrap().value, TagValue::Float(0.0));
+        assert!(aln.tag("xx").is_none());
+
+        for field in [
+            "NM:i:12",
+            "tp:A:P",
+            "de:f:0.0003",
+            "cg:Z:3M1I",
+            "zz:B:c,1,2",
+        ] {
+            assert_eq!(Tag::from_str(field).unwrap().to_string(), field);
+        }
+
+        assert!(Tag::from_str("NM:i:x").is_err());
+        assert!(Tag::from_str("NMi0").is_err());
+        assert!(Tag::from_str("NM:q:0").is_err());
+
+        // fields that are not tags are kept on the line as written
+        let aln = PafAlignment::from_str(
+            "q\t10\t0\t10\t+\tt\t10\t0\t10\t10\t10\t60\tNM:i:x\tNMi0\tXY:q:0\tcg:Z:10M",
+        );
+        assert_eq!(aln.cigar, "10M");
+        assert_eq!(
+            aln.tags
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<String>>(),
+            vec!["NM:i:x", "NMi0", "XY:q:0", "cg:Z:10M"]
+        );
+        assert_eq!(
+            aln.tag("XY").unwrap().value,
+            TagValue::Opaque(String::from("XY:q:0"))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "malformed CIGAR tag cg:i:10")]
+    fn test_malformed_cigar_tag() {
+        PafAlignment::from_str("q\t10\t0\t10\t+\tt\t10\t0\t10\t10\t10\t60\tcg:i:10");
+    }
+
+    #[test]
+    fn test_rewrite_line() {
+        let line = "q\t10\t0\t10\t+\tt\t10\t0\t10\t10\t10\t60\tcg:Z:10M\tNM:i:0";
+        assert_eq!(
+            rewrite_line(
+                line,
+                &[(2, String::from("2")), (9, String::from("8"))],
+                &[
+                    Tag::new("cg", TagValue::String(String::from("8M"))),
+                    Tag::new("ws", TagValue::Int(0))
+                ]
+            ),
+            "q\t10\t2\t10\t+\tt\t10\t0\t10\t8\t10\t60\tcg:Z:8M\tNM:i:0\tws:i:0"
+        );
+    }
+
+    #[test]
+    fn test_append_tags() {
+        let line = "q\t10\t0\t10\t+\tt\t10\t0\t10\t10\t10\t60\tws:i:3\tcg:Z:10M";
+        let tags = vec![
+            Tag::new("ws", TagValue::Int(0)),
+            Tag::new("sf", TagValue::Float(0.5)),
+        ];
+
+        assert_eq!(
+            append_tags(line, &tags),
+            "q\t10\t0\t10\t+\tt\t10\t0\t10\t10\t10\t60\tcg:Z:10M\tws:i:0\tsf:f:0.5"
+        );
     }
 }
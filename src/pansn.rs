@@ -0,0 +1,79 @@
+/*
+PanSN names
+-----------
+
+Pangenome sequence names prefix the contig with the sample and haplotype it
+comes from, separated by #, so that the sequences of many genomes can share a
+FASTA and a PAF:
+
+    HG002#1#chr20    sample HG002, haplotype 1, contig chr20
+
+A name with a single # is read as sample#contig with no haplotype. The genome
+of a sequence is its name up to the contig, HG002#1 above. Names without a #
+belong to no sample or genome.
+*/
+pub const DELIMITER: char = '#';
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PanSn<'a> {
+    pub sample: &'a str,
+    pub haplotype: Option<&'a str>,
+    pub contig: &'a str,
+    pub genome: &'a str,
+}
+
+impl<'a> PanSn<'a> {
+    pub fn parse(name: &'a str) -> Option<Self> {
+        let fields: Vec<&str> = name.splitn(3, DELIMITER).collect();
+        let (sample, haplotype, contig) = match fields[..] {
+            [sample, haplotype, contig] => (sample, Some(haplotype), contig),
+            [sample, contig] => (sample, None, contig),
+            _ => return None,
+        };
+
+        Some(PanSn {
+            sample,
+            haplotype,
+            contig,
+            genome: &name[..name.len() - contig.len() - 1],
+        })
+    }
+}
+
+// Whether two sequences are from the same sample, never if either has no sample
+pub fn same_sample(a: &str, b: &str) -> bool {
+    match (PanSn::parse(a), PanSn::parse(b)) {
+        (Some(a), Some(b)) => a.sample == b.sample,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            PanSn::parse("HG002#1#chr20"),
+            Some(PanSn {
+                sample: "HG002",
+                haplotype: Some("1"),
+                contig: "chr20",
+                genome: "HG002#1",
+            })
+        );
+        assert_eq!(PanSn::parse("grch38#chr1").unwrap().genome, "grch38");
+        assert_eq!(PanSn::parse("grch38#chr1").unwrap().haplotype, None);
+        // the contig keeps any further delimiters
+        assert_eq!(PanSn::parse("a#2#scaf#7").unwrap().contig, "scaf#7");
+        assert_eq!(PanSn::parse("chr1"), None);
+    }
+
+    #[test]
+    fn test_same_sample() {
+        assert!(same_sample("HG002#1#chr20", "HG002#2#chr20"));
+        assert!(!same_sample("HG002#1#chr20", "HG005#1#chr20"));
+        assert!(!same_sample("chr20", "chr20"));
+    }
+}
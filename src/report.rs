@@ -0,0 +1,381 @@
+/*
+Filter report
+-------------
+
+One row per PAF line explaining why it was kept or dropped
+
+|Column          |Description                                          |
+|:---------------|:----------------------------------------------------|
+|line            |Line number in the PAF (1-based)                     |
+|query           |Query sequence name                                  |
+|query_start     |Query start                                          |
+|query_end       |Query end                                            |
+|strand          |Relative strand: "+" or "-"                          |
+|target          |Target sequence name                                 |
+|target_start    |Target start                                         |
+|target_end      |Target end                                           |
+|matched_bases   |Bases in the M/= ops of the PAF CIGAR                |
+|query_supported |Matched bases on the query the WFA path supports     |
+|target_supported|Matched bases on the target the WFA path supports    |
+|supported_bases |The smaller of query_supported and target_supported  |
+|support_fraction|supported_bases / matched_bases                      |
+|score           |WFA score of the pair, "*" (null) if not aligned     |
+|decision        |kept or dropped                                      |
+
+The genome report sums the rows up for every pair of PanSN genomes (see
+pansn.rs), names without one are of genome "*"
+
+|Column          |Description                                          |
+|:---------------|:----------------------------------------------------|
+|query_genome    |Genome of the queries e.g. HG002#1                   |
+|target_genome   |Genome of the targets                                |
+|lines           |PAF lines between the two                            |
+|kept            |Lines kept                                           |
+|matched_bases   |Sum of matched_bases                                 |
+|supported_bases |Sum of supported_bases                               |
+|support_fraction|supported_bases / matched_bases                      |
+
+Both are TSV with a header row or a JSON array of objects with the same keys.
+*/
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use super::paf;
+use super::pansn::PanSn;
+use super::support;
+use super::types;
+
+const GENOME_COLUMNS: [&str; 7] = [
+    "query_genome",
+    "target_genome",
+    "lines",
+    "kept",
+    "matched_bases",
+    "supported_bases",
+    "support_fraction",
+];
+
+const COLUMNS: [&str; 15] = [
+    "line",
+    "query",
+    "query_start",
+    "query_end",
+    "strand",
+    "target",
+    "target_start",
+    "target_end",
+    "matched_bases",
+    "query_supported",
+    "target_supported",
+    "supported_bases",
+    "support_fraction",
+    "score",
+    "decision",
+];
+
+pub fn decision(kept: bool) -> &'static str {
+    if kept {
+        "kept"
+    } else {
+        "dropped"
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Quoted for JSON
+fn text(x: &str, format: types::ReportFormat) -> String {
+    match format {
+        types::ReportFormat::Json => json_string(x),
+        types::ReportFormat::Tsv => String::from(x),
+    }
+}
+
+// The values of a row in the order of COLUMNS, strings already quoted for JSON
+fn row(
+    line_num: usize,
+    a: &paf::PafAlignment,
+    s: &support::LineSupport,
+    cli_args: &types::CliArgs,
+    format: types::ReportFormat,
+) -> Vec<String> {
+    let json = format == types::ReportFormat::Json;
+    let text = |x: &str| text(x, format);
+    let score = match (s.score, json) {
+        (Some(score), _) => score.to_string(),
+        (None, true) => String::from("null"),
+        (None, false) => String::from("*"),
+    };
+
+    vec![
+        (line_num + 1).to_string(),
+        text(&a.query),
+        a.query_start.to_string(),
+        a.query_end.to_string(),
+        text(&format!("{:?}", a.strand)),
+        text(&a.target),
+        a.target_start.to_string(),
+        a.target_end.to_string(),
+        s.matched_bases.to_string(),
+        s.query_supported.to_string(),
+        s.target_supported.to_string(),
+        s.supported_bases().to_string(),
+        format!("{:.6}", s.fraction()),
+        score,
+        text(decision(s.is_kept(cli_args))),
+    ]
+}
+
+fn write_rows<W: Write>(
+    writer: &mut W,
+    columns: &[&str],
+    rows: Vec<Vec<String>>,
+    format: types::ReportFormat,
+) -> std::io::Result<()> {
+    match format {
+        types::ReportFormat::Tsv => {
+            writeln!(writer, "{}", columns.join("\t"))?;
+            for r in rows.iter() {
+                writeln!(writer, "{}", r.join("\t"))?;
+            }
+        }
+        types::ReportFormat::Json => {
+            writeln!(writer, "[")?;
+            for (i, r) in rows.iter().enumerate() {
+                let fields: Vec<String> = columns
+                    .iter()
+                    .zip(r.iter())
+                    .map(|(k, v)| format!("\"{}\": {}", k, v))
+                    .collect();
+                let separator = if i + 1 < rows.len() { "," } else { "" };
+                writeln!(writer, "  {{{}}}{}", fields.join(", "), separator)?;
+            }
+            writeln!(writer, "]")?;
+        }
+    }
+
+    writer.flush()
+}
+
+pub fn write_report<W: Write>(
+    writer: &mut W,
+    paf: &paf::PAF,
+    support: &[support::LineSupport],
+    cli_args: &types::CliArgs,
+) -> std::io::Result<()> {
+    let format = cli_args.report_format;
+    let rows = paf
+        .get_alignments()
+        .iter()
+        .zip(support.iter())
+        .enumerate()
+        .map(|(line_num, (a, s))| row(line_num, a, s, cli_args, format))
+        .collect();
+
+    write_rows(writer, &COLUMNS, rows, format)
+}
+
+#[derive(Default)]
+struct GenomePair {
+    lines: usize,
+    kept: usize,
+    matched_bases: u64,
+    supported_bases: u64,
+}
+
+pub fn write_genome_report<W: Write>(
+    writer: &mut W,
+    paf: &paf::PAF,
+    support: &[support::LineSupport],
+    cli_args: &types::CliArgs,
+) -> std::io::Result<()> {
+    fn genome(name: &str) -> &str {
+        PanSn::parse(name).map_or("*", |p| p.genome)
+    }
+    let mut pairs: BTreeMap<(&str, &str), GenomePair> = BTreeMap::new();
+    for (a, s) in paf.get_alignments().iter().zip(support.iter()) {
+        let pair = pairs
+            .entry((genome(&a.query), genome(&a.target)))
+            .or_default();
+        pair.lines += 1;
+        pair.kept += s.is_kept(cli_args) as usize;
+        pair.matched_bases += s.matched_bases;
+        pair.supported_bases += s.supported_bases();
+    }
+
+    let format = cli_args.report_format;
+    let rows = pairs
+        .iter()
+        .map(|((query, target), p)| {
+            let fraction = if p.matched_bases == 0 {
+                0.0
+            } else {
+                p.supported_bases as f64 / p.matched_bases as f64
+            };
+            vec![
+                text(query, format),
+                text(target, format),
+                p.lines.to_string(),
+                p.kept.to_string(),
+                p.matched_bases.to_string(),
+                p.supported_bases.to_string(),
+                format!("{:.6}", fraction),
+            ]
+        })
+        .collect();
+
+    write_rows(writer, &GENOME_COLUMNS, rows, format)
+}
+
+type Writer = fn(
+    &mut BufWriter<File>,
+    &paf::PAF,
+    &[support::LineSupport],
+    &types::CliArgs,
+) -> std::io::Result<()>;
+
+fn write_file(
+    write: Writer,
+    fp: &str,
+    paf: &paf::PAF,
+    support: &[support::LineSupport],
+    cli_args: &types::CliArgs,
+) {
+    let file = File::create(fp)
+        .unwrap_or_else(|e| panic!("[wfilter::report] could not create {}: {}", fp, e));
+    let mut writer = BufWriter::new(file);
+
+    write(&mut writer, paf, support, cli_args)
+        .unwrap_or_else(|e| panic!("[wfilter::report] could not write {}: {}", fp, e));
+}
+
+pub fn write_report_file(
+    fp: &str,
+    paf: &paf::PAF,
+    support: &[support::LineSupport],
+    cli_args: &types::CliArgs,
+) {
+    write_file(write_report, fp, paf, support, cli_args)
+}
+
+pub fn write_genome_report_file(
+    fp: &str,
+    paf: &paf::PAF,
+    support: &[support::LineSupport],
+    cli_args: &types::CliArgs,
+) {
+    write_file(write_genome_report, fp, paf, support, cli_args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PAF_STRING: &str = "\
+    qry\t100\t0\t100\t+\ttgt\t100\t0\t100\t100\t100\t60\tcg:Z:100M\n\
+    q\"2\t100\t40\t60\t-\ttgt\t100\t0\t20\t20\t20\t60\tcg:Z:20M";
+
+    fn support() -> Vec<support::LineSupport> {
+        vec![
+            support::LineSupport {
+                matched_bases: 100,
+                query_supported: 40,
+                target_supported: 50,
+                wfa_bases: 50,
+                score: Some(24),
+                target_span: None,
+                selected: true,
+            },
+            support::LineSupport {
+                matched_bases: 20,
+                query_supported: 0,
+                target_supported: 0,
+                wfa_bases: 0,
+                score: None,
+                target_span: None,
+                selected: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_tsv() {
+        let paf = paf::PAF::from_str(PAF_STRING);
+        let cli_args = types::CliArgs::for_tests();
+
+        let mut out: Vec<u8> = Vec::new();
+        write_report(&mut out, &paf, &support(), &cli_args).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("line\tquery\tquery_start"));
+        assert_eq!(
+            lines[1],
+            "1\tqry\t0\t100\t+\ttgt\t0\t100\t100\t40\t50\t40\t0.400000\t24\tkept"
+        );
+        assert_eq!(
+            lines[2],
+            "2\tq\"2\t40\t60\t-\ttgt\t0\t20\t20\t0\t0\t0\t0.000000\t*\tdropped"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let paf = paf::PAF::from_str(PAF_STRING);
+        let cli_args = types::CliArgs {
+            report_format: types::ReportFormat::Json,
+            ..types::CliArgs::for_tests()
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        write_report(&mut out, &paf, &support(), &cli_args).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("  {\"line\": 1, \"query\": \"qry\","));
+        assert!(lines[1].ends_with("\"score\": 24, \"decision\": \"kept\"},"));
+        assert!(lines[2].contains("\"query\": \"q\\\"2\""));
+        assert!(lines[2].ends_with("\"score\": null, \"decision\": \"dropped\"}"));
+    }
+
+    #[test]
+    fn test_genome_report() {
+        let paf = paf::PAF::from_str(
+            "\
+            HG002#1#chr1\t100\t0\t100\t+\tgrch38#chr1\t100\t0\t100\t100\t100\t60\n\
+            HG002#1#chr2\t100\t40\t60\t-\tgrch38#chr2\t100\t0\t20\t20\t20\t60\n\
+            qry\t100\t0\t100\t+\tgrch38#chr1\t100\t0\t100\t100\t100\t60",
+        );
+        let mut support = support();
+        support.push(support[0]);
+        let cli_args = types::CliArgs::for_tests();
+
+        let mut out: Vec<u8> = Vec::new();
+        write_genome_report(&mut out, &paf, &support, &cli_args).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out.lines().collect::<Vec<&str>>(),
+            vec![
+                "query_genome\ttarget_genome\tlines\tkept\tmatched_bases\tsupported_bases\tsupport_fraction",
+                "*\tgrch38\t1\t1\t100\t40\t0.400000",
+                "HG002#1\tgrch38\t2\t1\t120\t40\t0.333333",
+            ]
+        );
+    }
+}
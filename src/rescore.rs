@@ -0,0 +1,213 @@
+/*
+Rescoring
+---------
+
+Score the CIGAR a PAF line already has, without the sequences, as a cheap pass
+before the WFA filter.
+
+|Tag |Description                                                   |
+|:---|:-------------------------------------------------------------|
+|rs:i|Gap-affine score of the CIGAR under the penalties             |
+|ri:f|Identity, matches over matches, mismatches and gap bases      |
+|rg:f|Gap-compressed identity, a gap of any length counts once      |
+|re:i|Number of indel events, the I and D ops                       |
+
+Mappers such as minimap2 write M for both matches and mismatches. When a
+CIGAR has no X or = ops and the line has an NM tag the mismatches are taken
+to be NM less the gap bases, otherwise every M is counted as a match.
+
+With --expr a line must also be selected by the expression (see expr.rs),
+e.g. `rescore --expr 'identity > 0.9 && mapq >= 20'`.
+*/
+use std::io::{BufRead, Write};
+
+use super::cigar;
+use super::expr;
+use super::paf::{self, Tag, TagValue};
+use super::types;
+use super::utils;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CigarScore {
+    pub score: u64,
+    pub identity: f64,
+    pub gap_compressed_identity: f64,
+    pub indel_events: u64,
+}
+
+impl CigarScore {
+    pub fn is_kept(&self, cli_args: &types::CliArgs) -> bool {
+        self.identity >= cli_args.min_identity
+            && self.gap_compressed_identity >= cli_args.min_gap_compressed_identity
+            && cli_args
+                .max_indel_events
+                .is_none_or(|m| self.indel_events <= m)
+            && cli_args.max_score.is_none_or(|m| self.score <= m)
+    }
+
+    pub fn tags(&self) -> Vec<Tag> {
+        vec![
+            Tag::new("rs", TagValue::Int(self.score as i64)),
+            Tag::new("ri", TagValue::Float(utils::round(self.identity))),
+            Tag::new(
+                "rg",
+                TagValue::Float(utils::round(self.gap_compressed_identity)),
+            ),
+            Tag::new("re", TagValue::Int(self.indel_events as i64)),
+        ]
+    }
+}
+
+fn ratio(x: u64, y: u64) -> f64 {
+    if y == 0 {
+        0.0
+    } else {
+        x as f64 / y as f64
+    }
+}
+
+pub fn rescore(a: &paf::PafAlignment, penalties: &types::Penalties) -> Result<CigarScore, String> {
+    let ops = cigar::parse(&a.cigar)?;
+
+    let mut matches: u64 = 0;
+    let mut mismatches: u64 = 0;
+    let mut gap_bases: u64 = 0;
+    let mut indel_events: u64 = 0;
+    let mut gap_score: u64 = 0;
+
+    for op in ops.iter() {
+        let len = op.len as u64;
+        match op.op {
+            'M' | '=' => matches += len,
+            'X' => mismatches += len,
+            _ => {
+                gap_bases += len;
+                indel_events += 1;
+                gap_score += penalties.gap_open + penalties.gap_extend * len;
+            }
+        }
+    }
+
+    // M holds the mismatches as well
+    let m_only = !ops.iter().any(|o| matches!(o.op, '=' | 'X'));
+    if let (true, Some(TagValue::Int(nm))) = (m_only, a.tag("NM").map(|t| &t.value)) {
+        let nm_mismatches = (*nm as u64).saturating_sub(gap_bases).min(matches);
+        matches -= nm_mismatches;
+        mismatches += nm_mismatches;
+    }
+
+    Ok(CigarScore {
+        score: penalties.matches * matches + penalties.mismatch * mismatches + gap_score,
+        identity: ratio(matches, matches + mismatches + gap_bases),
+        gap_compressed_identity: ratio(matches, matches + mismatches + indel_events),
+        indel_events,
+    })
+}
+
+// Write the lines whose CIGAR scores pass the thresholds with the scores as tags
+pub fn rescore_paf<R: BufRead, W: Write>(
+    reader: R,
+    cli_args: &types::CliArgs,
+    out: &mut W,
+) -> std::io::Result<()> {
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        let a = paf::PafAlignment::from_str(&line);
+        if a.cigar.is_empty() {
+            panic!(
+                "[wfilter::rescore] no cg:Z: CIGAR on PAF line {} for {}",
+                line_num + 1,
+                a.query
+            );
+        }
+
+        let score = rescore(&a, &cli_args.penalties).unwrap_or_else(|e| {
+            panic!(
+                "[wfilter::rescore] could not rescore PAF line {}: {}",
+                line_num + 1,
+                e
+            )
+        });
+        if score.is_kept(cli_args) && expr::is_selected(&a, cli_args) {
+            writeln!(out, "{}", paf::append_tags(&line, &score.tags()))?;
+        }
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PENALTIES: types::Penalties = types::Penalties {
+        mismatch: 4,
+        matches: 0,
+        gap_open: 6,
+        gap_extend: 2,
+    };
+
+    fn line(cigar: &str, tags: &str) -> paf::PafAlignment {
+        paf::PafAlignment::from_str(&format!(
+            "q\t100\t0\t100\t+\tt\t100\t0\t100\t0\t0\t60\t{}cg:Z:{}",
+            tags, cigar
+        ))
+    }
+
+    #[test]
+    fn test_rescore() {
+        // 16 matches, 2 mismatches, a 2 base insertion and a 1 base deletion
+        let score = rescore(&line("8M2X2I8M1D", ""), &PENALTIES).unwrap();
+        assert_eq!(
+            score,
+            CigarScore {
+                score: 2 * 4 + (6 + 2 * 2) + (6 + 2),
+                identity: 16.0 / 21.0,
+                gap_compressed_identity: 16.0 / 20.0,
+                indel_events: 2,
+            }
+        );
+
+        // the same alignment from a mapper that writes M for mismatches
+        let m_only = rescore(&line("10M2I8M1D", "NM:i:5\t"), &PENALTIES).unwrap();
+        assert_eq!(m_only, score);
+
+        // without NM every M is a match
+        let score = rescore(&line("10M2I8M1D", ""), &PENALTIES).unwrap();
+        assert_eq!((score.score, score.identity), (18, 18.0 / 21.0));
+
+        assert!(rescore(&line("10Q", ""), &PENALTIES).is_err());
+    }
+
+    #[test]
+    fn test_rescore_paf() {
+        let paf = "\
+        q\t100\t0\t20\t+\tt\t100\t0\t20\t20\t20\t60\tcg:Z:20M\n\
+        q\t100\t0\t20\t+\tt\t100\t0\t20\t16\t21\t60\tcg:Z:8M2X2I8M1D\n";
+
+        let cli_args = types::CliArgs {
+            max_indel_events: Some(1),
+            ..types::CliArgs::for_tests()
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        rescore_paf(paf.as_bytes(), &cli_args, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "q\t100\t0\t20\t+\tt\t100\t0\t20\t20\t20\t60\tcg:Z:20M\trs:i:0\tri:f:1\trg:f:1\tre:i:0\n"
+        );
+
+        // --expr is applied on top of the thresholds
+        let cli_args = types::CliArgs {
+            expr: Some(expr::Expr::parse("identity < 0.9").unwrap()),
+            ..types::CliArgs::for_tests()
+        };
+
+        let mut out: Vec<u8> = Vec::new();
+        rescore_paf(paf.as_bytes(), &cli_args, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "q\t100\t0\t20\t+\tt\t100\t0\t20\t16\t21\t60\tcg:Z:8M2X2I8M1D\trs:i:26\tri:f:0.7619\trg:f:0.8\tre:i:2\n"
+        );
+    }
+}
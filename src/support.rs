@@ -0,0 +1,422 @@
+/*
+Support accounting
+------------------
+
+For every PAF line count how many of its matched bases lie under a WFA match
+run, separately on the query and on the target. A run only supports a match
+block of a line where both lie on the same diagonal, i.e. the WFA path pairs
+the same query and target bases as the PAF CIGAR. Overlaps are kept as
+intervals and merged at the end so that a base covered by several match runs
+is only counted once. The matches of a WFA alignment only count towards the
+lines of the target and query it aligned, never towards another pair's lines
+at the same coordinates.
+
+A line is supported by the smaller of its query and target counts and is kept
+when that reaches both `--min-supported-bases` and `--min-support-fraction`
+of the line's matched bases. Lines left out by `--expr` or `--best-hits` are
+never kept.
+
+The WFA matches inside a line's target span are also counted so that the
+concordance of the PAF CIGAR and the WFA path can be reported, the Jaccard
+index of their matches.
+*/
+use super::index;
+use super::paf::{self, Tag, TagValue};
+use super::types;
+use super::utils;
+
+// Half-open intervals on one sequence of a line
+type Overlaps = Vec<(i32, i32)>;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LineSupport {
+    pub matched_bases: u64,
+    pub query_supported: u64,
+    pub target_supported: u64,
+    pub wfa_bases: u64,       // WFA matched bases in the target span of the line
+    pub score: Option<usize>, // WFA score of the pair, None if it was not aligned
+    pub target_span: Option<(i32, i32)>, // first to last supported target base
+    pub selected: bool,       // passed --expr and --best-hits
+}
+
+impl LineSupport {
+    pub fn supported_bases(&self) -> u64 {
+        self.query_supported.min(self.target_supported)
+    }
+
+    pub fn fraction(&self) -> f64 {
+        if self.matched_bases == 0 {
+            0.0
+        } else {
+            self.supported_bases() as f64 / self.matched_bases as f64
+        }
+    }
+
+    // Matches on both paths over matches on either
+    pub fn concordance(&self) -> f64 {
+        let either =
+            self.matched_bases + self.wfa_bases - self.supported_bases().min(self.wfa_bases);
+        if either == 0 {
+            0.0
+        } else {
+            self.supported_bases() as f64 / either as f64
+        }
+    }
+
+    pub fn is_kept(&self, cli_args: &types::CliArgs) -> bool {
+        self.selected
+            && self.supported_bases() >= cli_args.min_supported_bases
+            && self.fraction() >= cli_args.min_support_fraction
+    }
+
+    /// Tags describing the support of the line
+    ///  ws:i WFA score of the pair (absent if it was not aligned)
+    ///  sb:i supported bases
+    ///  sf:f support fraction
+    ///  wc:f concordance of the PAF CIGAR and the WFA path
+    pub fn tags(&self) -> Vec<Tag> {
+        let mut tags: Vec<Tag> = Vec::new();
+        if let Some(score) = self.score {
+            tags.push(Tag::new("ws", TagValue::Int(score as i64)));
+        }
+        tags.push(Tag::new("sb", TagValue::Int(self.supported_bases() as i64)));
+        tags.push(Tag::new(
+            "sf",
+            TagValue::Float(utils::round(self.fraction())),
+        ));
+        tags.push(Tag::new(
+            "wc",
+            TagValue::Float(utils::round(self.concordance())),
+        ));
+        tags
+    }
+}
+
+pub struct SupportCounter {
+    matched_bases: Vec<u64>,
+    query: Vec<Overlaps>,
+    target: Vec<Overlaps>,
+    wfa: Vec<Overlaps>,
+    scores: Vec<Option<usize>>,
+    strands: Vec<types::Strand>,
+    selected: Vec<bool>,
+    spans: types::Index, // target span of every line
+}
+
+// From the start of the first to the end of the last interval
+fn span(overlaps: &[(i32, i32)]) -> Option<(i32, i32)> {
+    let start = overlaps.iter().map(|(s, _)| *s).min()?;
+    let end = overlaps.iter().map(|(_, e)| *e).max()?;
+    Some((start, end))
+}
+
+// Total length covered by a set of intervals
+fn covered_bases(overlaps: &mut Overlaps) -> u64 {
+    overlaps.sort_unstable();
+
+    let mut covered: u64 = 0;
+    let mut reach = i32::MIN;
+    for (start, end) in overlaps.iter() {
+        let start = (*start).max(reach);
+        if *end > start {
+            covered += (end - start) as u64;
+            reach = *end;
+        }
+    }
+
+    covered
+}
+
+impl SupportCounter {
+    pub fn new(paf: &paf::PAF) -> Self {
+        let alignments = paf.get_alignments();
+        let matched_bases: Vec<u64> = alignments
+            .iter()
+            .map(|a| index::matched_bases(&a.cigar))
+            .collect();
+        let lines = matched_bases.len();
+
+        // coitrees intervals are end-inclusive
+        let spans: Vec<coitrees::IntervalNode<types::AlignmentMetadata, u32>> = alignments
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| a.target_end > a.target_start)
+            .map(|(line_num, a)| {
+                coitrees::IntervalNode::new(
+                    a.target_start as i32,
+                    a.target_end as i32 - 1,
+                    line_num,
+                )
+            })
+            .collect();
+
+        SupportCounter {
+            matched_bases,
+            query: vec![Vec::new(); lines],
+            target: vec![Vec::new(); lines],
+            wfa: vec![Vec::new(); lines],
+            scores: vec![None; lines],
+            strands: alignments.iter().map(|a| a.strand).collect(),
+            selected: vec![true; lines],
+            spans: coitrees::COITree::new(spans),
+        }
+    }
+
+    pub fn set_score(&mut self, line_num: usize, score: usize) {
+        self.scores[line_num] = Some(score);
+    }
+
+    // Leave a line out, matches are no longer recorded for it
+    pub fn deselect(&mut self, line_num: usize) {
+        self.selected[line_num] = false;
+    }
+
+    // Record the match blocks a WFA match run lies on. Only the lines of the
+    // pair that was aligned, given sorted, can be supported by it.
+    pub fn add_match(
+        &mut self,
+        index: &index::SegmentIndex,
+        lines: &[usize],
+        query: (i32, i32),
+        target: (i32, i32),
+        strand: types::Strand,
+    ) {
+        let query_overlaps = &mut self.query;
+        let target_overlaps = &mut self.target;
+        let selected = &self.selected;
+        let counted =
+            |line_num: usize| selected[line_num] && lines.binary_search(&line_num).is_ok();
+
+        index.query(target.0, target.1, |segment| {
+            if !counted(segment.line_num) {
+                return;
+            }
+            if let Some((q, t)) = segment.overlap(query, target, strand) {
+                query_overlaps[segment.line_num].push(q);
+                target_overlaps[segment.line_num].push(t);
+            }
+        });
+
+        if target.0 >= target.1 {
+            return;
+        }
+
+        let wfa_overlaps = &mut self.wfa;
+        let strands = &self.strands;
+        self.spans.query(target.0, target.1 - 1, |node| {
+            let start = target.0.max(node.first);
+            let end = target.1.min(node.last + 1);
+            if counted(node.metadata) && strands[node.metadata] == strand && start < end {
+                wfa_overlaps[node.metadata].push((start, end));
+            }
+        });
+    }
+
+    // Take in the matches and scores another counter over the same PAF
+    // recorded. Overlaps are merged in into_lines so the order does not matter.
+    pub fn merge(&mut self, other: SupportCounter) {
+        let append = |mine: &mut Vec<Overlaps>, theirs: Vec<Overlaps>| {
+            mine.iter_mut()
+                .zip(theirs)
+                .for_each(|(m, mut t)| m.append(&mut t));
+        };
+        append(&mut self.query, other.query);
+        append(&mut self.target, other.target);
+        append(&mut self.wfa, other.wfa);
+
+        self.scores
+            .iter_mut()
+            .zip(other.scores)
+            .filter(|(_, theirs)| theirs.is_some())
+            .for_each(|(mine, theirs)| *mine = theirs);
+    }
+
+    pub fn into_lines(self) -> Vec<LineSupport> {
+        self.matched_bases
+            .into_iter()
+            .zip(self.query.into_iter().zip(self.target))
+            .zip(self.wfa.into_iter().zip(self.scores))
+            .zip(self.selected)
+            .map(
+                |(((matched_bases, (mut query, mut target)), (mut wfa, score)), selected)| {
+                    LineSupport {
+                        matched_bases,
+                        query_supported: covered_bases(&mut query),
+                        target_supported: covered_bases(&mut target),
+                        wfa_bases: covered_bases(&mut wfa),
+                        score,
+                        target_span: span(&target),
+                        selected,
+                    }
+                },
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PAF_STRING: &str = "\
+    qry\t100\t0\t100\t+\ttgt\t100\t0\t100\t100\t100\t60\tcg:Z:100M\n\
+    qry\t100\t10\t30\t+\ttgt\t100\t10\t30\t20\t20\t60\tcg:Z:10M2I8M2D\n\
+    qry\t100\t40\t60\t-\ttgt\t100\t0\t20\t20\t20\t60\tcg:Z:20M";
+
+    // every line of PAF_STRING is of the same pair
+    static ALL: &[usize] = &[0, 1, 2];
+
+    #[test]
+    fn test_covered_bases() {
+        let mut overlaps = vec![(10, 20), (0, 5), (15, 25), (30, 31)];
+        assert_eq!(covered_bases(&mut overlaps), 21);
+        assert_eq!(covered_bases(&mut Vec::new()), 0);
+    }
+
+    #[test]
+    fn test_support_counter() {
+        let paf = paf::PAF::from_str(PAF_STRING);
+        let index = index::index_paf_segments(&paf, &[true; 3]);
+        let forward = types::Strand::Forward;
+
+        let mut counter = SupportCounter::new(&paf);
+        counter.add_match(&index, ALL, (0, 15), (0, 15), forward);
+        // counted once even though it overlaps the previous run
+        counter.add_match(&index, ALL, (5, 15), (5, 15), forward);
+        counter.add_match(&index, ALL, (50, 60), (50, 60), forward);
+        // overlaps the second line on the target but off its diagonal
+        counter.add_match(&index, ALL, (30, 40), (20, 30), forward);
+        // on the anti-diagonal of the reverse line
+        counter.add_match(&index, ALL, (50, 60), (0, 10), types::Strand::Reverse);
+        counter.set_score(1, 12);
+
+        let lines = counter.into_lines();
+        assert_eq!(
+            lines[0],
+            LineSupport {
+                matched_bases: 100,
+                query_supported: 25,
+                target_supported: 25,
+                wfa_bases: 35,
+                score: None,
+                target_span: Some((0, 60)),
+                selected: true,
+            }
+        );
+        // query 10..15 and target 10..15 of the second line
+        assert_eq!(
+            lines[1],
+            LineSupport {
+                matched_bases: 18,
+                query_supported: 5,
+                target_supported: 5,
+                wfa_bases: 15,
+                score: Some(12),
+                target_span: Some((10, 15)),
+                selected: true,
+            }
+        );
+        assert_eq!(lines[2].supported_bases(), 10);
+
+        let cli_args = types::CliArgs::for_tests();
+        assert!(lines[0].is_kept(&cli_args));
+
+        let cli_args = types::CliArgs {
+            min_support_fraction: 0.5,
+            ..cli_args
+        };
+        assert!(!lines[0].is_kept(&cli_args));
+        assert!(lines[2].is_kept(&cli_args));
+
+        let cli_args = types::CliArgs {
+            min_support_fraction: 0.0,
+            min_supported_bases: 20,
+            ..cli_args
+        };
+        assert!(lines[0].is_kept(&cli_args));
+        assert!(!lines[1].is_kept(&cli_args));
+    }
+
+    #[test]
+    fn test_merge() {
+        let paf = paf::PAF::from_str(PAF_STRING);
+        let index = index::index_paf_segments(&paf, &[true; 3]);
+        let forward = types::Strand::Forward;
+
+        let mut counter = SupportCounter::new(&paf);
+        counter.add_match(&index, ALL, (0, 15), (0, 15), forward);
+        counter.set_score(0, 3);
+
+        let mut other = SupportCounter::new(&paf);
+        other.add_match(&index, ALL, (5, 15), (5, 15), forward);
+        other.add_match(&index, ALL, (50, 60), (50, 60), forward);
+        other.set_score(1, 12);
+
+        counter.merge(other);
+        let lines = counter.into_lines();
+        assert_eq!((lines[0].supported_bases(), lines[0].score), (25, Some(3)));
+        assert_eq!((lines[1].supported_bases(), lines[1].score), (5, Some(12)));
+    }
+
+    #[test]
+    fn test_other_pair() {
+        // two queries on the same target coordinates
+        let paf = paf::PAF::from_str(
+            "good\t20\t0\t20\t+\ttgt\t100\t0\t20\t20\t20\t60\tcg:Z:20M\n\
+             bad\t20\t0\t20\t+\ttgt\t100\t0\t20\t20\t20\t60\tcg:Z:20M",
+        );
+        let index = index::index_paf_segments(&paf, &[true; 2]);
+
+        let mut counter = SupportCounter::new(&paf);
+        counter.add_match(&index, &[0], (0, 20), (0, 20), types::Strand::Forward);
+
+        let lines = counter.into_lines();
+        assert_eq!((lines[0].supported_bases(), lines[0].wfa_bases), (20, 20));
+        assert_eq!((lines[1].supported_bases(), lines[1].wfa_bases), (0, 0));
+    }
+
+    #[test]
+    fn test_deselect() {
+        let paf = paf::PAF::from_str(PAF_STRING);
+        let index = index::index_paf_segments(&paf, &[true; 3]);
+
+        let mut counter = SupportCounter::new(&paf);
+        counter.deselect(1);
+        counter.add_match(&index, ALL, (0, 20), (0, 20), types::Strand::Forward);
+
+        let lines = counter.into_lines();
+        assert_eq!(lines[0].supported_bases(), 20);
+        assert_eq!((lines[1].supported_bases(), lines[1].wfa_bases), (0, 0));
+
+        let cli_args = types::CliArgs {
+            min_supported_bases: 0,
+            ..types::CliArgs::for_tests()
+        };
+        assert!(lines[0].is_kept(&cli_args));
+        assert!(!lines[1].is_kept(&cli_args));
+    }
+
+    #[test]
+    fn test_tags() {
+        let line = LineSupport {
+            matched_bases: 100,
+            query_supported: 40,
+            target_supported: 50,
+            wfa_bases: 60,
+            score: Some(24),
+            target_span: Some((0, 100)),
+            selected: true,
+        };
+        let tags: Vec<String> = line.tags().iter().map(|t| t.to_string()).collect();
+        // 40 on both of 100 + 60 - 40 on either
+        assert_eq!(tags, vec!["ws:i:24", "sb:i:40", "sf:f:0.4", "wc:f:0.3333"]);
+
+        let line = LineSupport {
+            score: None,
+            wfa_bases: 40,
+            ..line
+        };
+        let tags: Vec<String> = line.tags().iter().map(|t| t.to_string()).collect();
+        assert_eq!(tags, vec!["sb:i:40", "sf:f:0.4", "wc:f:0.4"]);
+    }
+}
@@ -0,0 +1,211 @@
+/*
+Trimming
+--------
+
+Clip the leading and trailing parts of a PAF alignment that the WFA path does
+not support. The supported span is given on the target, from the first to the
+last supported base, and the CIGAR is walked to find the query bases on either
+side of it. Insertions exactly at the edges of the span are clipped.
+
+Residue matches counts M and = bases, as the rest of the filter does, and the
+block length every base of the trimmed CIGAR. A line's NM is recomputed from
+the trimmed CIGAR when it has X or = ops. Mappers such as minimap2 write M for
+mismatches too, and then which of the kept bases mismatch is not known: NM is
+dropped, and residue matches is the line's own less the M bases clipped, so it
+is never too high. The mapper's scores and divergence (AS, ms, de, dv) cannot
+be recomputed either, so they are dropped.
+*/
+use super::cigar::{self, CigarOp};
+use super::paf::{self, Tag, TagValue};
+use super::types;
+
+// Tags of the mapper's alignment that no longer hold for the trimmed one
+const STALE_TAGS: [&str; 4] = ["AS", "ms", "de", "dv"];
+
+#[derive(PartialEq, Debug)]
+pub struct Trimmed {
+    pub query_start: u32,
+    pub query_end: u32,
+    pub target_start: u32,
+    pub target_end: u32,
+    pub residue_matches: u32,
+    pub block_len: u32,
+    pub edit_distance: Option<u32>, // None when M holds mismatches too
+    pub cigar: String,
+}
+
+fn push(ops: &mut Vec<CigarOp>, len: u32, op: char) {
+    if len == 0 {
+        return;
+    }
+    match ops.last_mut() {
+        Some(last) if last.op == op => last.len += len,
+        _ => ops.push(CigarOp::new(len, op)),
+    }
+}
+
+/// Clip an alignment to the target span [start, end) keeping the rest of the CIGAR.
+pub fn trim(a: &paf::PafAlignment, (start, end): (i32, i32)) -> Result<Trimmed, String> {
+    let ops = cigar::parse(&a.cigar)?;
+
+    let mut t = a.target_start as i32;
+    let mut query_before: u32 = 0; // query bases clipped before the span
+    let mut query_kept: u32 = 0;
+    let mut kept: Vec<CigarOp> = Vec::new();
+
+    for op in ops.iter() {
+        let len = op.len as i32;
+
+        if op.consumes_target() {
+            let lead = (start - t).clamp(0, len);
+            let keep = (end.min(t + len) - start.max(t)).max(0);
+
+            if op.consumes_query() {
+                query_before += lead as u32;
+                query_kept += keep as u32;
+            }
+            push(&mut kept, keep as u32, op.op);
+            t += len;
+        } else if t <= start {
+            // an insertion before the span
+            query_before += op.len;
+        } else if t < end {
+            query_kept += op.len;
+            push(&mut kept, op.len, op.op);
+        }
+    }
+
+    let (query_start, query_end) = match a.strand {
+        types::Strand::Forward => {
+            let query_start = a.query_start + query_before;
+            (query_start, query_start + query_kept)
+        }
+        types::Strand::Reverse => {
+            let query_end = a.query_end - query_before;
+            (query_end - query_kept, query_end)
+        }
+    };
+
+    // M holds the mismatches as well
+    let m_only = !ops.iter().any(|o| matches!(o.op, '=' | 'X'));
+    let (residue_matches, edit_distance) = if m_only {
+        let clipped = cigar::residue_matches(&ops) - cigar::residue_matches(&kept);
+        (a.residue_matches.saturating_sub(clipped), None)
+    } else {
+        (
+            cigar::residue_matches(&kept),
+            Some(cigar::edit_distance(&kept)),
+        )
+    };
+
+    Ok(Trimmed {
+        query_start,
+        query_end,
+        target_start: start as u32,
+        target_end: end as u32,
+        residue_matches,
+        block_len: cigar::block_len(&kept),
+        edit_distance,
+        cigar: cigar::to_string(&kept),
+    })
+}
+
+// The line with its coordinates, residue matches, block length, cg and NM if
+// it has one rewritten and the stale tags dropped
+pub fn rewrite(line: &str, trimmed: &Trimmed) -> String {
+    let mut tags = vec![Tag::new("cg", TagValue::String(trimmed.cigar.clone()))];
+    let mut stale = STALE_TAGS.to_vec();
+    if line.split('\t').skip(12).any(|f| f.starts_with("NM:")) {
+        match trimmed.edit_distance {
+            Some(nm) => tags.push(Tag::new("NM", TagValue::Int(nm as i64))),
+            None => stale.push("NM"),
+        }
+    }
+
+    let line = paf::rewrite_line(
+        line,
+        &[
+            (2, trimmed.query_start.to_string()),
+            (3, trimmed.query_end.to_string()),
+            (7, trimmed.target_start.to_string()),
+            (8, trimmed.target_end.to_string()),
+            (9, trimmed.residue_matches.to_string()),
+            (10, trimmed.block_len.to_string()),
+        ],
+        &tags,
+    );
+    paf::remove_tags(&line, &stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LINE: &str = "qry\t100\t10\t30\t+\ttgt\t200\t100\t121\t15\t23\t60\t\
+                         NM:i:8\tAS:i:-24\tde:f:0.381\tcg:Z:5M2I3X4M3D6M";
+
+    #[test]
+    fn test_trim_forward() {
+        let a = paf::PafAlignment::from_str(LINE);
+
+        // the whole alignment is supported
+        let trimmed = trim(&a, (100, 121)).unwrap();
+        assert_eq!(trimmed.cigar, "5M2I3X4M3D6M");
+        assert_eq!((trimmed.query_start, trimmed.query_end), (10, 30));
+        assert_eq!((trimmed.residue_matches, trimmed.block_len), (15, 23));
+
+        // clip 3 matches on the left and 4 on the right
+        let trimmed = trim(&a, (103, 117)).unwrap();
+        assert_eq!(trimmed.cigar, "2M2I3X4M3D2M");
+        assert_eq!((trimmed.query_start, trimmed.query_end), (13, 26));
+        assert_eq!((trimmed.target_start, trimmed.target_end), (103, 117));
+        assert_eq!((trimmed.residue_matches, trimmed.block_len), (8, 16));
+
+        assert_eq!(
+            rewrite(LINE, &trimmed),
+            "qry\t100\t13\t26\t+\ttgt\t200\t103\t117\t8\t16\t60\tNM:i:8\tcg:Z:2M2I3X4M3D2M"
+        );
+
+        // an insertion at the edge of the span is clipped
+        let trimmed = trim(&a, (105, 117)).unwrap();
+        assert_eq!(trimmed.cigar, "3X4M3D2M");
+        assert_eq!((trimmed.query_start, trimmed.query_end), (17, 26));
+        assert_eq!(
+            rewrite(LINE, &trimmed),
+            "qry\t100\t17\t26\t+\ttgt\t200\t105\t117\t6\t12\t60\tNM:i:6\tcg:Z:3X4M3D2M"
+        );
+
+        // and so are mismatches and deletions
+        let trimmed = trim(&a, (106, 113)).unwrap();
+        assert_eq!(trimmed.cigar, "2X4M1D");
+        assert_eq!(
+            (trimmed.residue_matches, trimmed.edit_distance),
+            (4, Some(3))
+        );
+    }
+
+    #[test]
+    fn test_trim_m_only() {
+        // as minimap2 writes it, 3 of the 20 M bases mismatch
+        let line = "qry\t100\t10\t30\t+\ttgt\t200\t100\t120\t17\t20\t60\tNM:i:3\tcg:Z:20M";
+        let a = paf::PafAlignment::from_str(line);
+
+        let trimmed = trim(&a, (105, 115)).unwrap();
+        assert_eq!((trimmed.residue_matches, trimmed.edit_distance), (7, None));
+        assert_eq!(
+            rewrite(line, &trimmed),
+            "qry\t100\t15\t25\t+\ttgt\t200\t105\t115\t7\t10\t60\tcg:Z:10M"
+        );
+    }
+
+    #[test]
+    fn test_trim_reverse() {
+        let line = LINE.replace("\t+\t", "\t-\t");
+        let a = paf::PafAlignment::from_str(&line);
+
+        // the query runs backwards from its end
+        let trimmed = trim(&a, (103, 117)).unwrap();
+        assert_eq!(trimmed.cigar, "2M2I3X4M3D2M");
+        assert_eq!((trimmed.query_start, trimmed.query_end), (14, 27));
+    }
+}
@@ -1,6 +1,9 @@
+use chrono::{DateTime, Local};
 use coitrees;
 use std::fmt;
-use chrono::{DateTime, Local};
+
+use super::expr;
+use super::fasta;
 
 #[derive(Copy, Clone)]
 pub struct Penalties {
@@ -10,14 +13,98 @@ pub struct Penalties {
     pub gap_extend: u64,
 }
 
+// Which implementation of wavefront alignment to run
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AlignerBackend {
+    Native,
+    Libwfa,
+}
+
+// What to do with the PAF: filter its lines, replace their CIGARs, score them
+// or select them with an expression
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Mode {
+    Filter,
+    Realign,
+    Rescore,
+    Select,
+}
+
+// Keep the best line on each query region, or on each query and target region
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BestHits {
+    Query,
+    OneToOne,
+}
+
+// What becomes of a PAF line that disagrees with itself or the FASTA
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Validation {
+    Warn,
+    Skip,
+    Abort,
+}
+
+// Keep lines between sequences of any samples, of the same PanSN sample or of
+// different ones
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SamplePairs {
+    All,
+    Same,
+    Different,
+}
+
+// What best hits are ranked by
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BestBy {
+    Identity,
+    Support,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ReportFormat {
+    Tsv,
+    Json,
+}
+
+#[derive(Clone)]
 pub struct CliArgs {
+    pub mode: Mode,
     pub verbosity_level: u8,
     pub input_paf: String,
-    pub target_fasta: String,
-    pub query_fasta: String,
+    pub target_fasta: Vec<String>,
+    pub query_fasta: Vec<String>,
     pub penalties: Penalties,
     pub adapt: bool,
+    pub packed: bool,
+    pub faidx: bool,
+    pub aliases: fasta::Aliases,
+    pub validation: Validation,
     pub generate_alignment_tsv: bool,
+    pub edit_distance: bool,
+    pub verify: bool,
+    pub aligner: AlignerBackend,
+    pub per_alignment: bool,
+    pub min_support_fraction: f64,
+    pub min_supported_bases: u64,
+    pub report: Option<String>,
+    pub report_format: ReportFormat,
+    pub genome_report: Option<String>,
+    pub rejected: Option<String>,
+    pub invert: bool,
+    pub annotate: bool,
+    pub add_tags: bool,
+    pub trim: bool,
+    pub min_identity: f64,
+    pub min_gap_compressed_identity: f64,
+    pub max_indel_events: Option<u64>,
+    pub max_score: Option<u64>,
+    pub expr: Option<expr::Expr>,
+    pub sample_pairs: SamplePairs,
+    pub best_hits: Option<BestHits>,
+    pub best_by: BestBy,
+    pub best_overlap: f64,
+    pub threads: usize,
     pub start_time: DateTime<Local>,
 }
 
@@ -43,16 +130,51 @@ impl CliArgs {
         };
 
         CliArgs {
+            mode: Mode::Filter,
             verbosity_level,
             input_paf: String::from(paf_filepath),
-            target_fasta: String::from(target_filepath),
-            query_fasta: String::from(query_filepath),
+            target_fasta: vec![String::from(target_filepath)],
+            query_fasta: vec![String::from(query_filepath)],
             penalties,
             adapt,
+            packed: false,
+            faidx: false,
+            aliases: fasta::Aliases::new(),
+            validation: Validation::Warn,
             generate_alignment_tsv,
+            edit_distance: false,
+            verify: false,
+            aligner: AlignerBackend::Native,
+            per_alignment: false,
+            min_support_fraction: 0.0,
+            min_supported_bases: 1,
+            report: None,
+            report_format: ReportFormat::Tsv,
+            genome_report: None,
+            rejected: None,
+            invert: false,
+            annotate: false,
+            add_tags: false,
+            trim: false,
+            min_identity: 0.0,
+            min_gap_compressed_identity: 0.0,
+            max_indel_events: None,
+            max_score: None,
+            expr: None,
+            sample_pairs: SamplePairs::All,
+            best_hits: None,
+            best_by: BestBy::Identity,
+            best_overlap: 0.5,
+            threads: 1,
             start_time: Local::now(),
         }
     }
+
+    // The defaults with no input files
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        CliArgs::new(0, "", "", "", None, false, false)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -69,7 +191,7 @@ pub enum SequenceType {
     Query,
 }
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum Strand {
     Forward,
     Reverse,
@@ -10,3 +10,39 @@ pub fn pretty_print_int(i: isize) -> String {
     }
     s
 }
+
+// Keep float tags short
+pub fn round(x: f64) -> f64 {
+    (x * 10_000.0).round() / 10_000.0
+}
+
+// Reverse complement DNA keeping the case, anything other than ACGT becomes N
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|b| match b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' => b'a',
+            b if b.is_ascii_lowercase() => b'n',
+            _ => b'N',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(b"GATTACA"), b"TGTAATC".to_vec());
+        assert_eq!(reverse_complement(b"acgtNR"), b"NNacgt".to_vec());
+        assert!(reverse_complement(b"").is_empty());
+    }
+}
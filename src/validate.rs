@@ -0,0 +1,219 @@
+/*
+Validation
+----------
+
+Every PAF line is checked against itself and the FASTA records it names
+before it is aligned, so that a PAF made against another version of an
+assembly is caught instead of being filtered against the wrong bases.
+
+|Check       |Description                                                |
+|:-----------|:----------------------------------------------------------|
+|coordinates |start is not past end and end is not past the length       |
+|length      |the query and target lengths match the FASTA records       |
+|CIGAR       |the bases the CIGAR consumes match the span of coordinates |
+
+`--validate` picks what becomes of a line that fails:
+
+|Mode  |Description                                              |
+|:-----|:--------------------------------------------------------|
+|warn  |Report it and carry on as if it were valid, the default  |
+|skip  |Report it and do not align it                            |
+|abort |Stop at it                                               |
+
+A skipped line gets no support so filter drops it, and realign writes it
+unchanged. Names that are not in the FASTA are reported before any of this.
+*/
+use std::collections::HashMap;
+
+use super::cigar;
+use super::fasta;
+use super::paf;
+use super::types;
+
+// What is wrong with a line, empty if nothing. A record that is not given is
+// not checked against.
+pub fn check(
+    a: &paf::PafAlignment,
+    target: Option<&fasta::Fasta>,
+    query: Option<&fasta::Fasta>,
+) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    let mut coordinates =
+        |kind: &str, start: u32, end: u32, length: u32, record: Option<&fasta::Fasta>| {
+            if start > end {
+                problems.push(format!("{} start {} is past its end {}", kind, start, end));
+            }
+            if end > length {
+                problems.push(format!(
+                    "{} end {} is past its length {}",
+                    kind, end, length
+                ));
+            }
+            match record {
+                Some(f) if f.length != length as usize => problems.push(format!(
+                    "{} length is {} in the PAF but {} in the FASTA",
+                    kind, length, f.length
+                )),
+                _ => {}
+            }
+        };
+    coordinates("query", a.query_start, a.query_end, a.query_length, query);
+    coordinates(
+        "target",
+        a.target_start,
+        a.target_end,
+        a.target_length,
+        target,
+    );
+
+    if a.cigar.is_empty() {
+        return problems;
+    }
+    let ops = match cigar::parse(&a.cigar) {
+        Ok(ops) => ops,
+        Err(e) => {
+            problems.push(e);
+            return problems;
+        }
+    };
+
+    let span = |consumes: fn(&cigar::CigarOp) -> bool| -> u64 {
+        ops.iter()
+            .filter(|o| consumes(o))
+            .map(|o| o.len as u64)
+            .sum()
+    };
+    let spans = [
+        (
+            "query",
+            span(cigar::CigarOp::consumes_query),
+            a.query_start,
+            a.query_end,
+        ),
+        (
+            "target",
+            span(cigar::CigarOp::consumes_target),
+            a.target_start,
+            a.target_end,
+        ),
+    ];
+    for (kind, cigar_span, start, end) in spans {
+        if start <= end && cigar_span != (end - start) as u64 {
+            problems.push(format!(
+                "CIGAR spans {} {} bases but the coordinates {}",
+                cigar_span,
+                kind,
+                end - start
+            ));
+        }
+    }
+
+    problems
+}
+
+// Check a line against the records it names and report what is wrong as
+// --validate asks. Returns false if the line is to be skipped.
+pub fn validate_line(
+    a: &paf::PafAlignment,
+    targets: &HashMap<&[u8], &fasta::Fasta>,
+    queries: &HashMap<&[u8], &fasta::Fasta>,
+    line_num: usize,
+    cli_args: &types::CliArgs,
+) -> bool {
+    let problems = check(
+        a,
+        targets.get(a.target.as_bytes()).copied(),
+        queries.get(a.query.as_bytes()).copied(),
+    );
+    if problems.is_empty() {
+        return true;
+    }
+
+    let message = format!("PAF line {}: {}", line_num + 1, problems.join(", "));
+    match cli_args.validation {
+        types::Validation::Abort => panic!("[wfilter::validate] {}", message),
+        types::Validation::Skip => eprintln!("[wfilter::validate] skipping {}", message),
+        types::Validation::Warn => eprintln!("[wfilter::validate] {}", message),
+    }
+
+    cli_args.validation != types::Validation::Skip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(line: &str) -> Vec<String> {
+        let fasta_file = fasta::Fasta::from_str(">t\nACGTACGTAC\n>q\nACGTAC\n");
+        check(
+            &paf::PafAlignment::from_str(line),
+            Some(&fasta_file[0]),
+            Some(&fasta_file[1]),
+        )
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(problems("q\t6\t0\t6\t+\tt\t10\t2\t8\t6\t6\t60\tcg:Z:6M").is_empty());
+        assert!(problems("q\t6\t0\t6\t+\tt\t10\t2\t8\t6\t6\t60").is_empty());
+        assert!(problems("q\t6\t0\t5\t-\tt\t10\t2\t8\t5\t6\t60\tcg:Z:3M1D2M").is_empty());
+
+        // a stale PAF, the target was 12 bases long
+        assert_eq!(
+            problems("q\t6\t0\t6\t+\tt\t12\t4\t12\t6\t6\t60"),
+            vec!["target length is 12 in the PAF but 10 in the FASTA"]
+        );
+        assert_eq!(
+            problems("q\t6\t4\t2\t+\tt\t10\t2\t8\t6\t6\t60"),
+            vec!["query start 4 is past its end 2"]
+        );
+        assert_eq!(
+            problems("q\t6\t0\t7\t+\tt\t10\t2\t9\t6\t6\t60\tcg:Z:7M"),
+            vec!["query end 7 is past its length 6"]
+        );
+        assert_eq!(
+            problems("q\t6\t0\t6\t+\tt\t10\t2\t8\t6\t6\t60\tcg:Z:5M1I1M"),
+            vec!["CIGAR spans 7 query bases but the coordinates 6"]
+        );
+        assert_eq!(
+            problems("q\t6\t0\t6\t+\tt\t10\t2\t8\t6\t6\t60\tcg:Z:6Q").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_validate_line() {
+        let fasta_file = fasta::Fasta::from_str(">t\nACGTACGTAC\n>q\nACGTAC\n");
+        let aliases = fasta::Aliases::new();
+        let names = fasta::index_by_name(&fasta_file, &aliases);
+        let valid = paf::PafAlignment::from_str("q\t6\t0\t6\t+\tt\t10\t2\t8\t6\t6\t60");
+        let stale = paf::PafAlignment::from_str("q\t6\t0\t6\t+\tt\t12\t2\t8\t6\t6\t60");
+
+        for validation in [types::Validation::Warn, types::Validation::Skip] {
+            let cli_args = types::CliArgs {
+                validation,
+                ..types::CliArgs::for_tests()
+            };
+            assert!(validate_line(&valid, &names, &names, 0, &cli_args));
+            assert_eq!(
+                validate_line(&stale, &names, &names, 1, &cli_args),
+                validation == types::Validation::Warn
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "PAF line 2: target length is 12")]
+    fn test_abort() {
+        let fasta_file = fasta::Fasta::from_str(">t\nACGTACGTAC\n");
+        let aliases = fasta::Aliases::new();
+        let names = fasta::index_by_name(&fasta_file, &aliases);
+        let stale = paf::PafAlignment::from_str("q\t6\t0\t6\t+\tt\t12\t2\t8\t6\t6\t60");
+        let cli_args = types::CliArgs {
+            validation: types::Validation::Abort,
+            ..types::CliArgs::for_tests()
+        };
+        validate_line(&stale, &names, &names, 1, &cli_args);
+    }
+}
@@ -0,0 +1,274 @@
+/*
+Alignment verification
+----------------------
+
+Replays a CIGAR returned by an aligner against the two sequences it aligned.
+It checks that
+ - the CIGAR consumes exactly the query and the target
+ - M ops are matches and X ops are mismatches
+ - the score recomputed under the penalties is the one the aligner reported
+ - the intervals passed to the backtrace lambda are exactly the runs of M ops
+
+Edit distance alignments can be checked with `edit_distance_penalties`.
+*/
+use std::collections::HashSet;
+
+use super::cigar;
+use super::types;
+
+// A run of matches as reported to the backtrace lambda, (query, target)
+pub type MatchInterval = ((i32, i32), (i32, i32));
+
+// Unit costs, scoring a CIGAR with these gives the edit distance
+pub fn edit_distance_penalties() -> types::Penalties {
+    types::Penalties {
+        mismatch: 1,
+        matches: 0,
+        gap_open: 0,
+        gap_extend: 1,
+    }
+}
+
+fn base(seq: &[u8], i: usize) -> char {
+    seq.get(i).map_or('?', |b| *b as char)
+}
+
+pub fn verify_alignment(
+    text: &[u8],
+    query: &[u8],
+    score: usize,
+    cigar: &str,
+    penalties: &types::Penalties,
+    intervals: &[MatchInterval],
+) -> Result<(), Vec<String>> {
+    let mut diagnostics: Vec<String> = Vec::new();
+
+    let ops = match cigar::parse(cigar) {
+        Ok(ops) => ops,
+        Err(e) => return Err(vec![e]),
+    };
+
+    let mut v: usize = 0; // query
+    let mut h: usize = 0; // text
+    let mut computed_score: u64 = 0;
+    let mut match_runs: Vec<MatchInterval> = Vec::new();
+
+    for (op_index, op) in ops.iter().enumerate() {
+        let len = op.len as usize;
+
+        if op.consumes_query() && v + len > query.len() {
+            diagnostics.push(format!(
+                "op {} ({}{}) at query {} runs past the end of the query (length {})",
+                op_index,
+                op.len,
+                op.op,
+                v,
+                query.len()
+            ));
+            break;
+        }
+
+        if op.consumes_target() && h + len > text.len() {
+            diagnostics.push(format!(
+                "op {} ({}{}) at target {} runs past the end of the target (length {})",
+                op_index,
+                op.len,
+                op.op,
+                h,
+                text.len()
+            ));
+            break;
+        }
+
+        match op.op {
+            'M' | '=' | 'X' => {
+                let expect_match = op.op != 'X';
+                for i in 0..len {
+                    if (query[v + i] == text[h + i]) != expect_match {
+                        diagnostics.push(format!(
+                            "op {} ({}{}) at query {} target {} is a {}: query {} target {}",
+                            op_index,
+                            op.len,
+                            op.op,
+                            v + i,
+                            h + i,
+                            if expect_match { "mismatch" } else { "match" },
+                            base(query, v + i),
+                            base(text, h + i)
+                        ));
+                    }
+                }
+
+                let per_base = if expect_match {
+                    penalties.matches
+                } else {
+                    penalties.mismatch
+                };
+                computed_score += per_base * op.len as u64;
+
+                if expect_match && len > 0 {
+                    let run = ((v as i32, (v + len) as i32), (h as i32, (h + len) as i32));
+                    // adjacent match ops are one run
+                    match match_runs.last_mut() {
+                        Some(((_, qe), (_, te))) if *qe == v as i32 && *te == h as i32 => {
+                            *qe = (run.0).1;
+                            *te = (run.1).1;
+                        }
+                        _ => match_runs.push(run),
+                    }
+                }
+            }
+            'I' | 'D' => {
+                computed_score += penalties.gap_open + penalties.gap_extend * op.len as u64;
+            }
+            _ => unreachable!(),
+        }
+
+        if op.consumes_query() {
+            v += len;
+        }
+        if op.consumes_target() {
+            h += len;
+        }
+    }
+
+    if diagnostics.is_empty() && (v != query.len() || h != text.len()) {
+        diagnostics.push(format!(
+            "CIGAR consumes {} of {} query bases and {} of {} target bases",
+            v,
+            query.len(),
+            h,
+            text.len()
+        ));
+    }
+
+    if computed_score != score as u64 {
+        diagnostics.push(format!(
+            "reported score {} but the CIGAR scores {} under mismatch={} gap_open={} gap_extend={}",
+            score, computed_score, penalties.mismatch, penalties.gap_open, penalties.gap_extend
+        ));
+    }
+
+    // Cross check the intervals the backtrace lambda received
+    for ((qs, qe), (ts, te)) in intervals.iter() {
+        if qs >= qe || ts >= te {
+            diagnostics.push(format!(
+                "empty interval query ({}, {}) target ({}, {}) passed to the backtrace lambda",
+                qs, qe, ts, te
+            ));
+        } else if qe - qs != te - ts {
+            diagnostics.push(format!(
+                "interval query ({}, {}) and target ({}, {}) passed to the backtrace lambda differ in length",
+                qs, qe, ts, te
+            ));
+        }
+    }
+
+    let reported: HashSet<&MatchInterval> = intervals.iter().collect();
+    let expected: HashSet<&MatchInterval> = match_runs.iter().collect();
+
+    let mut missing: Vec<&&MatchInterval> = expected.difference(&reported).collect();
+    missing.sort();
+    missing.iter().for_each(|((qs, qe), (ts, te))| {
+        diagnostics.push(format!(
+            "match run query ({}, {}) target ({}, {}) was not passed to the backtrace lambda",
+            qs, qe, ts, te
+        ))
+    });
+
+    let mut unexpected: Vec<&&MatchInterval> = reported.difference(&expected).collect();
+    unexpected.sort();
+    unexpected.iter().for_each(|((qs, qe), (ts, te))| {
+        diagnostics.push(format!(
+            "interval query ({}, {}) target ({}, {}) passed to the backtrace lambda is not a match run in the CIGAR",
+            qs, qe, ts, te
+        ))
+    });
+
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wflambda::{wfa, wflambda};
+
+    static PENALTIES: types::Penalties = types::Penalties {
+        mismatch: 4,
+        matches: 0,
+        gap_open: 6,
+        gap_extend: 2,
+    };
+
+    #[test]
+    fn test_valid_alignment() {
+        let intervals = vec![((0, 2), (0, 2)), ((3, 6), (3, 6))];
+        let result = verify_alignment(b"GACATA", b"GAGATA", 4, "2M1X3M", &PENALTIES, &intervals);
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn test_detects_discrepancies() {
+        let intervals = vec![((0, 2), (0, 2)), ((3, 6), (3, 6))];
+
+        // X that is a match and M that is a mismatch
+        let result = verify_alignment(b"GACATA", b"GAGATA", 4, "1X1M1M3M", &PENALTIES, &intervals);
+        assert!(result.is_err());
+
+        // lengths that do not add up
+        let result = verify_alignment(b"GACATA", b"GAGATA", 4, "2M1X2M", &PENALTIES, &intervals);
+        assert!(result.unwrap_err()[0].contains("consumes 5 of 6 query bases"));
+
+        // wrong score
+        let result = verify_alignment(b"GACATA", b"GAGATA", 8, "2M1X3M", &PENALTIES, &intervals);
+        assert!(result.unwrap_err()[0].contains("reported score 8"));
+
+        // empty query interval and a missing match run
+        let intervals = vec![((2, 2), (0, 2)), ((3, 6), (3, 6))];
+        let result = verify_alignment(b"GACATA", b"GAGATA", 4, "2M1X3M", &PENALTIES, &intervals);
+        let diagnostics = result.unwrap_err();
+        assert!(diagnostics.iter().any(|d| d.starts_with("empty interval")));
+        assert!(diagnostics.iter().any(|d| d.contains("was not passed")));
+    }
+
+    #[test]
+    fn test_verify_aligners() {
+        let text = "TCTATACTGCGCGTTTATCTAGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT\
+                    TCTATACTGCGCGTTTGGAGAAATAACTATCAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT";
+        let query = "TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT\
+                     TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT";
+        let cli_args = types::CliArgs::new(0, "", "", "", Some(PENALTIES), false, false);
+
+        let mut intervals: Vec<MatchInterval> = Vec::new();
+        let aln = wfa::wf_align(text.as_bytes(), query.as_bytes(), &cli_args, &mut |q, t| {
+            intervals.push((q, t))
+        });
+        let result = verify_alignment(
+            text.as_bytes(),
+            query.as_bytes(),
+            aln.score,
+            &aln.cigar,
+            &PENALTIES,
+            &intervals,
+        );
+        assert_eq!(result, Ok(()));
+
+        let mut intervals: Vec<MatchInterval> = Vec::new();
+        let aln = wflambda::wf_align(text.as_bytes(), query.as_bytes(), &cli_args, &mut |q, t| {
+            intervals.push((q, t))
+        });
+        let result = verify_alignment(
+            text.as_bytes(),
+            query.as_bytes(),
+            aln.distance,
+            &aln.cigar,
+            &edit_distance_penalties(),
+            &intervals,
+        );
+        assert_eq!(result, Ok(()));
+    }
+}
@@ -1,287 +1,297 @@
-#![allow(dead_code, unused_variables)]
-use std::cmp::max;
-use std::io::{self, Write};
-
-const MISMATCH_SCORE: i32 = -1;
-const GAP_SCORE: i32 = -2;
-const MATCH_SCORE: i32 = 1;
-
-#[allow(dead_code)]
-struct TraceBack {
-    cells: Vec<Cell>,
-    pattern: String,
-    text: String,
-}
-type Cell = (usize, usize);
-#[derive(Debug)]
-struct Score {
-    score: i32,
-    cell: Cell,
+/*
+Gap-affine Needleman-Wunsch (Gotoh)
+-----------------------------------
+
+A quadratic global aligner used as a reference for the wavefront aligners.
+It minimises the same penalties as `wfa`, a gap of length l costs
+gap_open + l * gap_extend.
+
+Three matrices are kept where i indexes the query (v) and j the text (h):
+
+  I[i][j] = min(H[i-1][j] + o + e, I[i-1][j] + e)           consumes query
+  D[i][j] = min(H[i][j-1] + o + e, D[i][j-1] + e)           consumes text
+  H[i][j] = min(H[i-1][j-1] + match_or_mismatch, I[i][j], D[i][j])
+*/
+// the reference aligner is only used in tests
+#![allow(dead_code)]
+
+use super::super::types::Penalties;
+use super::wfa::types::Alignment;
+use super::wfa::utils::run_length_encode;
+
+// Large enough to never be chosen but still safe to add penalties to
+const INF: u64 = u64::MAX / 4;
+
+#[derive(PartialEq, Clone, Copy)]
+enum Matrix {
+    H,
+    I,
+    D,
 }
 
-type AlignmentMatrix = Vec<Vec<i32>>;
+type AlignmentMatrix = Vec<Vec<u64>>;
+
 struct AlignmentMatrixWrapper<'a> {
-    pattern: &'a [u8],
     text: &'a [u8],
-    m: AlignmentMatrix,
-    score: Option<Score>,
+    query: &'a [u8],
+    penalties: Penalties,
+    h: AlignmentMatrix,
+    i: AlignmentMatrix,
+    d: AlignmentMatrix,
 }
 
 impl<'a> AlignmentMatrixWrapper<'a> {
-    fn init(text: &'a str, pattern: &'a str) -> Self {
-        // initialize a text_length by pattern_length matrix
-        // row are text and columns are pattern
-        // v is query, h is target. M(h,v)
-
-        /*
-
-             Matrix
-
-                      0 <--------- pattern (j) -------------> max(text_length+1, text_length+1)
-
-                 --------------------------------------------
-                 | 0 |  |  |  |  |  |  |  |  |  |  |  |  |  |
-             ^   --------------------------------------------
-             |   |   |  |  |  |  |  |  |  |  |  |  |  |  |  |
-                 --------------------------------------------
-             t   |   |  |  |  |  |  |  |  |  |  |  |  |  |  |
-             e   --------------------------------------------
-             x   |   |  |  |  |  |  |  |  |  |  |  |  |  |  |
-             t   --------------------------------------------
-                 |   |  |  |  |  |  |  |  |  |  |  |  |  |  |
-            (i)  --------------------------------------------
-             |   |   |  |  |  |  |  |  |  |  |  |  |  |  |  |
-             |   --------------------------------------------
-             |   |   |  |  |  |  |  |  |  |  |  |  |  |  |  |
-             |   --------------------------------------------
-
-        max(text_length+1, text_length+1)
-
-              */
-
-        // add extra col and row at the top and left for init
-        let text_length = text.len() + 1;
-        let pattern_length = pattern.len() + 1;
-
-        let v = Vec::with_capacity(pattern_length);
-        let mut m = vec![v; max(text_length, pattern_length)]; // h is implicit here
-
-        // init the M(0,0) to 0
-        m[0].insert(0, 0);
-
-        // init top row and left most column to save time
-        // init top row
-        for i in 1..max(pattern_length, text_length) {
-            let left_cell = m[0][i - 1];
-            m.get_mut(0).unwrap().insert(i, left_cell + GAP_SCORE);
+    fn init(text: &'a [u8], query: &'a [u8], penalties: &Penalties) -> Self {
+        // add an extra row and column at the top and left for init
+        let rows = query.len() + 1;
+        let cols = text.len() + 1;
+
+        let o = penalties.gap_open;
+        let e = penalties.gap_extend;
+
+        let mut h = vec![vec![INF; cols]; rows];
+        let mut i = vec![vec![INF; cols]; rows];
+        let mut d = vec![vec![INF; cols]; rows];
+
+        h[0][0] = 0;
+
+        // left most column, a leading insertion
+        for r in 1..rows {
+            i[r][0] = o + e * r as u64;
+            h[r][0] = i[r][0];
         }
 
-        // init left most column
-        for j in 1..max(pattern_length, text_length) {
-            let cell_above = m[j - 1][0];
-            m.get_mut(j).unwrap().insert(0, cell_above + GAP_SCORE);
+        // top row, a leading deletion
+        for c in 1..cols {
+            d[0][c] = o + e * c as u64;
+            h[0][c] = d[0][c];
         }
 
         Self {
-            pattern: &pattern.as_bytes()[..],
-            text: &text.as_bytes()[..],
-            m,
-            score: None,
+            text,
+            query,
+            penalties: *penalties,
+            h,
+            i,
+            d,
         }
     }
 
-    fn get_score(&self) -> i32 {
-        self.score.as_ref().unwrap().score
-    }
-}
-
-/*
-Align
------
-
-Max(
-  M[i-1, j-1] + match_or_mismatch(M[i,j]),
-  M[i-1, j] + gap_penalty,                  cell to the left
-  M[i, j-1] + gap_penalty,                  cell above
-)
- */
-fn align(matrix_wrapper: &mut AlignmentMatrixWrapper) {
-    let mut max_score = Score {
-        score: 0,
-        cell: (0, 0),
-    };
-
-    let text: &[u8] = matrix_wrapper.text;
-    let pattern: &[u8] = matrix_wrapper.pattern;
-    let aln: &mut AlignmentMatrix = &mut matrix_wrapper.m;
-
-    // Start from M[1,1] because top row and left most columns are full
-    let match_or_mismatch = |i: usize, j: usize| -> i32 {
-        let i = match text.get(i) {
-            Some(v) => v,
-            _ => return MISMATCH_SCORE,
-        };
-        let j = match pattern.get(j) {
-            Some(h) => h,
-            _ => return MISMATCH_SCORE,
-        };
-
-        if i == j {
-            MATCH_SCORE
+    fn match_or_mismatch(&self, r: usize, c: usize) -> u64 {
+        if self.query[r - 1] == self.text[c - 1] {
+            self.penalties.matches
         } else {
-            MISMATCH_SCORE
-        }
-    };
-
-    // does this fill the entire matrix?
-    let h = aln.len();
-    let v = aln[0].len();
-    for i in 1..h {
-        for j in 1..v {
-            let cell_above = aln[i - 1][j];
-            let left_cell = aln[i][j - 1];
-            let prev_diagonal = aln[i - 1][j - 1];
-
-            let best_score = max(
-                prev_diagonal + match_or_mismatch(i - 1, j - 1),
-                max(left_cell + GAP_SCORE, cell_above + GAP_SCORE),
-            );
-            aln.get_mut(i).unwrap().insert(j, best_score);
-
-            if best_score > max_score.score {
-                max_score = Score {
-                    score: best_score,
-                    cell: (i, j),
-                };
-            }
+            self.penalties.mismatch
         }
     }
 
-    matrix_wrapper.score = Some(max_score);
+    fn get_score(&self) -> u64 {
+        self.h[self.query.len()][self.text.len()]
+    }
 }
 
-fn compute_traceback(matrix_wrapper: &AlignmentMatrixWrapper) -> TraceBack {
-    let matrix = &matrix_wrapper.m;
-    let pattern = matrix_wrapper.pattern;
-    let text = matrix_wrapper.text;
-    let mut h = matrix_wrapper.text.len();
-    let mut v = matrix_wrapper.pattern.len();
-    let mut traceback: Vec<Cell> = Vec::with_capacity(max(v, h));
-
-    let mut h_s = String::new();
-    let mut v_s = String::new();
-
-    loop {
-        traceback.insert(0, (h, v));
-
-        if h == 0 && v == 0 {
-            break;
+fn align(matrix_wrapper: &mut AlignmentMatrixWrapper) {
+    let o = matrix_wrapper.penalties.gap_open;
+    let e = matrix_wrapper.penalties.gap_extend;
+
+    for r in 1..=matrix_wrapper.query.len() {
+        for c in 1..=matrix_wrapper.text.len() {
+            let ins = (matrix_wrapper.h[r - 1][c] + o + e).min(matrix_wrapper.i[r - 1][c] + e);
+            let del = (matrix_wrapper.h[r][c - 1] + o + e).min(matrix_wrapper.d[r][c - 1] + e);
+            let sub = matrix_wrapper.h[r - 1][c - 1] + matrix_wrapper.match_or_mismatch(r, c);
+
+            matrix_wrapper.i[r][c] = ins;
+            matrix_wrapper.d[r][c] = del;
+            matrix_wrapper.h[r][c] = sub.min(ins).min(del);
         }
-
-        let cell_above = if h == 0 { i32::MIN } else { matrix[h - 1][v] };
-        let left_cell = if v == 0 { i32::MIN } else { matrix[h][v - 1] };
-        let prev_diagonal = matrix[h - 1][v - 1];
-
-        let lowest = max(prev_diagonal, max(left_cell, cell_above));
-
-        if lowest == cell_above {
-            v_s.push(pattern[v - 1] as char);
-            h_s.push('-');
-
-            h = h - 1;
-        } else if lowest == left_cell {
-            v_s.push('-');
-            h_s.push(text[h - 1] as char);
-
-            v = v - 1;
-        } else {
-            h_s.push(text[h - 1] as char);
-            v_s.push(pattern[v - 1] as char);
-            h = h - 1;
-            v = v - 1;
-        };
     }
+}
 
-    h_s = h_s.chars().rev().collect();
-    v_s = v_s.chars().rev().collect();
-
-    traceback.shrink_to_fit(); // TODO: Is this necessary?
-    TraceBack {
-        pattern: v_s,
-        text: h_s,
-        cells: traceback,
+// Walk back from (n,m) and build the CIGAR in reverse
+fn compute_traceback(matrix_wrapper: &AlignmentMatrixWrapper) -> String {
+    let o = matrix_wrapper.penalties.gap_open;
+    let e = matrix_wrapper.penalties.gap_extend;
+    let h = &matrix_wrapper.h;
+    let i = &matrix_wrapper.i;
+    let d = &matrix_wrapper.d;
+
+    let mut r = matrix_wrapper.query.len();
+    let mut c = matrix_wrapper.text.len();
+    let mut matrix = Matrix::H;
+    let mut cigar = String::with_capacity(r + c);
+
+    while r > 0 || c > 0 {
+        match matrix {
+            Matrix::H => {
+                if r > 0
+                    && c > 0
+                    && h[r][c] == h[r - 1][c - 1] + matrix_wrapper.match_or_mismatch(r, c)
+                {
+                    let same = matrix_wrapper.query[r - 1] == matrix_wrapper.text[c - 1];
+                    cigar.push(if same { 'M' } else { 'X' });
+                    r -= 1;
+                    c -= 1;
+                } else if r > 0 && h[r][c] == i[r][c] {
+                    matrix = Matrix::I;
+                } else {
+                    matrix = Matrix::D;
+                }
+            }
+            Matrix::I => {
+                cigar.push('I');
+                // stay in the gap only if it was extended into this cell
+                if r == 1 || i[r][c] != i[r - 1][c] + e || i[r][c] == h[r - 1][c] + o + e {
+                    matrix = Matrix::H;
+                }
+                r -= 1;
+            }
+            Matrix::D => {
+                cigar.push('D');
+                if c == 1 || d[r][c] != d[r][c - 1] + e || d[r][c] == h[r][c - 1] + o + e {
+                    matrix = Matrix::H;
+                }
+                c -= 1;
+            }
+        }
     }
-}
 
-fn print_alignment(traceback: &TraceBack) {
-    println!("{}", traceback.text);
-    traceback
-        .text
-        .chars()
-        .zip(traceback.pattern.chars())
-        .for_each(|(t, p)| if t == p { print!("|") } else { print!(" ") });
-    print!("\n");
-    io::stdout().flush().unwrap();
-
-    println!("{}", traceback.pattern);
-    println!("");
+    cigar
 }
 
-fn needleman_wunsch(text: &str, pattern: &str) {
-    let mut aln: &mut AlignmentMatrixWrapper = &mut AlignmentMatrixWrapper::init(text, pattern);
+/// Optimal global alignment of `query` against `text` under `penalties`.
+pub fn needleman_wunsch(text: &[u8], query: &[u8], penalties: &Penalties) -> Alignment {
+    let mut aln = AlignmentMatrixWrapper::init(text, query, penalties);
     align(&mut aln);
-    let tr = compute_traceback(&aln);
-    print_alignment(&tr);
-}
 
-pub fn compute_alginment_score(text: &str, pattern: &str) -> i32 {
-    let mut aln: &mut AlignmentMatrixWrapper = &mut AlignmentMatrixWrapper::init(text, pattern);
-    align(&mut aln);
+    let cigar = compute_traceback(&aln);
+    let cigar = if cigar.is_empty() {
+        cigar
+    } else {
+        run_length_encode(&cigar[..], true)
+    };
 
-    aln.get_score()
+    Alignment {
+        score: aln.get_score() as usize,
+        cigar,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const TEXT: &'static str = "TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT\
-                                TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT";
+    static PENALTIES: Penalties = Penalties {
+        mismatch: 4,
+        matches: 0,
+        gap_open: 6,
+        gap_extend: 2,
+    };
 
-    const PATTERN: &'static str = "TCTATACTGCGCGTTTGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAAAAT\
-                                   AGTTCTATACTGCGCGTTTGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAA\
-                                   AATAGT";
+    // Score a CIGAR under the penalties checking it against both sequences
+    fn score_cigar(text: &[u8], query: &[u8], cigar: &str, penalties: &Penalties) -> u64 {
+        let mut v = 0;
+        let mut h = 0;
+        let mut score = 0;
+        let mut count = String::new();
+
+        for c in cigar.chars() {
+            if c.is_ascii_digit() {
+                count.push(c);
+                continue;
+            }
 
-    #[ignore]
-    #[test]
-    fn test_align_equal_length() {
-        let text_window = &TEXT[..12];
-        let pattern_window = &PATTERN[..12];
-        assert_eq!(needleman_wunsch(text_window, pattern_window), ());
+            let l: usize = count.parse().unwrap();
+            count.clear();
+            match c {
+                'M' | 'X' => {
+                    for _ in 0..l {
+                        assert_eq!(c == 'M', query[v] == text[h]);
+                        score += if c == 'M' {
+                            penalties.matches
+                        } else {
+                            penalties.mismatch
+                        };
+                        v += 1;
+                        h += 1;
+                    }
+                }
+                'I' => {
+                    score += penalties.gap_open + penalties.gap_extend * l as u64;
+                    v += l;
+                }
+                'D' => {
+                    score += penalties.gap_open + penalties.gap_extend * l as u64;
+                    h += l;
+                }
+                _ => panic!("unexpected op {}", c),
+            }
+        }
 
-        let text_window = &TEXT[..12];
-        let pattern_window = &PATTERN[12..24];
-        assert_eq!(needleman_wunsch(text_window, pattern_window), ());
+        assert_eq!(v, query.len());
+        assert_eq!(h, text.len());
+        score
+    }
 
-        let text = &TEXT[..];
-        let pattern = &PATTERN[..];
-        let mut aln: &mut AlignmentMatrixWrapper = &mut AlignmentMatrixWrapper::init(text, pattern);
-        align(&mut aln);
+    #[test]
+    fn test_same_sequence() {
+        let aln = needleman_wunsch(b"GAGATA", b"GAGATA", &PENALTIES);
+        assert_eq!(aln.score, 0);
+        assert_eq!(aln.cigar, "6M");
+    }
 
-        assert_eq!(aln.get_score(), 92);
+    #[test]
+    fn test_paper_example() {
+        let aln = needleman_wunsch(b"GATACA", b"GAGATA", &PENALTIES);
+        assert_eq!(aln.score, 8);
+        assert_eq!(aln.cigar, "2M1X1M1X1M");
     }
 
-    #[ignore]
     #[test]
-    fn test_align_varying_length() {
-        let text_window = &TEXT[..14];
-        let pattern_window = &PATTERN[..18];
-        assert_eq!(needleman_wunsch(text_window, pattern_window), ());
+    fn test_affine_gap() {
+        // one gap of 3 is cheaper than three gaps of 1
+        let aln = needleman_wunsch(b"ACGTTTACGT", b"ACGACGT", &PENALTIES);
+        assert_eq!(aln.score, 12);
+        assert_eq!(
+            score_cigar(b"ACGTTTACGT", b"ACGACGT", &aln.cigar, &PENALTIES),
+            12
+        );
+
+        let aln = needleman_wunsch(b"ACGACGT", b"ACGTTTACGT", &PENALTIES);
+        assert_eq!(aln.score, 12);
+        assert_eq!(
+            score_cigar(b"ACGACGT", b"ACGTTTACGT", &aln.cigar, &PENALTIES),
+            12
+        );
+    }
 
-        let text_window = &TEXT[..18];
-        let pattern_window = &PATTERN[..14];
-        assert_eq!(needleman_wunsch(text_window, pattern_window), ());
+    #[test]
+    fn test_empty_sequences() {
+        let aln = needleman_wunsch(b"", b"ACG", &PENALTIES);
+        assert_eq!(aln.score, 12);
+        assert_eq!(aln.cigar, "3I");
+
+        let aln = needleman_wunsch(b"ACG", b"", &PENALTIES);
+        assert_eq!(aln.score, 12);
+        assert_eq!(aln.cigar, "3D");
+
+        let aln = needleman_wunsch(b"", b"", &PENALTIES);
+        assert_eq!(aln.score, 0);
+        assert_eq!(aln.cigar, "");
+    }
+
+    #[test]
+    fn test_cigar_matches_score() {
+        let text = "TCTATACTGCGCGTTTATCTAGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT";
+        let query = "TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT";
+        let penalties = Penalties {
+            mismatch: 3,
+            matches: 0,
+            gap_open: 5,
+            gap_extend: 1,
+        };
 
-        assert_eq!(needleman_wunsch(TEXT, PATTERN), ());
+        let aln = needleman_wunsch(text.as_bytes(), query.as_bytes(), &penalties);
+        let score = score_cigar(text.as_bytes(), query.as_bytes(), &aln.cigar, &penalties);
+        assert_eq!(aln.score as u64, score);
     }
 }
@@ -1,89 +1,86 @@
-#![allow(dead_code, unused_variables)]
 use self::backtrace_utils::*;
 use self::types::*;
 use self::utils::*;
-use std::cmp::max;
 
 use super::super::types::CliArgs;
 
 use indicatif::{ProgressBar, ProgressStyle};
+use scoped_threadpool::Pool;
 
-const VERBOSITY_LEVEL: usize = 2;
-const NULL_OFFSET: isize = -10;
-const MIN_WAVEFRONT_LENGTH: isize = 10;
-const MAX_DISTANCE_THRESHOLD: isize = 50;
+pub const MIN_WAVEFRONT_LENGTH: isize = 10;
+pub const MAX_DISTANCE_THRESHOLD: isize = 50;
+// Wavefronts with fewer diagonals are computed and extended on one thread
+pub const MIN_PARALLEL_WAVEFRONT_LENGTH: isize = 4096;
 
-/*
-k = (v-h) + (qlen-1)
- */
-#[macro_use]
-mod macros {
-    macro_rules! abs {
-        ($x: expr) => {{
-            if $x >= 0 {
-                $x
-            } else {
-                -$x
-            }
-        }};
+/// The text or query of an alignment, a byte slice or a packed sequence
+pub trait Sequence: Sync {
+    fn len(&self) -> usize;
+
+    fn base(&self, i: usize) -> u8;
+
+    /// Length of the common prefix of self[i..] and other[j..]
+    fn match_length(&self, i: usize, other: &Self, j: usize) -> usize;
+}
+
+impl Sequence for [u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
     }
 
-    #[allow(unused_macros)]
-    macro_rules! v {
-        ($offset: expr, $k: expr, $a_k: expr) => {{
-            if $k <= $a_k {
-                $offset
-            } else {
-                $offset + abs!($k - a_k)
-            }
-        }};
+    fn base(&self, i: usize) -> u8 {
+        self[i]
     }
 
-    #[allow(unused_macros)]
-    macro_rules! h {
-        ($offset: expr, $k: expr, $a_k: expr) => {{
-            if $k <= $a_k {
-                $offset + abs!($k - a_k)
-            } else {
-                $offset
-            }
-        }};
+    fn match_length(&self, i: usize, other: &Self, j: usize) -> usize {
+        utils::match_length(&self[i..], &other[j..])
     }
 }
 
-mod utils {
-    use super::types::*;
+/*
+Diagonals are k = h - v where v indexes the query and h indexes the text.
+The offset stored on a diagonal is h, the furthest reaching position in the
+text, therefore v = offset - k.
 
-    pub fn compute_k(k: isize, central_diagonal: usize) -> usize {
-        (k + central_diagonal as isize) as usize
-    }
+The alignment starts at (0,0) on diagonal 0 and ends at (qlen,tlen) on
+diagonal tlen - qlen with offset tlen.
 
-    pub fn compute_kk(k: usize, central_diagonal: usize) -> isize {
-        k as isize - central_diagonal as isize
-    }
+For a score s and penalties x (mismatch), o (gap open) and e (gap extend)
+  I[s][k] = max(M[s-o-e][k+1], I[s-e][k+1])          consumes query
+  D[s][k] = max(M[s-o-e][k-1], D[s-e][k-1]) + 1      consumes text
+  M[s][k] = max(M[s-x][k] + 1, I[s][k], D[s][k])     then extend matches
+ */
+pub mod utils {
+    use super::types::*;
+    use std::convert::TryInto;
 
-    pub fn v(offset: Offset, diagonal: usize, central_diagonal: usize) -> Offset {
-        if diagonal <= central_diagonal {
-            offset
-        } else {
-            offset + abs!(diagonal as isize - central_diagonal as isize) as usize
-        }
+    pub fn compute_v(offset: Offset, k: isize) -> Offset {
+        offset - k
     }
 
-    pub fn h(offset: Offset, diagonal: usize, central_diagonal: usize) -> Offset {
-        if diagonal <= central_diagonal {
-            offset + abs!(diagonal as isize - central_diagonal as isize) as usize
-        } else {
-            offset
-        }
+    pub fn compute_h(offset: Offset, _k: isize) -> Offset {
+        offset
     }
 
-    pub fn compute_v(offset: Offset, diagonal: usize, central_diagonal: usize) -> Offset {
-        v(offset, diagonal, central_diagonal)
-    }
+    // Length of the common prefix of a and b, compared 8 bytes at a time.
+    // The first differing byte is the lowest set byte of the XOR of two words.
+    pub fn match_length(a: &[u8], b: &[u8]) -> usize {
+        let len = std::cmp::min(a.len(), b.len());
+        let word = |s: &[u8], i: usize| u64::from_le_bytes(s[i..i + 8].try_into().unwrap());
+
+        let mut i = 0;
+        while i + 8 <= len {
+            let diff = word(a, i) ^ word(b, i);
+            if diff != 0 {
+                return i + (diff.trailing_zeros() / 8) as usize;
+            }
+            i += 8;
+        }
 
-    pub fn compute_h(offset: Offset, diagonal: usize, central_diagonal: usize) -> Offset {
-        h(offset, diagonal, central_diagonal)
+        i + a[i..len]
+            .iter()
+            .zip(&b[i..len])
+            .take_while(|(x, y)| x == y)
+            .count()
     }
 
     // just make the cigar proper
@@ -96,7 +93,7 @@ mod utils {
         let mut xcigar = String::new();
 
         // edge cases
-        if cigar.len() == 0 {
+        if cigar.is_empty() {
             panic!("[wfa::utils::run_length_encode] empty cigar");
         } else if cigar.len() == 1 {
             xcigar.push_str(&format!("{}{}", 1, cigar));
@@ -126,15 +123,19 @@ mod utils {
 
 pub mod types {
     use super::super::super::types::Penalties;
-    use std::cmp::max;
+    use super::utils::*;
+    use super::Sequence;
 
     use indicatif::{ProgressBar, ProgressStyle};
     use std::fs::OpenOptions;
     use std::io::prelude::*;
 
-    pub type Offset = usize;
+    pub type Offset = isize;
     pub type DpMatrix = Vec<Vec<Option<usize>>>;
 
+    // An offset no diagonal ever reaches
+    pub const NULL_OFFSET: Offset = isize::MIN / 2;
+
     pub struct Alignment {
         pub score: usize,
         pub cigar: String,
@@ -149,20 +150,35 @@ pub mod types {
 
     #[derive(Debug)]
     pub struct Wavefront {
-        // TODO: make usize?
+        // effective highest and lowest diagonals (inclusive)
         pub hi: isize,
         pub lo: isize,
+        // diagonal of offsets[0], reduction moves lo and hi but not this
+        pub lo_base: isize,
         pub offsets: Vec<Offset>,
     }
 
     impl Wavefront {
-        pub fn new(offset_len: usize) -> Self {
+        pub fn new(lo: isize, hi: isize) -> Self {
             Self {
-                hi: 0,
-                lo: 0,
-                offsets: vec![0; offset_len],
+                hi,
+                lo,
+                lo_base: lo,
+                offsets: vec![NULL_OFFSET; (hi - lo + 1) as usize],
             }
         }
+
+        pub fn get(&self, k: isize) -> Offset {
+            if self.lo <= k && k <= self.hi {
+                self.offsets[(k - self.lo_base) as usize]
+            } else {
+                NULL_OFFSET
+            }
+        }
+
+        pub fn set(&mut self, k: isize, offset: Offset) {
+            self.offsets[(k - self.lo_base) as usize] = offset;
+        }
     }
 
     pub struct WavefrontSet {
@@ -172,11 +188,11 @@ pub mod types {
     }
 
     impl WavefrontSet {
-        pub fn new(offset_len: usize) -> Self {
+        pub fn new(lo: isize, hi: isize) -> Self {
             Self {
-                m: Some(Wavefront::new(offset_len)),
-                d: Some(Wavefront::new(offset_len)),
-                i: Some(Wavefront::new(offset_len)),
+                m: Some(Wavefront::new(lo, hi)),
+                d: Some(Wavefront::new(lo, hi)),
+                i: Some(Wavefront::new(lo, hi)),
             }
         }
 
@@ -203,71 +219,42 @@ pub mod types {
         pub fn mwavefront_mut(&mut self) -> Option<&mut Wavefront> {
             self.m.as_mut()
         }
-
-        pub fn fields(&self) -> (Option<&Wavefront>, Option<&Wavefront>, Option<&Wavefront>) {
-            (self.m.as_ref(), self.i.as_ref(), self.d.as_ref())
-        }
-
-        pub fn fields_mut(
-            &mut self,
-        ) -> (
-            Option<&mut Wavefront>,
-            Option<&mut Wavefront>,
-            Option<&mut Wavefront>,
-        ) {
-            (self.m.as_mut(), self.i.as_mut(), self.d.as_mut())
-        }
     }
 
     pub type OptWavefrontSet = Option<WavefrontSet>;
     pub type BoxedWavefront = Box<OptWavefrontSet>;
 
-    pub struct Wavefronts<'a> {
-        pub query: &'a [u8],
-        pub text: &'a [u8],
+    pub struct Wavefronts {
+        pub qlen: usize,
+        pub tlen: usize,
 
+        // one (possibly empty) wavefront set per score
         pub wavefronts: Vec<BoxedWavefront>,
 
-        pub diagonals: usize,
-
-        pub central_diagonal: usize, // k_zero
-        // max_diagonal: usize,
-        pub min_diagonal: isize,
-        pub a_offset: usize,
-
         pub penalties: Penalties,
 
-        pub dp_matrix: Vec<Vec<Option<usize>>>,
+        // only allocated when the DP table is printed
+        pub dp_matrix: DpMatrix,
     }
 
-    impl<'a> Wavefronts<'a> {
-        pub fn new(query: &'a [u8], text: &'a [u8], penalties: Penalties) -> Self {
-            let qlen = query.len();
-            let tlen = text.len();
-            let diagonals = qlen + tlen - 1;
-
-            let mut wavefronts = Vec::new();
-            let x = Box::new(Some(WavefrontSet::new(diagonals)));
-            wavefronts.push(x);
-            // let mut wavefronts = Vec::with_capacity(diagonals);
-            // (0..=diagonals).for_each(|_| {
-            //    wavefronts.push(WavefrontSet::new(diagonals));
-            // });
+    impl Wavefronts {
+        pub fn new(qlen: usize, tlen: usize, penalties: Penalties, track: bool) -> Self {
+            // score 0 is a single point at (0,0)
+            let mut wavefront_set = WavefrontSet::new(0, 0);
+            wavefront_set.mwavefront_mut().unwrap().set(0, 0);
+            let wavefronts = vec![Box::new(Some(wavefront_set))];
 
-            let dp_matrix: Vec<Vec<Option<usize>>> = vec![vec![None; qlen]; tlen];
-
-            let a_offset = max(qlen, tlen);
+            let dp_matrix: DpMatrix = if track {
+                vec![vec![None; qlen]; tlen]
+            } else {
+                Vec::new()
+            };
 
             Self {
-                query,
-                text,
+                qlen,
+                tlen,
                 wavefronts,
-                diagonals,
                 penalties,
-                central_diagonal: qlen - 1,
-                // max_diagonal: diagonals - 2,
-                min_diagonal: -(qlen as isize - 1),
-                a_offset,
                 dp_matrix,
             }
         }
@@ -280,27 +267,77 @@ pub mod types {
             self.wavefronts.get_mut(score).and_then(|x| (**x).as_mut())
         }
 
-        pub fn print(&self) {
+        // wavefront set for a score that may be negative
+        pub fn get_wavefront_at(&self, score: isize) -> Option<&WavefrontSet> {
+            if score < 0 {
+                None
+            } else {
+                self.get_wavefront(score as usize)
+            }
+        }
+
+        pub fn moffset(&self, score: isize, k: isize) -> Offset {
+            self.get_wavefront_at(score)
+                .and_then(|w| w.mwavefront())
+                .map_or(NULL_OFFSET, |w| w.get(k))
+        }
+
+        pub fn ioffset(&self, score: isize, k: isize) -> Offset {
+            self.get_wavefront_at(score)
+                .and_then(|w| w.iwavefront())
+                .map_or(NULL_OFFSET, |w| w.get(k))
+        }
+
+        pub fn doffset(&self, score: isize, k: isize) -> Offset {
+            self.get_wavefront_at(score)
+                .and_then(|w| w.dwavefront())
+                .map_or(NULL_OFFSET, |w| w.get(k))
+        }
+
+        // the diagonal the alignment ends on
+        pub fn target_k(&self) -> isize {
+            self.tlen as isize - self.qlen as isize
+        }
+
+        pub fn target_offset(&self) -> Offset {
+            self.tlen as Offset
+        }
+
+        // drop offsets that fall outside the DP matrix
+        pub fn bound(&self, k: isize, offset: Offset) -> Offset {
+            let v = compute_v(offset, k);
+            let h = compute_h(offset, k);
+
+            if v < 0 || h < 0 || v > self.qlen as isize || h > self.tlen as isize {
+                NULL_OFFSET
+            } else {
+                offset
+            }
+        }
+
+        pub fn print<S: Sequence + ?Sized>(&self, text: &S, query: &S) {
             let dp_matrix = &self.dp_matrix;
-            let query = self.query;
-            let text = self.text;
 
-            // print chars
+            if dp_matrix.is_empty() {
+                return;
+            }
+
+            // print col nums
             eprint!("\t");
             dp_matrix[0].iter().enumerate().for_each(|(j, _)| {
                 eprint!("{}\t", j);
             });
-            eprint!("\n");
+            eprintln!();
 
-            // print col nums
+            // print chars
             eprint!("\t");
             dp_matrix[0].iter().enumerate().for_each(|(j, _)| {
-                eprint!("{}\t", query[j] as char);
+                eprint!("{}\t", query.base(j) as char);
             });
 
-            eprint!("\n");
+            eprintln!();
             dp_matrix.iter().enumerate().for_each(|(i, row)| {
-                eprint!("{} {}\t", i, text[i] as char);
+                eprint!("{} {}\t", i, text.base(i) as char);
                 row.iter().for_each(|col| {
                     match col {
                         Some(o) => {
@@ -312,19 +349,16 @@ pub mod types {
                     }
                     eprint!("\t");
                 });
-                eprint!("\n");
+                eprintln!();
             });
 
             eprint!("\n\n");
         }
 
-        pub fn print_tsv(&self, filename: &str) {
+        pub fn print_tsv<S: Sequence + ?Sized>(&self, filename: &str, text: &S, query: &S) {
             let dp_matrix = &self.dp_matrix;
-            let query = self.query;
-            let text = self.text;
 
             let mut file = OpenOptions::new()
-                .write(true)
                 .append(true)
                 .create(true)
                 .open(filename)
@@ -341,15 +375,19 @@ pub mod types {
 
             dp_matrix.iter().enumerate().for_each(|(i, row)| {
                 progress_bar.inc(1);
-                row.iter().enumerate().for_each(|(j, col)| match col {
-                    Some(score) => {
+                row.iter().enumerate().for_each(|(j, col)| {
+                    if let Some(score) = col {
                         writeln!(
                             file,
                             "{}\t{}\t{}\t{}\t{}",
-                            i, j, score, query[j] as char, text[i] as char
-                        ).unwrap();
+                            i,
+                            j,
+                            score,
+                            query.base(j) as char,
+                            text.base(i) as char
+                        )
+                        .unwrap();
                     }
-                    None => {}
                 });
             });
         }
@@ -360,39 +398,33 @@ mod backtrace_utils {
     use super::types::*;
     use super::utils::*;
 
-    const NULL_OFFSET: isize = -10;
-
     pub fn backtrace_matches_check<T>(
         offset: &mut isize,
         cigar: &mut String,
         num_matches: usize,
         k: isize,
-        central_diagonal: usize,
         backtrace_lambda: &mut T,
     ) where
-        T: FnMut((i32, i32), (i32, i32)) -> (),
+        T: FnMut((i32, i32), (i32, i32)),
     {
-        let k = compute_k(k, central_diagonal);
+        if num_matches == 0 {
+            return;
+        }
 
         {
-            let query_start = compute_v(*offset as usize, k, central_diagonal);
-            let target_start = compute_h(*offset as usize, k, central_diagonal);
+            let query_stop = compute_v(*offset, k);
+            let target_stop = compute_h(*offset, k);
 
-            let query_stop = query_start + num_matches as usize;
-            let target_stop = target_start + num_matches as usize;
+            let query_start = query_stop - num_matches as isize;
+            let target_start = target_stop - num_matches as isize;
 
-            let query = (query_stop as i32, query_stop as i32);
+            let query = (query_start as i32, query_stop as i32);
             let target = (target_start as i32, target_stop as i32);
 
             backtrace_lambda(query, target);
-
-            // println!("({}, {}) ({}, {})", query_start, query_stop, target_start, target_stop);
         }
 
         (0..num_matches).for_each(|_| {
-            let v = compute_v(*offset as usize, k, central_diagonal);
-            let h = compute_h(*offset as usize, k, central_diagonal);
-
             cigar.push('M');
             *offset -= 1;
         });
@@ -403,21 +435,7 @@ mod backtrace_utils {
         score: isize,
         k: isize,
     ) -> isize {
-        if score < 0 {
-            return NULL_OFFSET;
-        }
-
-        let d = wavefronts
-            .get_wavefront(score as usize)
-            .unwrap()
-            .dwavefront()
-            .unwrap();
-        if d.lo <= k + 1 && k + 1 <= d.hi {
-            let k = compute_k(k, wavefronts.central_diagonal);
-            return d.offsets[k + 1] as isize;
-        } else {
-            return NULL_OFFSET;
-        }
+        wavefronts.bound(k, wavefronts.doffset(score, k - 1) + 1)
     }
 
     pub fn backtrace_deletion_open_offset(
@@ -425,22 +443,7 @@ mod backtrace_utils {
         score: isize,
         k: isize,
     ) -> isize {
-        if score < 0 {
-            return NULL_OFFSET;
-        }
-
-        let m = wavefronts
-            .get_wavefront(score as usize)
-            .unwrap()
-            .mwavefront()
-            .unwrap();
-
-        if m.lo <= k + 1 && k + 1 <= m.hi {
-            let k = compute_k(k, wavefronts.central_diagonal);
-            return m.offsets[k + 1] as isize;
-        } else {
-            return NULL_OFFSET;
-        }
+        wavefronts.bound(k, wavefronts.moffset(score, k - 1) + 1)
     }
 
     pub fn backtrace_insertion_extend_offset(
@@ -448,22 +451,7 @@ mod backtrace_utils {
         score: isize,
         k: isize,
     ) -> isize {
-        if score < 0 {
-            return NULL_OFFSET;
-        }
-
-        let i = wavefronts
-            .get_wavefront(score as usize)
-            .unwrap()
-            .iwavefront()
-            .unwrap();
-
-        if i.lo <= k - 1 && k - 1 <= i.hi {
-            let k = compute_k(k, wavefronts.central_diagonal);
-            return i.offsets[k - 1] as isize;
-        } else {
-            return NULL_OFFSET;
-        }
+        wavefronts.bound(k, wavefronts.ioffset(score, k + 1))
     }
 
     pub fn backtrace_insertion_open_offset(
@@ -471,384 +459,292 @@ mod backtrace_utils {
         score: isize,
         k: isize,
     ) -> isize {
-        if score < 0 {
-            return NULL_OFFSET;
-        }
-
-        let m = wavefronts
-            .get_wavefront(score as usize)
-            .unwrap()
-            .mwavefront()
-            .unwrap();
-
-        if m.lo <= k - 1 && k - 1 <= m.hi {
-            let k = compute_k(k, wavefronts.central_diagonal);
-            return m.offsets[k - 1] as isize + 1;
-        } else {
-            return NULL_OFFSET;
-        }
-    }
-
-    pub fn backtrace_insertion_mismatch_offset(
-        wavefronts: &Wavefronts,
-        score: isize,
-        k: isize,
-    ) -> isize {
-        if score < 0 {
-            return NULL_OFFSET;
-        }
-
-        let m = wavefronts
-            .get_wavefront(score as usize)
-            .unwrap()
-            .mwavefront()
-            .unwrap();
-
-        if m.lo <= k && k <= m.hi {
-            let k = compute_k(k, wavefronts.central_diagonal);
-            return m.offsets[k] as isize + 1;
-        } else {
-            return NULL_OFFSET;
-        }
+        wavefronts.bound(k, wavefronts.moffset(score, k + 1))
     }
 
     pub fn backtrace_mismatch_offset(wavefronts: &Wavefronts, score: isize, k: isize) -> isize {
-        if score < 0 {
-            return NULL_OFFSET;
-        }
-
-        let m = wavefronts
-            .get_wavefront(score as usize)
-            .unwrap()
-            .mwavefront()
-            .unwrap();
-
-        if m.lo <= k && k <= m.hi {
-            // eprintln!("\t[backtrace_mismatch_offset] score={} k={} offset={}", score, k, m.offsets[k as usize]);
-            let k = compute_k(k, wavefronts.central_diagonal);
-            return m.offsets[k] as isize + 1;
-        } else {
-            return NULL_OFFSET;
-        }
+        wavefronts.bound(k, wavefronts.moffset(score, k) + 1)
     }
 }
 
+// Adaptive WFA: drop the diagonals that have fallen too far behind the best one
 fn reduce(wavefronts: &mut Wavefronts, score: usize) {
-    let qlen = wavefronts.query.len();
-    let tlen = wavefronts.text.len();
+    let qlen = wavefronts.qlen as isize;
+    let tlen = wavefronts.tlen as isize;
 
-    let a_k = wavefronts.central_diagonal;
-
-    let wavefront: &mut WavefrontSet = wavefronts.get_wavefront_mut(score).unwrap();
+    let wavefront: &mut WavefrontSet = match wavefronts.get_wavefront_mut(score) {
+        Some(w) => w,
+        None => return,
+    };
 
     // fetch the m wavefront
-    let m_wavefront: Option<&Wavefront> = wavefront.m.as_ref();
-    if m_wavefront.is_none() {
-        return;
-    }
-    let m_wavefront: &Wavefront = m_wavefront.unwrap();
-    let hi = m_wavefront.hi;
-    let lo = m_wavefront.lo;
+    let m_wavefront: &mut Wavefront = match wavefront.m.as_mut() {
+        Some(m) => m,
+        None => return,
+    };
 
-    if (hi - lo + 1) < MIN_WAVEFRONT_LENGTH {
+    if (m_wavefront.hi - m_wavefront.lo + 1) < MIN_WAVEFRONT_LENGTH {
         return;
     }
 
+    // distance left to (n,m) from each diagonal
+    let distance = |m_wavefront: &Wavefront, k: isize| -> Option<isize> {
+        let offset = m_wavefront.get(k);
+        if offset == NULL_OFFSET {
+            return None;
+        }
+
+        let left_v = qlen - compute_v(offset, k);
+        let left_h = tlen - compute_h(offset, k);
+        Some(std::cmp::max(left_v, left_h))
+    };
+
     // Find minimum distance to (n,m)
-    let mut min_distance: usize = max(qlen, tlen);
+    let min_distance: isize = match (m_wavefront.lo..=m_wavefront.hi)
+        .filter_map(|k| distance(m_wavefront, k))
+        .min()
+    {
+        Some(d) => d,
+        None => return,
+    };
 
-    for k in lo..hi {
-        let i = k;
-        let k: usize = compute_k(k, a_k);
-        let s = format!("i={} k={}", i, k);
+    let too_far = |m_wavefront: &Wavefront, k: isize| -> bool {
+        match distance(m_wavefront, k) {
+            Some(d) => d - min_distance > MAX_DISTANCE_THRESHOLD,
+            None => true,
+        }
+    };
 
-        let offset: Option<&usize> = m_wavefront.offsets.get(k);
+    // Reduce M from the bottom and the top
+    while m_wavefront.lo < m_wavefront.hi && too_far(m_wavefront, m_wavefront.lo) {
+        m_wavefront.lo += 1;
+    }
+    while m_wavefront.hi > m_wavefront.lo && too_far(m_wavefront, m_wavefront.hi) {
+        m_wavefront.hi -= 1;
+    }
 
-        if offset.is_none() {
-            continue
+    let (lo, hi) = (m_wavefront.lo, m_wavefront.hi);
+
+    // Reduce I and D to the range of M
+    for gap_wavefront in [wavefront.i.as_mut(), wavefront.d.as_mut()]
+        .iter_mut()
+        .flatten()
+    {
+        if lo > gap_wavefront.lo {
+            gap_wavefront.lo = lo
+        }
+        if hi < gap_wavefront.hi {
+            gap_wavefront.hi = hi
         }
+    }
 
-        let offset: usize = *offset.unwrap();
+    if wavefront.i.as_ref().is_some_and(|i| i.lo > i.hi) {
+        wavefront.i = None;
+    }
+    if wavefront.d.as_ref().is_some_and(|d| d.lo > d.hi) {
+        wavefront.d = None;
+    }
+}
 
-        let left_v: usize = if offset >= k && qlen >= (offset - k) {
-            qlen - (offset - k)
-        } else {
-            0
-        };
-        let left_h: usize = if tlen >= offset { tlen - offset } else { 0 };
+// Extend the matches on diagonal k from offset. Given the text and the query
+// they are compared a word at a time, otherwise the lambda is called per base.
+fn extend_diagonal<T, S: Sequence + ?Sized>(
+    k: isize,
+    offset: Offset,
+    match_lambda: &T,
+    sequences: Option<(&S, &S)>,
+    score: usize,
+    dp_matrix: &mut DpMatrix,
+    verbosity: u8,
+) -> Offset
+where
+    T: Fn(usize, usize, usize, usize, &mut DpMatrix) -> bool,
+{
+    let mut v = compute_v(offset, k) as usize;
+    let mut h = compute_h(offset, k) as usize;
 
-        let distance = std::cmp::max(left_v, left_h);
-        min_distance = std::cmp::max(min_distance, distance);
+    if verbosity > 4 {
+        eprintln!("\tpre extend k={} offset={} ({},{})", k, offset, v, h);
     }
 
-    // Reduce WF from the bottom
-    let i_wavefront: Option<&mut Wavefront> = wavefront.i.as_mut();
-    if i_wavefront.is_some() {
-        let i_wavefront_unwrapped: &mut Wavefront = i_wavefront.unwrap();
-        if m_wavefront.lo > i_wavefront_unwrapped.lo {
-            i_wavefront_unwrapped.lo = m_wavefront.lo
-        }
-        if m_wavefront.hi < i_wavefront_unwrapped.hi {
-            i_wavefront_unwrapped.hi = m_wavefront.hi
+    let mut offset = offset;
+    if let Some((text, query)) = sequences {
+        let matches = text.match_length(h, query, v);
+        offset += matches as Offset;
+        v += matches;
+        h += matches;
+    } else {
+        while match_lambda(v, h, offset as usize, score, dp_matrix) {
+            offset += 1;
+            v += 1;
+            h += 1;
         }
-        if i_wavefront_unwrapped.lo > i_wavefront_unwrapped.hi {
-            wavefront.i = None
-        };
     }
 
-    // Reduce WF from the top
-    let d_wavefront: Option<&mut Wavefront> = wavefront.i.as_mut();
-    if d_wavefront.is_some() {
-        let d_wavefront_unwrapped: &mut Wavefront = d_wavefront.unwrap();
-        if m_wavefront.lo > d_wavefront_unwrapped.lo {
-            d_wavefront_unwrapped.lo = m_wavefront.lo
-        }
-        if m_wavefront.hi < d_wavefront_unwrapped.hi {
-            d_wavefront_unwrapped.hi = m_wavefront.hi
-        }
-        if d_wavefront_unwrapped.lo > d_wavefront_unwrapped.hi {
-            wavefront.d = None
-        };
+    if verbosity > 4 {
+        eprintln!("\tpost extend k={} offset={} ({},{})", k, offset, v, h);
+        eprintln!();
     }
+
+    offset
+}
+
+// The pool to split the diagonals lo..=hi over, one chunk per thread, None if
+// they are computed on this thread. Its threads are started the first time a
+// wavefront is wide enough and kept for every score after.
+fn pool_for(
+    pool: &mut Option<Pool>,
+    lo: isize,
+    hi: isize,
+    threads: usize,
+    min_parallel_length: isize,
+) -> Option<&mut Pool> {
+    if threads <= 1 || hi - lo + 1 < min_parallel_length {
+        return None;
+    }
+    Some(pool.get_or_insert_with(|| Pool::new(threads as u32)))
 }
 
-fn wf_extend<T>(
+// The diagonals are independent so given a pool each chunk of them is extended
+// on its own thread. The DP matrix is not filled in then.
+fn wf_extend<T, S: Sequence + ?Sized>(
     mwavefront: &mut Wavefront,
     match_lambda: T,
-    central_diagonal: usize,
+    sequences: Option<(&S, &S)>,
     score: usize,
-    dp_matrix: &mut Vec<Vec<Option<usize>>>,
+    dp_matrix: &mut DpMatrix,
+    pool: Option<&mut Pool>,
     verbosity: u8,
 ) where
-    T: Fn(usize, usize, usize, usize, &mut Vec<Vec<Option<usize>>>) -> bool,
+    T: Fn(usize, usize, usize, usize, &mut DpMatrix) -> bool + Sync,
 {
     let lo = mwavefront.lo;
     let hi = mwavefront.hi;
+    let chunks = pool.as_ref().map_or(1, |p| p.thread_count() as usize);
 
     if verbosity > 2 {
         eprintln!("[wfa::wf_extend] Extending wavefront with score {}", score);
-        eprintln!("\tlo={}, hi={}", lo, hi);
+        eprintln!("\tlo={}, hi={} chunks={}", lo, hi, chunks);
     }
 
-    for k in lo..=hi {
-        let _lo = k;
-        let k: usize = compute_k(k, central_diagonal);
-
-        if k >= mwavefront.offsets.len() {
-            if verbosity > 3 {
-                eprintln!(
-                    "[wfa::wf_extend] k={} is therefore out of scope. Skipping",
-                    k
-                );
+    let pool = match pool {
+        Some(pool) => pool,
+        _ => {
+            for k in lo..=hi {
+                let offset = mwavefront.get(k);
+                if offset != NULL_OFFSET {
+                    let offset = extend_diagonal(
+                        k,
+                        offset,
+                        &match_lambda,
+                        sequences,
+                        score,
+                        dp_matrix,
+                        verbosity,
+                    );
+                    mwavefront.set(k, offset);
+                }
             }
-            continue;
-        }
-        let offset = mwavefront.offsets[k];
-        let mut v: usize = v(offset, k, central_diagonal);
-        let mut h: usize = h(offset, k, central_diagonal);
-
-        if verbosity > 4 {
-            eprintln!("\tk={} offset={}", k, offset);
-            eprintln!("\tpre extend k={} offset={} ({},{})", k, offset, v, h);
+            return;
         }
+    };
 
-        while match_lambda(v, h, mwavefront.offsets[k], score, dp_matrix) {
-            mwavefront.offsets[k] += 1;
-            v += 1;
-            h += 1;
-        }
+    let first = (lo - mwavefront.lo_base) as usize;
+    let width = (hi - lo + 1) as usize;
+    let chunk_len = width.div_ceil(chunks);
+    let match_lambda = &match_lambda;
 
-        if verbosity > 4 {
-            eprintln!(
-                "\tpost extend k={} offset={} ({},{})",
-                k, mwavefront.offsets[k], v, h
-            );
-            eprintln!("");
+    pool.scoped(|scope| {
+        for (i, chunk) in mwavefront.offsets[first..first + width]
+            .chunks_mut(chunk_len)
+            .enumerate()
+        {
+            let chunk_lo = lo + (i * chunk_len) as isize;
+            scope.execute(move || {
+                let mut dp_matrix = DpMatrix::new();
+                for (k, offset) in (chunk_lo..).zip(chunk.iter_mut()) {
+                    if *offset != NULL_OFFSET {
+                        *offset = extend_diagonal(
+                            k,
+                            *offset,
+                            match_lambda,
+                            sequences,
+                            score,
+                            &mut dp_matrix,
+                            verbosity,
+                        );
+                    }
+                }
+            });
         }
-    }
+    });
 }
 
-fn wf_expand(wavefronts: &mut Wavefronts, score: usize) -> (isize, isize) {
+// The range of diagonals the wavefront at score can reach, None if it is empty
+fn wf_expand(wavefronts: &Wavefronts, score: usize) -> Option<(isize, isize)> {
     let s: isize = score as isize;
 
     let x: isize = wavefronts.penalties.mismatch as isize;
     let o: isize = wavefronts.penalties.gap_open as isize;
     let e: isize = wavefronts.penalties.gap_extend as isize;
 
-    let m_hi = wavefronts
-        .get_wavefront(score)
-        .unwrap()
-        .mwavefront()
-        .unwrap()
-        .hi;
-    let i_hi = wavefronts
-        .get_wavefront(score)
-        .unwrap()
-        .iwavefront()
-        .unwrap()
-        .hi;
-    let d_hi = wavefronts
-        .get_wavefront(score)
-        .unwrap()
-        .dwavefront()
-        .unwrap()
-        .hi;
-
-    let m_lo = wavefronts
-        .get_wavefront(score)
-        .unwrap()
-        .mwavefront()
-        .unwrap()
-        .lo;
-    let d_lo = wavefronts
-        .get_wavefront(score)
-        .unwrap()
-        .dwavefront()
-        .unwrap()
-        .lo;
-    let i_lo = wavefronts
-        .get_wavefront(score)
-        .unwrap()
-        .iwavefront()
-        .unwrap()
-        .lo;
-
-    let hi = vec![
-        if s - x < 0 {
-            m_hi
-        } else {
-            wavefronts
-                .get_wavefront((s - x) as usize)
-                .unwrap()
-                .mwavefront()
-                .unwrap()
-                .hi
-        },
-        if s - o - e < 0 {
-            m_hi
-        } else {
-            wavefronts
-                .get_wavefront((s - o - e) as usize)
-                .unwrap()
-                .mwavefront()
-                .unwrap()
-                .hi
-        },
-        if s - e < 0 {
-            i_hi
-        } else {
-            wavefronts
-                .get_wavefront((s - e) as usize)
-                .unwrap()
-                .iwavefront()
-                .unwrap()
-                .hi
-        },
-        if s - e < 0 {
-            d_hi
-        } else {
-            wavefronts
-                .get_wavefront((s - e) as usize)
-                .unwrap()
-                .dwavefront()
-                .unwrap()
-                .hi
-        },
-    ]
-    .iter()
-    .max()
-    .unwrap()
-        + 1;
-
-    let lo = vec![
-        if s - x < 0 {
-            m_lo
-        } else {
-            wavefronts
-                .get_wavefront((s - x) as usize)
-                .unwrap()
-                .mwavefront()
-                .unwrap()
-                .lo
-        },
-        if s - o - e < 0 {
-            m_lo
-        } else {
-            wavefronts
-                .get_wavefront((s - o - e) as usize)
-                .unwrap()
-                .mwavefront()
-                .unwrap()
-                .lo
-        },
-        if s - e < 0 {
-            i_lo
-        } else {
-            wavefronts
-                .get_wavefront((s - e) as usize)
-                .unwrap()
-                .iwavefront()
-                .unwrap()
-                .lo
-        },
-        if s - e < 0 {
-            d_lo
-        } else {
-            wavefronts
-                .get_wavefront((s - e) as usize)
-                .unwrap()
-                .dwavefront()
-                .unwrap()
-                .lo
-        },
+    let sources: Vec<&Wavefront> = vec![
+        wavefronts
+            .get_wavefront_at(s - x)
+            .and_then(|w| w.mwavefront()),
+        wavefronts
+            .get_wavefront_at(s - o - e)
+            .and_then(|w| w.mwavefront()),
+        wavefronts
+            .get_wavefront_at(s - e)
+            .and_then(|w| w.iwavefront()),
+        wavefronts
+            .get_wavefront_at(s - e)
+            .and_then(|w| w.dwavefront()),
     ]
-    .iter()
-    .min()
-    .unwrap()
-        - 1;
-
-    wavefronts
-        .get_wavefront_mut(score)
-        .unwrap()
-        .mwavefront_mut()
-        .unwrap()
-        .hi = hi;
-    wavefronts
-        .get_wavefront_mut(score)
-        .unwrap()
-        .dwavefront_mut()
-        .unwrap()
-        .hi = hi;
-    wavefronts
-        .get_wavefront_mut(score)
-        .unwrap()
-        .iwavefront_mut()
-        .unwrap()
-        .hi = hi;
-
-    wavefronts
-        .get_wavefront_mut(score)
-        .unwrap()
-        .iwavefront_mut()
-        .unwrap()
-        .lo = lo;
-    wavefronts
-        .get_wavefront_mut(score)
-        .unwrap()
-        .mwavefront_mut()
-        .unwrap()
-        .lo = lo;
-    wavefronts
-        .get_wavefront_mut(score)
-        .unwrap()
-        .dwavefront_mut()
-        .unwrap()
-        .lo = lo;
-
-    (lo, hi)
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let hi = sources.iter().map(|w| w.hi).max()? + 1;
+    let lo = sources.iter().map(|w| w.lo).min()? - 1;
+
+    Some((lo, hi))
 }
 
-fn wf_next(wavefronts: &mut Wavefronts, score: usize, cli_args: &CliArgs) {
+// The I, D and M offsets on diagonal k at score s
+fn wf_compute(wavefronts: &Wavefronts, s: isize, k: isize) -> (Offset, Offset, Offset) {
+    let x: isize = wavefronts.penalties.mismatch as isize;
+    let o: isize = wavefronts.penalties.gap_open as isize;
+    let e: isize = wavefronts.penalties.gap_extend as isize;
+
+    let imax = std::cmp::max(
+        wavefronts.moffset(s - o - e, k + 1),
+        wavefronts.ioffset(s - e, k + 1),
+    );
+    let imax = wavefronts.bound(k, imax);
+
+    let dmax = std::cmp::max(
+        wavefronts.moffset(s - o - e, k - 1),
+        wavefronts.doffset(s - e, k - 1),
+    ) + 1;
+    let dmax = wavefronts.bound(k, dmax);
+
+    let misms = wavefronts.bound(k, wavefronts.moffset(s - x, k) + 1);
+    let mmax = *[misms, imax, dmax].iter().max().unwrap();
+
+    (imax, dmax, mmax)
+}
+
+// Every diagonal of the next wavefront only reads the previous ones so when
+// it is wide enough each chunk of diagonals is computed on a thread of the pool.
+fn wf_next(
+    wavefronts: &mut Wavefronts,
+    score: usize,
+    cli_args: &CliArgs,
+    min_parallel_length: isize,
+    pool: &mut Option<Pool>,
+) {
     let verbosity = cli_args.verbosity_level;
     if verbosity > 2 {
         eprintln!("[wf_next] Computing wavefront for score {}", score);
@@ -856,162 +752,71 @@ fn wf_next(wavefronts: &mut Wavefronts, score: usize, cli_args: &CliArgs) {
 
     let s: isize = score as isize;
 
-    let x: isize = wavefronts.penalties.mismatch as isize;
-    let o: isize = wavefronts.penalties.gap_open as isize;
-    let e: isize = wavefronts.penalties.gap_extend as isize;
+    let (lo, hi) = match wf_expand(wavefronts, score) {
+        Some(range) => range,
+        None => {
+            // nothing reaches this score
+            wavefronts.wavefronts.push(Box::new(None));
+            return;
+        }
+    };
 
-    let num_wavefronts = wavefronts.wavefronts.len();
-    if num_wavefronts <= score {
-        (num_wavefronts..=score).for_each(|_| {
-            let x = Box::new(Some(WavefrontSet::new(wavefronts.diagonals)));
-            wavefronts.wavefronts.push(x);
-        });
-    }
+    let mut next = WavefrontSet::new(lo, hi);
 
-    let (lo, hi) = wf_expand(wavefronts, score);
+    // the table of offsets is only printed from one thread
+    let pool = if verbosity > 3 {
+        None
+    } else {
+        pool_for(pool, lo, hi, cli_args.threads, min_parallel_length)
+    };
 
-    if verbosity > 3 {
-        eprintln!("\tk'\tk\tmmax\timax\tdmax");
-    }
+    if let Some(pool) = pool {
+        let chunk_len = ((hi - lo + 1) as usize).div_ceil(pool.thread_count() as usize);
+        let wavefronts: &Wavefronts = wavefronts;
+        let WavefrontSet { m, d, i } = &mut next;
+        let (m, d, i) = (
+            m.as_mut().unwrap(),
+            d.as_mut().unwrap(),
+            i.as_mut().unwrap(),
+        );
 
-    for k in lo..=hi {
-        if k < wavefronts.min_diagonal {
-            if verbosity > 3 {
-                eprintln!("[wf_next] k={} is therefore out of scope. Skipping", k);
+        pool.scoped(|scope| {
+            for (n, ((m_chunk, d_chunk), i_chunk)) in m
+                .offsets
+                .chunks_mut(chunk_len)
+                .zip(d.offsets.chunks_mut(chunk_len))
+                .zip(i.offsets.chunks_mut(chunk_len))
+                .enumerate()
+            {
+                let chunk_lo = lo + (n * chunk_len) as isize;
+                scope.execute(move || {
+                    for (j, k) in (chunk_lo..).take(m_chunk.len()).enumerate() {
+                        (i_chunk[j], d_chunk[j], m_chunk[j]) = wf_compute(wavefronts, s, k);
+                    }
+                });
             }
-            continue;
+        });
+    } else {
+        if verbosity > 3 {
+            eprintln!("\tk\tmmax\timax\tdmax");
         }
 
-        let k: usize = compute_k(k, wavefronts.central_diagonal);
+        for k in lo..=hi {
+            let (imax, dmax, mmax) = wf_compute(wavefronts, s, k);
 
-        let imax = *vec![
-            if s - o - e < 0 || k == 0 || k + 1 >= wavefronts.diagonals {
-                0
-            } else {
-                wavefronts
-                    .get_wavefront((s - o - e) as usize)
-                    .unwrap()
-                    .mwavefront()
-                    .unwrap()
-                    .offsets[(k - 1) as usize] as isize
-            },
-            if s - e < 0 || k == 0 || k + 1 >= wavefronts.diagonals {
-                0
-            } else {
-                wavefronts
-                    .get_wavefront((s - e) as usize)
-                    .unwrap()
-                    .iwavefront()
-                    .unwrap()
-                    .offsets[(k - 1) as usize] as isize
-            },
-        ]
-        .iter()
-        .max()
-        .unwrap();
-
-        if k < wavefronts.diagonals {
-            wavefronts
-                .get_wavefront_mut(score)
-                .unwrap()
-                .iwavefront_mut()
-                .unwrap()
-                .offsets[k] = imax as usize;
-        }
-
-        let dmax = *vec![
-            if s - o - e < 0 || k + 1 >= wavefronts.diagonals {
-                0
-            } else {
-                wavefronts
-                    .get_wavefront((s - o - e) as usize)
-                    .unwrap()
-                    .mwavefront()
-                    .unwrap()
-                    .offsets[k + 1] as isize
-            },
-            if s - e < 0 || k + 1 >= wavefronts.diagonals {
-                0
-            } else {
-                wavefronts
-                    .get_wavefront((s - e) as usize)
-                    .unwrap()
-                    .dwavefront()
-                    .unwrap()
-                    .offsets[k + 1] as isize
-            },
-        ]
-        .iter()
-        .max()
-        .unwrap();
-
-        if k < wavefronts.diagonals {
-            wavefronts
-                .get_wavefront_mut(score)
-                .unwrap()
-                .dwavefront_mut()
-                .unwrap()
-                .offsets[k] = dmax as usize;
-        }
-
-        let mmax = *vec![
-            if s - x < 0 || k >= wavefronts.diagonals {
-                0
-            } else {
-                (wavefronts
-                    .get_wavefront((s - x) as usize)
-                    .unwrap()
-                    .mwavefront()
-                    .unwrap()
-                    .offsets[k]
-                    + 1 as usize) as isize
-            },
-            if k >= wavefronts.diagonals {
-                0
-            } else {
-                wavefronts
-                    .get_wavefront(score)
-                    .unwrap()
-                    .iwavefront()
-                    .unwrap()
-                    .offsets[k] as isize
-            },
-            if k >= wavefronts.diagonals {
-                0
-            } else {
-                wavefronts
-                    .get_wavefront(score)
-                    .unwrap()
-                    .dwavefront()
-                    .unwrap()
-                    .offsets[k] as isize
-            },
-        ]
-        .iter()
-        .max()
-        .unwrap();
-
-        if k < wavefronts.diagonals {
-            wavefronts
-                .get_wavefront_mut(score)
-                .unwrap()
-                .mwavefront_mut()
-                .unwrap()
-                .offsets[k] = mmax as usize;
-        }
+            next.iwavefront_mut().unwrap().set(k, imax);
+            next.dwavefront_mut().unwrap().set(k, dmax);
+            next.mwavefront_mut().unwrap().set(k, mmax);
 
-        if verbosity > 3 {
-            let k_prime = k as isize - wavefronts.central_diagonal as isize;
-            eprintln!("\t{}\t{}\t{}\t{}\t{}", k_prime, k, mmax, imax, dmax);
+            if verbosity > 3 {
+                eprintln!("\t{}\t{}\t{}\t{}", k, mmax, imax, dmax);
+            }
         }
     }
 
-    if cli_args.adapt {
-        reduce(wavefronts, score);
-    }
+    wavefronts.wavefronts.push(Box::new(Some(next)));
 }
 
-#[allow(unused_variables, unused_mut)]
 fn backtrace<T>(
     wavefronts: &mut Wavefronts,
     score: usize,
@@ -1025,24 +830,11 @@ where
         eprintln!("[wfa::backtrace]");
     }
 
-    let a_k = wavefronts.central_diagonal;
-    let a_offset = wavefronts.a_offset;
-    let text = wavefronts.text;
-    let query = wavefronts.query;
-
     let mut cigar = String::new();
-    let mut q_aln = String::new();
-    let mut t_aln = String::new();
-
-    let wf = wavefronts.get_wavefront(score).unwrap();
 
     let mut score: isize = score as isize;
-    let mut offset: isize = wf.mwavefront().unwrap().offsets[a_k] as isize;
-
-    let mut k = compute_kk(a_k, a_k);
-
-    let mut v: usize = compute_v(offset as usize, k as usize, a_k);
-    let mut h: usize = compute_h(offset as usize, k as usize, a_k);
+    let mut k: isize = wavefronts.target_k();
+    let mut offset: isize = wavefronts.target_offset();
 
     let mut backtrace_type = Operation::MatchMismatch;
 
@@ -1050,7 +842,7 @@ where
     let o: isize = wavefronts.penalties.gap_open as isize;
     let e: isize = wavefronts.penalties.gap_extend as isize;
 
-    while v > 0 && h > 0 && score > 0 {
+    while score > 0 {
         if verbosity > 4 {
             eprintln!("\tbacktrace_type = {:?}", backtrace_type);
         }
@@ -1062,31 +854,31 @@ where
         let del_ext: isize = if backtrace_type == Operation::Insertion {
             NULL_OFFSET
         } else {
-            backtrace_deletion_extend_offset(&wavefronts, gap_extend_score, k)
+            backtrace_deletion_extend_offset(wavefronts, gap_extend_score, k)
         };
         let del_open: isize = if backtrace_type == Operation::Insertion {
             NULL_OFFSET
         } else {
-            backtrace_deletion_open_offset(&wavefronts, gap_open_score, k)
+            backtrace_deletion_open_offset(wavefronts, gap_open_score, k)
         };
         let ins_ext: isize = if backtrace_type == Operation::Deletion {
             NULL_OFFSET
         } else {
-            backtrace_insertion_extend_offset(&wavefronts, gap_extend_score, k)
+            backtrace_insertion_extend_offset(wavefronts, gap_extend_score, k)
         };
         let ins_open: isize = if backtrace_type == Operation::Deletion {
             NULL_OFFSET
         } else {
-            backtrace_insertion_open_offset(&wavefronts, gap_open_score, k)
+            backtrace_insertion_open_offset(wavefronts, gap_open_score, k)
         };
         let misms: isize = if backtrace_type != Operation::MatchMismatch {
             NULL_OFFSET
         } else {
-            backtrace_mismatch_offset(&wavefronts, mismatch_score, k)
+            backtrace_mismatch_offset(wavefronts, mismatch_score, k)
         };
 
         // Compute maximum offset
-        let max_all = *vec![del_ext, del_open, ins_ext, ins_open, misms]
+        let max_all = *[del_ext, del_open, ins_ext, ins_open, misms]
             .iter()
             .max()
             .unwrap();
@@ -1094,9 +886,9 @@ where
         if verbosity > 4 {
             eprintln!(
                 "\tscore={} offset={} k={} \
-                       gap_open_score={} gap_extend_score={} mismatch_score={} \
-                       max_all={} del_ext={} del_open={} ins_ext={} ins_open={} mims={} \
-                       backtrace_type={:?}",
+                 gap_open_score={} gap_extend_score={} mismatch_score={} \
+                 max_all={} del_ext={} del_open={} ins_ext={} ins_open={} mims={} \
+                 backtrace_type={:?}",
                 score,
                 offset,
                 k,
@@ -1113,18 +905,14 @@ where
             );
         }
 
+        if max_all == NULL_OFFSET || offset < max_all {
+            panic!("Backtrace error: No link found during backtrace\n")
+        }
+
         // Traceback Matches
-        if backtrace_type == Operation::MatchMismatch && offset >= max_all {
+        if backtrace_type == Operation::MatchMismatch {
             let num_matches = (offset - max_all) as usize;
-            backtrace_matches_check(
-                &mut offset,
-                &mut cigar,
-                num_matches,
-                k,
-                wavefronts.central_diagonal,
-                backtrace_lambda,
-            );
-            offset = max_all;
+            backtrace_matches_check(&mut offset, &mut cigar, num_matches, k, backtrace_lambda);
         }
 
         if max_all == del_ext {
@@ -1132,100 +920,115 @@ where
             cigar.push('D');
             // Update state
             score = gap_extend_score;
-            k += 1;
+            k -= 1;
+            offset -= 1;
             backtrace_type = Operation::Deletion;
         } else if max_all == del_open {
             // Add Deletion
             cigar.push('D');
             // Update state
             score = gap_open_score;
-            k += 1;
+            k -= 1;
+            offset -= 1;
             backtrace_type = Operation::MatchMismatch;
         } else if max_all == ins_ext {
             // Add Insertion
             cigar.push('I');
             // Update state
             score = gap_extend_score;
-            k -= 1;
-            offset -= 1;
+            k += 1;
             backtrace_type = Operation::Insertion;
         } else if max_all == ins_open {
             // Add Insertion
             cigar.push('I');
             // Update state
             score = gap_open_score;
-            k -= 1;
-            offset -= 1;
+            k += 1;
             backtrace_type = Operation::MatchMismatch;
-        } else if max_all == misms {
+        } else {
             // Add Mismatch
             cigar.push('X');
 
             // Update state
             score = mismatch_score;
             offset -= 1;
-        } else {
-            panic!("Backtrace error: No link found during backtrace\n")
         }
-
-        // Update coordinates
-        v = compute_v(offset as usize, k as usize, a_k);
-        h = compute_h(offset as usize, k as usize, a_k);
     }
 
-    if score == 0 {
-        // backtrace matches check
-        let num_matches = offset as usize;
-        backtrace_matches_check(
-            &mut offset,
-            &mut cigar,
-            num_matches,
-            k,
-            wavefronts.central_diagonal,
-            backtrace_lambda,
-        );
-    } else {
-        while v > 0 {
-            cigar.push('D');
-            v -= 1;
-        }
+    // the leading matches on diagonal 0
+    let num_matches = offset as usize;
+    backtrace_matches_check(&mut offset, &mut cigar, num_matches, k, backtrace_lambda);
 
-        while h > 0 {
-            cigar.push('I');
-            h -= 1;
-        }
+    if cigar.is_empty() {
+        return cigar;
     }
 
-    cigar = run_length_encode(&cigar[..], true);
-    cigar
+    run_length_encode(&cigar[..], true)
 }
 
-// TODO: remove arg penalties
 pub fn wf_align<T>(
     text: &[u8],
     query: &[u8],
     cli_args: &CliArgs,
     backtrace_lambda: &mut T,
 ) -> Alignment
+where
+    T: FnMut((i32, i32), (i32, i32)),
+{
+    wf_align_sequences(text, query, cli_args, backtrace_lambda)
+}
+
+/// As wf_align over any sequence, for example 2-bit packed ones
+pub fn wf_align_sequences<T, S: Sequence + ?Sized>(
+    text: &S,
+    query: &S,
+    cli_args: &CliArgs,
+    backtrace_lambda: &mut T,
+) -> Alignment
+where
+    T: FnMut((i32, i32), (i32, i32)),
+{
+    align(
+        text,
+        query,
+        cli_args,
+        MIN_PARALLEL_WAVEFRONT_LENGTH,
+        backtrace_lambda,
+    )
+}
+
+// Wavefronts at least min_parallel_length wide are split over cli_args.threads
+fn align<T, S: Sequence + ?Sized>(
+    text: &S,
+    query: &S,
+    cli_args: &CliArgs,
+    min_parallel_length: isize,
+    backtrace_lambda: &mut T,
+) -> Alignment
 where
     T: FnMut((i32, i32), (i32, i32)),
 {
     let verbosity = cli_args.verbosity_level;
-    let mut wavefronts = Wavefronts::new(query, text, cli_args.penalties);
+    let track = cli_args.generate_alignment_tsv || verbosity > 3;
+    let mut wavefronts = Wavefronts::new(query.len(), text.len(), cli_args.penalties, track);
 
     let qlen = query.len();
     let tlen = text.len();
 
-    // central diagonal
-    let a_k: usize = qlen - 1;
-
-    // furthest offset along...
-    let a_offset: usize = max(qlen, tlen);
+    // the cell (n,m)
+    let a_k: isize = wavefronts.target_k();
+    let a_offset: isize = wavefronts.target_offset();
 
     let mut score = 0;
+    // the threads wide wavefronts are split over, kept from one score to the next
+    let mut pool: Option<Pool> = None;
 
     // Progress bar
-    let bar = ProgressBar::new(a_offset as u64).with_prefix("\t");
+    let bar = if verbosity > 1 {
+        ProgressBar::new(a_offset as u64).with_prefix("\t")
+    } else {
+        ProgressBar::hidden()
+    };
     let template = "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta_precise})";
     let progress_style = ProgressStyle::default_bar()
         .template(template)
@@ -1234,79 +1037,110 @@ where
     let mut progress_value: u64 = 0;
 
     let mut exit_condition = |wavefronts: &Wavefronts, score: usize| {
-        let current_offset = *wavefronts
-            .get_wavefront(score)
-            .unwrap()
-            .mwavefront()
-            .unwrap()
-            .offsets
-            .get(a_k)
-            .unwrap();
-        if verbosity > 1 {
+        let current_offset = wavefronts.moffset(score as isize, a_k);
+        if current_offset > progress_value as isize {
             // handle progress bar
-            let delta = current_offset as u64 - progress_value;
-            bar.inc(delta);
+            bar.inc(current_offset as u64 - progress_value);
             progress_value = current_offset as u64;
         }
 
-        current_offset >= a_offset
+        current_offset == a_offset
     };
 
     let match_lambda =
-        |v: usize, h: usize, offset: usize, score: usize, dp_matrix: &mut DpMatrix| -> bool {
-            if h < tlen && v < qlen && dp_matrix[h][v] == None {
-                dp_matrix[h][v] = Some(offset);
+        |v: usize, h: usize, _offset: usize, score: usize, dp_matrix: &mut DpMatrix| -> bool {
+            if h < tlen && v < qlen {
+                if let Some(row) = dp_matrix.get_mut(h) {
+                    if row[v].is_none() {
+                        row[v] = Some(score);
+                    }
+                }
             }
 
-            h < tlen && v < qlen && text[h] == query[v]
+            h < tlen && v < qlen && text.base(h) == query.base(v)
         };
 
     loop {
         let dp_matrix = &mut wavefronts.dp_matrix;
-        let k = wavefronts
+        if let Some(m_s) = wavefronts
             .wavefronts
             .get_mut(score)
-            .and_then(|x| (**x).as_mut());
-        let m_s = k.unwrap().mwavefront_mut().unwrap();
-        wf_extend(m_s, match_lambda, a_k, score, dp_matrix, verbosity);
+            .and_then(|x| (**x).as_mut())
+            .and_then(|w| w.mwavefront_mut())
+        {
+            // a tracked DP matrix is filled in by the lambda from one thread
+            let (sequences, pool) = if track {
+                (None, None)
+            } else {
+                let pool = pool_for(
+                    &mut pool,
+                    m_s.lo,
+                    m_s.hi,
+                    cli_args.threads,
+                    min_parallel_length,
+                );
+                (Some((text, query)), pool)
+            };
+            wf_extend(
+                m_s,
+                match_lambda,
+                sequences,
+                score,
+                dp_matrix,
+                pool,
+                verbosity,
+            );
+        }
 
         if exit_condition(&wavefronts, score) {
+            bar.finish_and_clear();
+
             if verbosity > 3 {
                 eprintln!("Final state of the DP table");
                 eprintln!("---------------------------");
-                wavefronts.print();
+                wavefronts.print(text, query);
             }
 
             if verbosity > 2 {
                 eprintln!(
                     "\tscore: {}\n\
-                           \tcentral diagonal (a_k): {}\n\
-                           \tmaximum offset (a_offset): {}",
+                     \ttarget diagonal (a_k): {}\n\
+                     \tmaximum offset (a_offset): {}",
                     score, a_k, a_offset
                 );
             }
 
             if cli_args.generate_alignment_tsv {
-                let adapt_str: &str = if cli_args.adapt {"adapt"} else {"no_adapt"};
+                let adapt_str: &str = if cli_args.adapt { "adapt" } else { "no_adapt" };
                 let now: String = cli_args.start_time.format("%Y-%m-%d_%H-%M-%S").to_string();
                 let filename = format!("wfilter-{}-{}.tsv", adapt_str, now);
 
                 eprintln!("[wfa::align] Generating alignment tsv: {}", filename);
 
-                wavefronts.print_tsv(&filename[..]);
+                wavefronts.print_tsv(&filename[..], text, query);
             }
 
             let cigar = backtrace(&mut wavefronts, score, verbosity, backtrace_lambda);
             return Alignment { score, cigar };
         }
 
+        if cli_args.adapt {
+            reduce(&mut wavefronts, score);
+        }
+
         if verbosity > 3 {
-            wavefronts.print();
+            wavefronts.print(text, query);
         }
 
         score += 1;
 
-        wf_next(&mut wavefronts, score, cli_args);
+        wf_next(
+            &mut wavefronts,
+            score,
+            cli_args,
+            min_parallel_length,
+            &mut pool,
+        );
     }
 }
 
@@ -1314,7 +1148,6 @@ where
 mod tests {
     use super::*;
     use crate::types::Penalties;
-    use chrono::Local;
 
     static PENALTIES: Penalties = Penalties {
         mismatch: 4,
@@ -1323,27 +1156,13 @@ mod tests {
         gap_extend: 2,
     };
 
-    static CLI: CliArgs = CliArgs {
-        verbosity_level: 0,
-        input_paf: String::new(),
-        target_fasta: String::new(),
-        query_fasta: String::new(),
-        penalties: PENALTIES,
-        adapt: false,
-        generate_alignment_tsv: false,
-        start_time: Local::now(),
-    };
+    fn cli() -> CliArgs {
+        CliArgs::new(0, "", "", "", Some(PENALTIES), false, false)
+    }
 
-    static CLI_ADAPT: CliArgs = CliArgs {
-        verbosity_level: 0,
-        input_paf: String::new(),
-        target_fasta: String::new(),
-        query_fasta: String::new(),
-        penalties: PENALTIES,
-        adapt: true,
-        generate_alignment_tsv: false,
-        start_time: Local::now(),
-    };
+    fn cli_adapt() -> CliArgs {
+        CliArgs::new(0, "", "", "", Some(PENALTIES), true, false)
+    }
 
     fn mock_backtrace_lambda(_query: (i32, i32), _target: (i32, i32)) {}
 
@@ -1360,7 +1179,7 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI,
+                &cli(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 0);
@@ -1376,7 +1195,7 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI,
+                &cli(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 4);
@@ -1391,7 +1210,7 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI,
+                &cli(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 8);
@@ -1410,17 +1229,14 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI,
+                &cli(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 96);
             assert_eq!(
                 aln.cigar,
-                String::from(
-                    "3M1X4M1I6M1D10M1X9M1X4M1I6M1D10M1X9M1X4M1I6M1D10M1X9M1X4M1I6M1D10M1X6M"
-                )
+                String::from("3M1X4M1I7M1D9M1X9M1X4M1I7M1D9M1X9M1X4M1I7M1D9M1X9M1X4M1I7M1D9M1X6M")
             );
-            // assert_eq!(aln.cigar, String::from("3M1X4M1D7M1I9M1X9M1X4M1D7M1I9M1X9M1X4M1D7M1I9M1X9M1X4M1D7M1I9M1X6M"));
         }
     }
 
@@ -1438,6 +1254,21 @@ mod tests {
             let s = "DDMMMMMMXMMMMMMMMMM";
             assert_eq!("2D6M1X10M", run_length_encode(s, false));
         }
+
+        #[test]
+        fn test_match_length() {
+            let a = b"ACGTACGTACGTACGTACGTA";
+            assert_eq!(match_length(a, a), a.len());
+            assert_eq!(match_length(a, &a[..5]), 5);
+            assert_eq!(match_length(b"", a), 0);
+
+            // a mismatch at every position, in and after the whole words
+            for i in 0..a.len() {
+                let mut b = a.to_vec();
+                b[i] = b'N';
+                assert_eq!(match_length(a, &b), i);
+            }
+        }
     }
 
     mod align {
@@ -1453,7 +1284,7 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI,
+                &cli(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 0);
@@ -1466,7 +1297,7 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI,
+                &cli(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 4);
@@ -1476,7 +1307,7 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI,
+                &cli(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 8);
@@ -1490,7 +1321,7 @@ mod tests {
             let aln = wf_align(
                 &text.as_bytes()[..10],
                 &query.as_bytes()[..10],
-                &CLI,
+                &cli(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 12);
@@ -1498,7 +1329,7 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI_ADAPT,
+                &cli_adapt(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 24);
@@ -1516,7 +1347,7 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI_ADAPT,
+                &cli_adapt(),
                 &mut mock_backtrace_lambda,
             );
             assert_eq!(aln.score, 96);
@@ -1532,10 +1363,10 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI_ADAPT,
+                &cli_adapt(),
                 &mut mock_backtrace_lambda,
             );
-            assert_eq!(aln.score, 200);
+            assert_eq!(aln.score, 118);
         }
 
         #[test]
@@ -1548,11 +1379,164 @@ mod tests {
             let aln = wf_align(
                 text.as_bytes(),
                 query.as_bytes(),
-                &CLI_ADAPT,
+                &cli_adapt(),
                 &mut mock_backtrace_lambda,
             );
 
-            assert_eq!(aln.score, 200);
+            assert_eq!(aln.score, 118);
+        }
+    }
+
+    mod differential {
+        use super::super::super::needleman_wunsch::needleman_wunsch;
+        use super::super::*;
+        use super::*;
+        use crate::verify::{verify_alignment, MatchInterval};
+
+        // xorshift so the test is reproducible without extra dependencies
+        struct Rng(u64);
+
+        impl Rng {
+            fn next(&mut self) -> u64 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0
+            }
+
+            fn seq(&mut self, len: usize) -> Vec<u8> {
+                (0..len)
+                    .map(|_| b"ACGT"[(self.next() % 4) as usize])
+                    .collect()
+            }
+
+            // a copy of seq with a few random edits
+            fn mutate(&mut self, seq: &[u8]) -> Vec<u8> {
+                let mut seq = seq.to_vec();
+                for _ in 0..(self.next() % 8) {
+                    let pos = (self.next() % (seq.len() as u64 + 1)) as usize;
+                    let base = b"ACGT"[(self.next() % 4) as usize];
+                    match self.next() % 3 {
+                        0 if pos < seq.len() => seq[pos] = base,
+                        1 if pos < seq.len() => {
+                            seq.remove(pos);
+                        }
+                        _ => seq.insert(pos, base),
+                    }
+                }
+                seq
+            }
+        }
+
+        fn check_pairs(cli_args: &CliArgs, seed: u64) {
+            let mut rng = Rng(seed);
+
+            for _ in 0..2000 {
+                let tlen = (rng.next() % 32) as usize;
+                let text = rng.seq(tlen);
+                // half unrelated and half similar pairs
+                let query = if rng.next() & 1 == 0 {
+                    let qlen = (rng.next() % 32) as usize;
+                    rng.seq(qlen)
+                } else {
+                    rng.mutate(&text[..])
+                };
+
+                let expected = needleman_wunsch(&text[..], &query[..], &cli_args.penalties);
+                let mut intervals: Vec<MatchInterval> = Vec::new();
+                let aln = wf_align(&text[..], &query[..], cli_args, &mut |q, t| {
+                    intervals.push((q, t))
+                });
+
+                assert_eq!(
+                    aln.score,
+                    expected.score,
+                    "text={} query={} cigar={} expected cigar={}",
+                    String::from_utf8_lossy(&text[..]),
+                    String::from_utf8_lossy(&query[..]),
+                    aln.cigar,
+                    expected.cigar
+                );
+
+                let verified = verify_alignment(
+                    &text[..],
+                    &query[..],
+                    aln.score,
+                    &aln.cigar,
+                    &cli_args.penalties,
+                    &intervals,
+                );
+                assert_eq!(verified, Ok(()));
+            }
+        }
+
+        #[test]
+        fn test_exact_is_optimal() {
+            check_pairs(&cli(), 0x9e37_79b9_7f4a_7c15);
+        }
+
+        #[test]
+        fn test_adaptive_is_optimal() {
+            check_pairs(&cli_adapt(), 0x2545_f491_4f6c_dd1d);
+        }
+
+        #[test]
+        fn test_other_penalties() {
+            let penalties = Penalties {
+                mismatch: 1,
+                matches: 0,
+                gap_open: 1,
+                gap_extend: 1,
+            };
+            let cli_args = CliArgs::new(0, "", "", "", Some(penalties), false, false);
+            check_pairs(&cli_args, 0x1234_5678_9abc_def1);
+
+            let penalties = Penalties {
+                mismatch: 5,
+                matches: 0,
+                gap_open: 0,
+                gap_extend: 3,
+            };
+            let cli_args = CliArgs::new(0, "", "", "", Some(penalties), false, false);
+            check_pairs(&cli_args, 0x0fed_cba9_8765_4321);
+        }
+
+        #[test]
+        fn test_parallel_matches_serial() {
+            let mut rng = Rng(0x5851_f42d_4c95_7f2d);
+            let serial = cli();
+            let parallel = CliArgs {
+                threads: 3,
+                ..cli()
+            };
+
+            for _ in 0..200 {
+                let tlen = 64 + (rng.next() % 64) as usize;
+                let text = rng.seq(tlen);
+                let query = if rng.next() & 1 == 0 {
+                    let qlen = 64 + (rng.next() % 64) as usize;
+                    rng.seq(qlen)
+                } else {
+                    rng.mutate(&text[..])
+                };
+
+                // split every wavefront of two or more diagonals
+                let expected = align(
+                    &text[..],
+                    &query[..],
+                    &serial,
+                    2,
+                    &mut mock_backtrace_lambda,
+                );
+                let aln = align(
+                    &text[..],
+                    &query[..],
+                    &parallel,
+                    2,
+                    &mut mock_backtrace_lambda,
+                );
+                assert_eq!((aln.score, aln.cigar), (expected.score, expected.cigar));
+            }
         }
     }
 }
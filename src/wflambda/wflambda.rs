@@ -1,29 +1,25 @@
-#![allow(unused_variables, dead_code)]
+/*
+Edit distance wavefront alignment
+---------------------------------
 
-const MATCH_SCORE: usize = 0;
-const MISMATCH: usize = 7;
-const GAP_OPENING: usize = 11;
-const GAP_EXTENSION: usize = 1;
+A port of the edit distance (unit cost) variant of the wavefront algorithm
+where both the matching and the traceback are driven by lambdas.
 
-macro_rules! max {
-    ($x: expr, $y: expr) => {{
-        if $x > $y {
-            $x
-        } else {
-            $y
-        }
-    }};
-}
+Diagonals are k = h - v where v indexes the pattern (query) and h indexes the
+text (target). The offset stored on a diagonal is h, the furthest reaching
+position in the text, so that v = h - k.
 
-macro_rules! abs {
-    ($x: expr) => {{
-        if $x >= 0 {
-            $x
-        } else {
-            -$x
-        }
-    }};
-}
+Operations follow the CIGAR conventions used in `wfa`:
+ - M a match (consumes query and target)
+ - X a mismatch (consumes query and target)
+ - I an insertion (consumes query)
+ - D a deletion (consumes target)
+*/
+use super::super::types::CliArgs;
+use super::wfa::utils::run_length_encode;
+
+// An offset that is never reached by any diagonal
+const NULL_OFFSET: EwfOffsetT = isize::MIN / 2;
 
 macro_rules! ewavefront_diagonal {
     ($h: expr, $v: expr) => {{
@@ -31,12 +27,6 @@ macro_rules! ewavefront_diagonal {
     }};
 }
 
-macro_rules! ewavefront_offset {
-    ($h: expr, $v: expr) => {{
-        $h
-    }};
-}
-
 macro_rules! ewavefront_v {
     ($k: expr, $offset: expr) => {{
         $offset - $k
@@ -49,7 +39,12 @@ macro_rules! ewavefront_h {
     }};
 }
 
-type EwfOffsetT = i16;
+type EwfOffsetT = isize;
+
+pub struct EditAlignment {
+    pub distance: usize,
+    pub cigar: String,
+}
 
 struct EditWavefrontT {
     // Effective lowest diagonal (inclusive)
@@ -60,515 +55,468 @@ struct EditWavefrontT {
     offsets: Vec<EwfOffsetT>,
 }
 
+impl EditWavefrontT {
+    fn new(lo: isize, hi: isize) -> Self {
+        let wavefront_length: usize = (hi - lo + 1) as usize;
+
+        EditWavefrontT {
+            lo,
+            hi,
+            offsets: vec![NULL_OFFSET; wavefront_length],
+        }
+    }
+
+    fn get(&self, k: isize) -> EwfOffsetT {
+        if self.lo <= k && k <= self.hi {
+            self.offsets[(k - self.lo) as usize]
+        } else {
+            NULL_OFFSET
+        }
+    }
+}
+
 struct EditWavefrontsT {
     // Dimensions
     pattern_length: usize,
     text_length: usize,
-    max_distance: usize,
 
     // Waves Offsets
     wavefronts: Vec<EditWavefrontT>,
-    wavefronts_allocated: usize,
 
-    // CIGAR
+    // CIGAR (reversed)
     edit_cigar: Vec<u8>,
-    edit_cigar_length: usize,
 }
 
-fn edit_wavefronts_backtrace<U>(
-    wavefronts: &mut EditWavefrontsT,
-    traceback_lambda: &U,
-    _pattern: &[u8],
-    _text: &[u8],
-    target_k: isize,
-    target_distance: usize,
-) where
-    U: Fn(isize, isize) -> bool,
-{
-    //print!("edit_wavefronts_backtrace\n");
-
-    // Parameters
-    let mut k = target_k;
-    let mut distance = target_distance;
-
-    let wavefronts_slice = &wavefronts.wavefronts[..=target_distance];
-
-    let wavefront = &wavefronts_slice[target_distance];
-
-    let mut offset = wavefront.offsets[(k - wavefront.lo) as usize];
-
-    wavefronts.edit_cigar_length = 0;
-
-    while distance > 0 {
-        /*print!("\tdistance: {}\n", distance);
-        print!("\tk: {}\n", k);*/
+impl EditWavefrontsT {
+    fn new(pattern_length: usize, text_length: usize) -> Self {
+        // Init wavefronts, alignments start at (0,0) on diagonal 0
+        let mut wavefront = EditWavefrontT::new(0, 0);
+        wavefront.offsets[0] = 0;
 
-        // Fetch
-        let wavefront = &wavefronts_slice[distance - 1];
-
-        /*print!("\tdistance - 1: {}\n", distance - 1);
-        print!("\t\twavefront->lo: {}\n", wavefront.lo);
-        print!("\t\twavefront->hi: {}\n", wavefront.hi);
-        if wavefront.lo <= k - 1 && k - 1 <= wavefront.hi {
-            print!("\t\toffsets[{}]: {}\n", k - 1, wavefront.offsets[(k - 1 + (-lo)) as usize]);
+        EditWavefrontsT {
+            pattern_length,
+            text_length,
+            wavefronts: vec![wavefront],
+            edit_cigar: Vec::with_capacity(pattern_length + text_length),
         }
-        print!("\t\toffset[{}]: {}\n", k - 1, offset);
-        if wavefront.lo <= k + 1 && k + 1 <= wavefront.hi {
-            print!("\t\toffsets[{}]: {}\n", k + 1, wavefront.offsets[(k + 1 + (-lo)) as usize]);
-        }*/
-
-        let i_k = k - wavefront.lo;
+    }
 
-        let v: isize = wavefront.offsets[i_k as usize] as isize;
-        let h: isize = offset as isize;
+    // Drop offsets that fall outside the DP matrix
+    fn bound(&self, k: isize, offset: EwfOffsetT) -> EwfOffsetT {
+        let h = ewavefront_h!(k, offset);
+        let v = ewavefront_v!(k, offset);
 
-        if v < 0 {
-            traceback_lambda(h, v);
+        if h < 0 || v < 0 || h > self.text_length as isize || v > self.pattern_length as isize {
+            NULL_OFFSET
         } else {
-            traceback_lambda(h, -1);
+            offset
         }
-
-        // Traceback operation
-        if -1 <= i_k && k + 1 <= wavefront.hi && offset == wavefront.offsets[(i_k + 1) as usize] {
-            // if wavefront.lo <= k + 1 && k + 1 <= wavefront.hi
-
-            wavefronts.edit_cigar[wavefronts.edit_cigar_length] = b'D';
-            wavefronts.edit_cigar_length += 1;
-            k += 1;
-            distance -= 1;
-        } else if 1 <= i_k
-            && k - 1 <= wavefront.hi
-            && offset == wavefront.offsets[(i_k - 1) as usize] + 1
-        {
-            // wavefront.lo <= k - 1 && k - 1 <= wavefront.hi
-
-            wavefronts.edit_cigar[wavefronts.edit_cigar_length] = b'I';
-            wavefronts.edit_cigar_length += 1;
-            k -= 1;
-            offset -= 1;
-            distance -= 1;
-        } else if 0 <= i_k && k <= wavefront.hi && offset == wavefront.offsets[i_k as usize] + 1 {
-            // wavefront.lo <= k && k <= wavefront.hi
-
-            wavefronts.edit_cigar[wavefronts.edit_cigar_length] = b'X';
-            wavefronts.edit_cigar_length += 1;
-            distance -= 1;
-            offset -= 1;
-        } else {
-            wavefronts.edit_cigar[wavefronts.edit_cigar_length] = b'M';
-            wavefronts.edit_cigar_length += 1;
-            offset -= 1;
-        }
-
-        //print!("\t\t{}\n", wavefronts.edit_cigar.chars().last().unwrap());
     }
+}
 
-    // Account for last offset of matches
-    while offset > 0 {
-        wavefronts.edit_cigar[wavefronts.edit_cigar_length] = b'M';
-        wavefronts.edit_cigar_length += 1;
-        offset -= 1;
-    }
+/*
+ * The three cells a diagonal k at distance d can be reached from at d - 1
+ *           deletion <-- wf_prec.offsets[k - 1] + 1; // consumes text
+ *       substitution <-- wf_prec.offsets[k] + 1;     // consumes both
+ *          insertion <-- wf_prec.offsets[k + 1];     // consumes pattern
+ */
+fn edit_wavefronts_sources(
+    wavefronts: &EditWavefrontsT,
+    wf_prec: &EditWavefrontT,
+    k: isize,
+) -> (EwfOffsetT, EwfOffsetT, EwfOffsetT) {
+    let del = wavefronts.bound(k, wf_prec.get(k - 1) + 1);
+    let sub = wavefronts.bound(k, wf_prec.get(k) + 1);
+    let ins = wavefronts.bound(k, wf_prec.get(k + 1));
+
+    (del, sub, ins)
 }
 
 fn edit_wavefronts_extend_wavefront<T>(
     wavefront: &mut EditWavefrontT,
     pattern_length: usize,
     text_length: usize,
-    lambda: T,
+    match_lambda: &T,
 ) where
     T: Fn(usize, usize) -> bool,
 {
-    print!("\tedit_wavefronts_extend_wavefront\n");
-
-    // Parameters
     let k_min = wavefront.lo;
 
-    print!(
-        "\t\twavefront.offsets length = {}\n",
-        wavefront.offsets.len()
-    );
-    // Extend diagonally each wavefront point (with take the execution times are slightly better)
-    for (i, offset) in wavefront
-        .offsets
-        .iter_mut()
-        .take((wavefront.hi - wavefront.lo + 1) as usize)
-        .enumerate()
-    {
-        let k = i as isize + k_min; // the diagonal
-        let mut v = ewavefront_v!(i as isize + k_min, *offset as isize) as usize; // offsets[k]-k (offset - k)
-        let mut h = ewavefront_h!(i as isize + k_min, *offset as isize) as usize; // offsets[k]   (offset)
-
-        // print!("\t\tk={}\n", i as isize + k_min);
-        while v < pattern_length && h < text_length && lambda(v, h) {
-            /*
-            print!("{}\t{}\t{}\t{}\t{}\tM\t{}\t{}\n", v, h, *offset, _distance, i as isize + k_min, pattern[v] as char, text[h] as char);
-
-            print!("\t\t\twavefronts[{}]->offsets[{}]: {}\n", _distance, i as isize + k_min, offset);
-            print!("\t\t\t(v, h) == ({}, {}) ==> ({}, {})\n", v, h, pattern[v] as char, text[h] as char);
-             */
+    for (i, offset) in wavefront.offsets.iter_mut().enumerate() {
+        if *offset == NULL_OFFSET {
+            continue;
+        }
+
+        let k = i as isize + k_min;
+        let mut v = ewavefront_v!(k, *offset) as usize;
+        let mut h = ewavefront_h!(k, *offset) as usize;
 
+        while v < pattern_length && h < text_length && match_lambda(v, h) {
             *offset += 1;
             v += 1;
             h += 1;
         }
+    }
+}
+
+fn edit_wavefronts_compute_wavefront(wavefronts: &mut EditWavefrontsT, distance: usize) {
+    let wf_prec = &wavefronts.wavefronts[distance - 1];
+    let mut wf_succ = EditWavefrontT::new(wf_prec.lo - 1, wf_prec.hi + 1);
 
-        //print!("{}\t{}\t{}\t{}\t{}\tX\t{}\t{}\n", v, h, *offset, _distance, i as isize + k_min, pattern[v] as char, text[h] as char);
-        /*print!("\t\t\twavefronts[{}]->offsets[{}]: {}\n", _distance, i as isize + k_min, offset);
-        print!("\t\t\t(v, h) == ({}, {}) ==> ({}, {})\n", v, h, pattern[v] as char, text[h] as char);*/
+    for k in wf_succ.lo..=wf_succ.hi {
+        let (del, sub, ins) = edit_wavefronts_sources(wavefronts, wf_prec, k);
+        wf_succ.offsets[(k - wf_succ.lo) as usize] = del.max(sub).max(ins);
     }
-    //print!("\t---------------\n");
+
+    wavefronts.wavefronts.push(wf_succ);
 }
 
-fn edit_wavefronts_compute_wavefront(
+fn edit_wavefronts_backtrace<U>(
     wavefronts: &mut EditWavefrontsT,
-    _pattern_length: usize,
-    _text_length: usize,
-    distance: usize,
-) {
-    //print!("\tedit_wavefronts_compute_wavefront\n");
-
-    // Fetch wavefronts
-    //let wavefront_next_wavefront = &mut wavefronts.wavefronts[distance - 1..=distance];
-    let (xxx, yyy) = wavefronts.wavefronts.split_at_mut(distance);
-    let wf_prec = &mut xxx[distance - 1];
-    let wf_succ = &mut yyy[0];
-
-    edit_wavefronts_allocate_wavefront(wf_succ, wf_prec.lo - 1, wf_prec.hi + 1);
-    // Allocate wavefront
-    wavefronts.wavefronts_allocated += 1; // Next
-                                          /*print!("\t\tedit_wavefronts->wavefronts_allocated: {}\n", wavefronts.wavefronts_allocated);
-                                          print!("\t---------------\n");*/
-
-    let hi_minus_lo = (wf_prec.hi - wf_prec.lo) as usize;
-
-    // Fetch offsets
-    let wf_prec_offset_lo = wf_prec.offsets[0];
-
-    // Loop peeling (k=lo-1); there is only the Upper DP cell (there are no Mid and Lower cells)
-    wf_succ.offsets[0] = wf_prec_offset_lo;
-
-    // Loop peeling (k=lo) ((wf_prec.lo + 1) <= wf_prec.hi)
-    let bottom_upper_del = if hi_minus_lo >= 1 {
-        wf_prec.offsets[1]
-    } else {
-        -1
-    };
-    wf_succ.offsets[1] = max!(wf_prec_offset_lo + 1, bottom_upper_del);
-
-    /*print!("\t\tlo - 1, next_wavefront[{}]->next_offsets[{}]: {}\n", distance, lo - 1, wf_succ.offsets[(lo - 1 + (-wf_succ.lo)) as usize]);
-    //print!("\t\tlo - 1, wavefront[{}]->offsets[{}]: {}\n", distance - 1, lo - 1, wf_prec.offsets[(lo - 1 + (-wf_prec.lo)) as usize]);
-    print!("\t\tlo    , next_wavefront[{}]->next_offsets[{}]: {}\n", distance, lo, wf_succ.offsets[(lo + (-wf_succ.lo)) as usize]);
-    print!("\t\tlo    , wavefront[{}]->offsets[{}]: {}\n", distance - 1, lo, wf_prec.offsets[(lo + (-wf_prec.lo)) as usize]);*/
-
-    // Compute next wavefront starting point
-    for i_k in 1..hi_minus_lo {
-        /*
-         *           deletion <-- wf_prec.offsets[k + 1];     // Upper
-         *       substitution <-- wf_prec.offsets[k] + 1;     // Mid
-         *          insertion <-- wf_prec.offsets[k - 1] + 1; // Lower
-         * wf_succ.offsets[k] <-- MAX(substitution, insertion, deletion); // MAX
-         */
-        let max_ins_sub = max!(wf_prec.offsets[i_k], wf_prec.offsets[i_k - 1]) + 1;
-        wf_succ.offsets[i_k + 1] = max!(max_ins_sub, wf_prec.offsets[i_k + 1]);
-
-        /*print!("\t\t - next_wavefront[{}]->next_offsets[{}]: {}\n", distance, k, wf_succ.offsets[(k + (-wf_succ.lo)) as usize]);
-        print!("\t\t - wavefront[{}]->offsets[{}]: {}\n", distance - 1, k, wf_prec.offsets[(k + (-lo)) as usize]);*/
-    }
+    traceback_lambda: &mut U,
+    target_k: isize,
+    target_distance: usize,
+) where
+    U: FnMut((i32, i32), (i32, i32)),
+{
+    let mut k = target_k;
+    let mut distance = target_distance;
+    let mut offset = wavefronts.wavefronts[distance].get(k);
 
-    let wf_prec_offset_hi = wf_prec.offsets[hi_minus_lo];
+    // report a run of matches that ends at offset on diagonal k
+    let mut matches = |cigar: &mut Vec<u8>, k: isize, offset: EwfOffsetT, num_matches: isize| {
+        if num_matches <= 0 {
+            return;
+        }
 
-    // Loop peeling (k=hi) (wf_prec.lo <= (wf_prec.hi - 1))
-    let top_lower_ins = if hi_minus_lo >= 1 {
-        wf_prec.offsets[hi_minus_lo - 1]
-    } else {
-        -1
+        let h = ewavefront_h!(k, offset);
+        let v = ewavefront_v!(k, offset);
+        traceback_lambda(
+            ((v - num_matches) as i32, v as i32),
+            ((h - num_matches) as i32, h as i32),
+        );
+
+        (0..num_matches).for_each(|_| cigar.push(b'M'));
     };
-    wf_succ.offsets[hi_minus_lo + 1] = max!(wf_prec_offset_hi, top_lower_ins) + 1;
 
-    // Loop peeling (k=hi+1); there is only the Lower DP cell (there are no Mid and Upper cells)
-    wf_succ.offsets[hi_minus_lo + 2] = wf_prec_offset_hi + 1;
+    while distance > 0 {
+        let wf_prec = &wavefronts.wavefronts[distance - 1];
+        let (del, sub, ins) = edit_wavefronts_sources(wavefronts, wf_prec, k);
+        let source = del.max(sub).max(ins);
+
+        matches(&mut wavefronts.edit_cigar, k, offset, offset - source);
 
-    /*print!("\t\thi    , next_wavefront[{}]->next_offsets[{}]: {}\n", distance, hi, wf_succ.offsets[(hi + (-wf_succ.lo)) as usize]);
-    print!("\t\thi    , wavefront[{}]->offsets[{}]: {}\n", distance - 1, hi, wf_prec.offsets[(hi + (-wf_prec.lo)) as usize]);
-    print!("\t\thi + 1, next_wavefront[{}]->next_offsets[{}]: {}\n", distance, hi + 1, wf_succ.offsets[(hi + 1 + (-wf_succ.lo)) as usize]);
-    print!("\t\thi + 1, wavefront[{}]->offsets[{}]: {}\n", distance - 1, hi + 1, wf_prec.offsets[(hi + 1 + (-wf_prec.lo)) as usize]);*/
+        if source == sub {
+            wavefronts.edit_cigar.push(b'X');
+            offset = source - 1;
+        } else if source == del {
+            wavefronts.edit_cigar.push(b'D');
+            k -= 1;
+            offset = source - 1;
+        } else {
+            wavefronts.edit_cigar.push(b'I');
+            k += 1;
+            offset = source;
+        }
+
+        distance -= 1;
+    }
+
+    // Account for the leading matches on diagonal 0
+    matches(&mut wavefronts.edit_cigar, k, offset, offset);
 }
 
 fn edit_wavefronts_align<T, U>(
     wavefronts: &mut EditWavefrontsT,
     match_lambda: &T,
-    traceback_lambda: &U,
-    pattern: &[u8],
-    pattern_length: usize,
-    text: &[u8],
-    text_length: usize,
-) where
+    traceback_lambda: &mut U,
+    verbosity: u8,
+) -> usize
+where
     T: Fn(usize, usize) -> bool,
-    U: Fn(isize, isize) -> bool,
+    U: FnMut((i32, i32), (i32, i32)),
 {
-    // Parameters
-
-    // This is the diagonal we start with
-    let target_k: isize = ewavefront_diagonal!(text_length as isize, pattern_length as isize); // h - v
-                                                                                               // offset on diagonal k
-    let target_offset: EwfOffsetT = ewavefront_offset!(text_length, pattern) as EwfOffsetT; // h
-
-    let target_k_abs: usize = abs!(target_k) as usize;
-
-    print!("edit_wavefronts_align\n");
-    print!(
-        "\ttarget_k: {} == text_length ({}) - pattern_length ({})\n",
-        target_k, text_length, pattern_length
-    );
-    print!(
-        "\ttarget_offset: {} == text_length ({})\n\n",
-        target_offset, text_length
-    );
-
-    // Init wavefronts
-    // assume target_k will always be 0
-    edit_wavefronts_allocate_wavefront(&mut wavefronts.wavefronts[0], 0, 0);
-    wavefronts.wavefronts_allocated += 1; // Next
-
-    print!(
-        "\t\tedit_wavefronts->wavefronts_allocated: {}\n",
-        wavefronts.wavefronts_allocated
-    );
-    print!("\n");
-
-    // TODO: what does this mean?
-    // max diagonals?
-    let mut target_distance: usize = wavefronts.max_distance;
-
-    // Compute wavefronts for increasing distance
-    // distance is how far the wavefront has traveled
-    for distance in 0..wavefronts.max_distance {
-        // Extend diagonally each wavefront point
+    let pattern_length = wavefronts.pattern_length;
+    let text_length = wavefronts.text_length;
+
+    // The diagonal and offset of the cell (n,m)
+    let target_k: isize = ewavefront_diagonal!(text_length as isize, pattern_length as isize);
+    let target_offset: EwfOffsetT = text_length as EwfOffsetT;
+
+    if verbosity > 2 {
+        eprintln!(
+            "[wflambda::edit_wavefronts_align] target_k={} target_offset={}",
+            target_k, target_offset
+        );
+    }
+
+    // The minimum distance is the absolute difference of the sequence lengths
+    let mut distance: usize = 0;
+    loop {
         edit_wavefronts_extend_wavefront(
-            wavefronts.wavefronts.get_mut(distance).unwrap(),
+            &mut wavefronts.wavefronts[distance],
             pattern_length,
             text_length,
             match_lambda,
         );
 
-        // Exit condition (the minimum distance is the absolute difference of the sequences' lengths aligned)
-        /*
-        print!("\t\ttarget_k={}, target_offset={}, distance={}, wavefronts.wavefronts[{2}].lo={}, \
-                wavefronts.wavefronts[distance].offsets[(target_k - wavefronts.wavefronts[distance].lo) as usize]={}\n",
-               target_k, target_offset, distance, wavefronts.wavefronts[distance].lo,
-               wavefronts.wavefronts[distance].offsets[(target_k - wavefronts.wavefronts[distance].lo) as usize]
-        );
-         */
-
-        // has the middle diagonal reached the target offset or cell (n,m)?
-        if distance >= target_k_abs
-            && wavefronts.wavefronts[distance].offsets
-                [(target_k - wavefronts.wavefronts[distance].lo) as usize]
-                == target_offset
-        {
-            print!("Exit condition\n");
-            print!(
-                "\tdistance ({}) >= target_k_abs ({})\n",
-                distance, target_k_abs
-            );
-            print!(
-                "\twavefronts[{}]->offsets[{}] ({}) == target_offset ({})\n",
-                distance,
-                target_k_abs,
-                wavefronts.wavefronts[distance].offsets
-                    [(target_k - wavefronts.wavefronts[distance].lo) as usize],
-                target_offset
-            );
-
-            target_distance = distance;
+        if wavefronts.wavefronts[distance].get(target_k) == target_offset {
             break;
         }
 
-        // Compute next wavefront starting point
-        edit_wavefronts_compute_wavefront(wavefronts, pattern_length, text_length, distance + 1);
+        distance += 1;
+        edit_wavefronts_compute_wavefront(wavefronts, distance);
     }
 
-    // Backtrace wavefronts
-    edit_wavefronts_backtrace(
-        wavefronts,
-        traceback_lambda,
-        pattern,
-        text,
-        target_k,
-        target_distance,
-    );
-}
-
-fn edit_wavefronts_clean(wavefronts: &mut EditWavefrontsT) {
-    /*
-    for i in 0..wavefronts.wavefronts_allocated {
-        wavefronts.wavefronts[i].offsets.clear();
+    if verbosity > 2 {
+        eprintln!("[wflambda::edit_wavefronts_align] distance={}", distance);
     }
-    */
 
-    wavefronts
-        .wavefronts
-        .iter_mut()
-        .for_each(|wf: &mut EditWavefrontT| wf.offsets.clear());
-
-    wavefronts.wavefronts_allocated = 0;
-}
+    edit_wavefronts_backtrace(wavefronts, traceback_lambda, target_k, distance);
 
-fn edit_wavefronts_allocate_wavefront(
-    wavefront: &mut EditWavefrontT,
-    lo_base: isize,
-    hi_base: isize,
-) {
-    // Compute limits
-    // if hi_base == lo_base && hi_base == 0 { 1 } else { 2 }) as usize; // (+1) for k=0
-    let wavefront_length: usize = (hi_base - lo_base + 1) as usize;
-
-    // Configure offsets
-    wavefront.lo = lo_base;
-    wavefront.hi = hi_base;
-
-    // Allocate offsets
-    wavefront.offsets = vec![0; wavefront_length];
-
-    /*
-    print!("\tedit_wavefronts_allocate_wavefront\n");
-    print!("\t\twavefront_length: {}\n", wavefront_length);
-    print!("\t\twavefronts[{}]->[lo_base, hi_base] == [{}, {}]\n", distance, lo_base, hi_base);
-     */
+    distance
 }
 
-fn wflambda<T, U>(
-    wavefronts: &mut EditWavefrontsT,
-    match_lambda: &T,
-    traceback_lambda: &U,
-    pattern: &[u8],
+/// Edit distance alignment of a pattern of length `pattern_length` against a
+/// text of length `text_length` where `match_lambda(v, h)` decides whether
+/// pattern position v matches text position h.
+///
+/// Every run of matches in the alignment is passed to `traceback_lambda` as
+/// half open (query, target) intervals.
+pub fn wflambda<T, U>(
     pattern_length: usize,
-    text: &[u8],
     text_length: usize,
-) where
+    match_lambda: &T,
+    traceback_lambda: &mut U,
+    verbosity: u8,
+) -> EditAlignment
+where
     T: Fn(usize, usize) -> bool,
-    U: Fn(isize, isize) -> bool,
+    U: FnMut((i32, i32), (i32, i32)),
+{
+    let mut wavefronts = EditWavefrontsT::new(pattern_length, text_length);
+    let distance =
+        edit_wavefronts_align(&mut wavefronts, match_lambda, traceback_lambda, verbosity);
+
+    let cigar = if wavefronts.edit_cigar.is_empty() {
+        String::new()
+    } else {
+        let ops = String::from_utf8(wavefronts.edit_cigar).unwrap();
+        run_length_encode(&ops[..], true)
+    };
+
+    EditAlignment { distance, cigar }
+}
+
+/// Align `query` against `text` under unit costs.
+/// Mirrors `wfa::wf_align` so that the filter can use either.
+pub fn wf_align<T>(
+    text: &[u8],
+    query: &[u8],
+    cli_args: &CliArgs,
+    backtrace_lambda: &mut T,
+) -> EditAlignment
+where
+    T: FnMut((i32, i32), (i32, i32)),
 {
-    edit_wavefronts_align(
-        wavefronts,
-        match_lambda,
-        traceback_lambda,
-        pattern,
-        pattern_length,
-        text,
-        text_length,
-    );
+    let match_lambda = |v: usize, h: usize| -> bool { query[v] == text[h] };
+
+    wflambda(
+        query.len(),
+        text.len(),
+        &match_lambda,
+        backtrace_lambda,
+        cli_args.verbosity_level,
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[ignore]
+    // Classic quadratic edit distance
+    fn levenshtein(text: &[u8], query: &[u8]) -> usize {
+        let mut prev: Vec<usize> = (0..=text.len()).collect();
+
+        for (v, q) in query.iter().enumerate() {
+            let mut curr = vec![v + 1; text.len() + 1];
+            for (h, t) in text.iter().enumerate() {
+                let sub = prev[h] + if q == t { 0 } else { 1 };
+                curr[h + 1] = sub.min(prev[h + 1] + 1).min(curr[h] + 1);
+            }
+            prev = curr;
+        }
+
+        prev[text.len()]
+    }
+
+    // Expand the CIGAR and walk both sequences checking every operation
+    fn check_cigar(text: &[u8], query: &[u8], aln: &EditAlignment) {
+        let mut v = 0;
+        let mut h = 0;
+        let mut edits = 0;
+        let mut count = 0;
+
+        for c in aln.cigar.chars() {
+            if let Some(d) = c.to_digit(10) {
+                count = count * 10 + d as usize;
+                continue;
+            }
+
+            for _ in 0..count {
+                match c {
+                    'M' => {
+                        assert_eq!(query[v], text[h]);
+                        v += 1;
+                        h += 1;
+                    }
+                    'X' => {
+                        assert_ne!(query[v], text[h]);
+                        v += 1;
+                        h += 1;
+                        edits += 1;
+                    }
+                    'I' => {
+                        v += 1;
+                        edits += 1;
+                    }
+                    'D' => {
+                        h += 1;
+                        edits += 1;
+                    }
+                    _ => panic!("unexpected op {}", c),
+                }
+            }
+            count = 0;
+        }
+
+        assert_eq!(v, query.len());
+        assert_eq!(h, text.len());
+        assert_eq!(edits, aln.distance);
+    }
+
+    fn align(text: &str, query: &str) -> EditAlignment {
+        let cli_args = CliArgs::for_tests();
+        wf_align(text.as_bytes(), query.as_bytes(), &cli_args, &mut |_, _| {})
+    }
+
     #[test]
-    fn test_wfilter() {
-        // Buffers
-        // TODO? Why the padding?
-        // 128 byes padding
-        let pattern_mem = "XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX\
-                           TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAG\
-                           TTCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT\
-                           XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX"
-            .as_bytes();
-        let text_mem = "\
-        YYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYY\
-        TCTATACTGCGCGTTTGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGA\
-        GAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT\
-        YYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYYY"
-            .as_bytes();
-
-        // Pattern & Text (seq + 64 Xs/Ys)
-        let pattern = &pattern_mem[64..];
-        let text = &text_mem[64..];
-        /*
-        println!("pattern+XXX...: {}", pattern);
-        println!("    text+YYY...: {}", text);
-         */
-
-        // isize to avoid overflow operation in a - b when a < b and a usize and b usize
-        // TODO: why the - 2 * 64?
-        let pattern_length: usize = pattern_mem.len() - 2 * 64;
-        let text_length: usize = text_mem.len() - 2 * 64;
-        let reps: usize = 1;
-
-        /*
-        println!("pattern_length: {}", pattern_length);
-        println!("    text_length: {}", text_length);
-         */
-
-        // Init Wavefronts
-        let mut wavefronts: EditWavefrontsT = EditWavefrontsT {
-            // Dimensions
-            pattern_length,
-            text_length,
-            max_distance: pattern_length + text_length,
+    fn test_same_sequence() {
+        let aln = align("GAGATA", "GAGATA");
+        assert_eq!(aln.distance, 0);
+        assert_eq!(aln.cigar, "6M");
+    }
+
+    #[test]
+    fn test_snp() {
+        let aln = align("GACATA", "GAGATA");
+        assert_eq!(aln.distance, 1);
+        assert_eq!(aln.cigar, "2M1X3M");
+    }
+
+    #[test]
+    fn test_indels() {
+        let aln = align("GATTACA", "GATACA");
+        assert_eq!(aln.distance, 1);
+        check_cigar(b"GATTACA", b"GATACA", &aln);
+
+        let aln = align("GATACA", "GATTACA");
+        assert_eq!(aln.distance, 1);
+        check_cigar(b"GATACA", b"GATTACA", &aln);
+
+        let aln = align("", "ACGT");
+        assert_eq!(aln.distance, 4);
+        assert_eq!(aln.cigar, "4I");
+
+        let aln = align("ACGT", "");
+        assert_eq!(aln.distance, 4);
+        assert_eq!(aln.cigar, "4D");
+    }
 
-            wavefronts: Vec::with_capacity(pattern_length + text_length),
-            wavefronts_allocated: 0,
+    #[test]
+    fn test_long_sequences() {
+        let text = "TCTATACTGCGCGTTTATCTAGGAGAAATAAAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT\
+                    TCTATACTGCGCGTTTGGAGAAATAACTATCAATAGTTCTATACTGCGCGTTTGGAGAAATAAAATAGT";
+        let query = "TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT\
+                     TCTTTACTCGCGCGTTGGAGAAATACAATAGTTCTTTACTCGCGCGTTGGAGAAATACAATAGT";
+
+        let aln = align(text, query);
+        assert_eq!(aln.distance, levenshtein(text.as_bytes(), query.as_bytes()));
+        check_cigar(text.as_bytes(), query.as_bytes(), &aln);
+    }
+
+    #[test]
+    fn test_offsets_beyond_i16() {
+        // 40 kb with a handful of edits spread along it
+        let text: Vec<u8> = (0..40_000).map(|i| b"ACGT"[(i * 7 + i / 3) % 4]).collect();
+        let mut query = text.clone();
+        query[10_000] = b'N';
+        query.remove(33_000);
+        query.insert(39_000, b'A');
+
+        let cli_args = CliArgs::for_tests();
+        let aln = wf_align(&text[..], &query[..], &cli_args, &mut |_, _| {});
+
+        assert!(aln.distance <= 3);
+        check_cigar(&text[..], &query[..], &aln);
+    }
 
-            // Allocate CIGAR
-            edit_cigar: vec![0; pattern_length + text_length],
-            edit_cigar_length: 0,
+    #[test]
+    fn test_traceback_lambda_reports_matches() {
+        let text = b"ACGTTACGT";
+        let query = b"ACGTACGT";
+        let mut matched = 0;
+        let mut traceback_lambda = |q: (i32, i32), t: (i32, i32)| {
+            assert_eq!(q.1 - q.0, t.1 - t.0);
+            assert_eq!(
+                &query[q.0 as usize..q.1 as usize],
+                &text[t.0 as usize..t.1 as usize]
+            );
+            matched += q.1 - q.0;
         };
+        let match_lambda = |v: usize, h: usize| query[v] == text[h];
+
+        let aln = wflambda(
+            query.len(),
+            text.len(),
+            &match_lambda,
+            &mut traceback_lambda,
+            0,
+        );
+        assert_eq!(aln.distance, 1);
+        assert_eq!(matched, 8);
+    }
 
-        //edit_wavefronts_init()
-        for _ in 0..wavefronts.max_distance {
-            wavefronts.wavefronts.push(EditWavefrontT {
-                lo: 0,
-                hi: 0,
-                offsets: Vec::new(),
-            });
-        }
+    #[test]
+    fn test_random_pairs() {
+        // xorshift so the test is reproducible without extra dependencies
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
 
-        let match_lambda = |v: usize, h: usize| -> bool { pattern[v] == text[h] };
-        let traceback_lambda = |v: isize, h: isize| -> bool {
-            if v >= 0 && h >= 0 && (v as usize) < pattern_length && (h as usize) < text_length {
-                true
-            } else {
-                false
+        for _ in 0..500 {
+            let tlen = (next() % 40) as usize;
+            let text: Vec<u8> = (0..tlen).map(|_| b"ACGT"[(next() % 4) as usize]).collect();
+            let mut query = text.clone();
+            for _ in 0..(next() % 6) {
+                let pos = (next() % (query.len() as u64 + 1)) as usize;
+                match next() % 3 {
+                    0 if pos < query.len() => query[pos] = b"ACGT"[(next() % 4) as usize],
+                    1 if pos < query.len() => {
+                        query.remove(pos);
+                    }
+                    _ => query.insert(pos, b"ACGT"[(next() % 4) as usize]),
+                }
             }
-        };
 
-        println!("");
-        (0..reps).for_each(|_rep| {
-            edit_wavefronts_clean(&mut wavefronts);
-            wflambda(
-                &mut wavefronts,
-                &match_lambda,
-                &traceback_lambda,
-                pattern,
-                pattern_length,
-                text,
-                text_length,
-            )
-        });
-
-        // Two ways to display the CIGAR string
-        // 1)
-        //for i in (0..wavefronts.edit_cigar_length).rev() {
-        //    print!("{}", wavefronts.edit_cigar[i] as char);
-        //}
-        // 2)
-        //wavefronts.edit_cigar.truncate(wavefronts.edit_cigar_length);
-        //wavefronts.edit_cigar.reverse();
-        //debug_assert!(&wavefronts.edit_cigar == &"MMMXMMMMDMMMMMMMIMMMMMMMMMXMMMMMMMMMXMMMMDMMMMMMMIMMMMMMMMMXMMMMMMMMMXMMMMDMMMMMMMIMMMMMMMMMXMMMMMMMMMXMMMMDMMMMMMMIMMMMMMMMMXMMMMMM".as_bytes());
-        //println!("{}", String::from_utf8(wavefronts.edit_cigar).unwrap());
-
-        print!("\n\n");
-        print!("Printing CIGAR\n");
-        wavefronts
-            .edit_cigar
-            .iter()
-            .rev()
-            .for_each(|c: &u8| print!("{}", *c as char));
-        print!("\n\n");
-
-        assert_eq!((), ());
+            let cli_args = CliArgs::for_tests();
+            let aln = wf_align(&text[..], &query[..], &cli_args, &mut |_, _| {});
+            assert_eq!(aln.distance, levenshtein(&text[..], &query[..]));
+            check_cigar(&text[..], &query[..], &aln);
+        }
     }
 }